@@ -4,7 +4,29 @@
 //     prost_build::compile_protos(&["src/peer.proto"], &["src/"]);
 // }
 use std::io::Result;
+use std::process::Command;
+
 fn main() -> Result<()> {
+    println!("cargo:rerun-if-changed=src/peer.proto");
     prost_build::compile_protos(&["src/peer.proto"], &["src/"])?;
+
+    // Made available as GIT_HASH for the identify agent version, if we are building from a checkout.
+    if let Some(hash) = git(&["rev-parse", "--short", "HEAD"]) {
+        println!("cargo:rustc-env=GIT_HASH={hash}");
+    }
+    if let Some(git_dir) = git(&["rev-parse", "--git-dir"]) {
+        println!("cargo:rerun-if-changed={git_dir}/HEAD");
+        println!("cargo:rerun-if-changed={git_dir}/refs");
+    }
+
     Ok(())
-}
\ No newline at end of file
+}
+
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    Some(String::from_utf8(output.stdout).ok()?.trim().to_string())
+}
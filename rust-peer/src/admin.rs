@@ -0,0 +1,248 @@
+use anyhow::Result;
+use libp2p::{gossipsub::MessageId, Multiaddr, PeerId};
+use tracing::{debug, info};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::command::Command;
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SERVER_ERROR: i64 = -32000;
+
+/// A command for the swarm, sent from the admin socket to the main loop.
+#[derive(Debug)]
+pub enum AdminCommand {
+    ListConnectedPeers,
+    ListSubscribedTopics,
+    /// Per subscribed topic, the number of mesh peers and of connected peers subscribed to it,
+    /// plus the network-wide estimate if `--topic-census` is enabled.
+    TopicPeers,
+    /// Our local listen addresses and the confirmed external ones, as dialable `/p2p` addresses.
+    ListListenAddrs,
+    Dial(Multiaddr),
+    Disconnect(PeerId),
+    /// Closes the connections to the peer and refuses new ones, also after a restart if there is
+    /// a `--blocklist-file`.
+    BlockPeer(PeerId),
+    /// Whether we processed the gossipsub message with this id recently.
+    HasSeenMessage(MessageId),
+    /// Connected peers, mesh members per topic, explicit peers, external addresses and the
+    /// Kademlia routing table.
+    DumpState,
+}
+
+/// An [`AdminCommand`] together with the channel to send its result back on.
+pub struct AdminRequest {
+    pub command: AdminCommand,
+    pub reply: oneshot::Sender<Result<Value, AdminError>>,
+}
+
+#[derive(Debug)]
+pub struct AdminError {
+    code: i64,
+    message: String,
+}
+
+impl AdminError {
+    /// An error executing an otherwise valid command.
+    pub fn server(message: impl Into<String>) -> Self {
+        Self {
+            code: SERVER_ERROR,
+            message: message.into(),
+        }
+    }
+
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RpcRequest {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+/// Serves newline-delimited JSON-RPC 2.0 requests on a Unix socket at `path`, forwarding them to
+/// the main loop via `commands`. Messages are published through `publish`, the same way an
+/// embedding application would.
+pub async fn serve(
+    path: PathBuf,
+    commands: mpsc::Sender<AdminRequest>,
+    publish: mpsc::Sender<Command>,
+) -> Result<()> {
+    // A socket left behind by a previous run would make the bind fail.
+    if path.exists() {
+        std::fs::remove_file(&path)?;
+    }
+
+    let listener = UnixListener::bind(&path)?;
+    // The socket gets the permissions of the umask, which usually lets any local user connect and
+    // control the node.
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+    info!(path = %path.display(), "Serving admin API");
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let commands = commands.clone();
+        let publish = publish.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, commands, publish).await {
+                debug!(%e, "Admin connection failed");
+            }
+        });
+    }
+}
+
+async fn handle_connection(
+    stream: UnixStream,
+    commands: mpsc::Sender<AdminRequest>,
+    publish: mpsc::Sender<Command>,
+) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = match serde_json::from_str::<Value>(&line) {
+            Ok(request) => handle_request(request, &commands, &publish).await,
+            Err(e) => error_response(Value::Null, AdminError::new(PARSE_ERROR, e.to_string())),
+        };
+
+        let mut response = serde_json::to_vec(&response)?;
+        response.push(b'\n');
+        writer.write_all(&response).await?;
+    }
+
+    Ok(())
+}
+
+async fn handle_request(
+    request: Value,
+    commands: &mpsc::Sender<AdminRequest>,
+    publish: &mpsc::Sender<Command>,
+) -> Value {
+    let request = match serde_json::from_value::<RpcRequest>(request) {
+        Ok(request) => request,
+        Err(e) => {
+            return error_response(Value::Null, AdminError::new(INVALID_REQUEST, e.to_string()))
+        }
+    };
+
+    if request.method == "publish" {
+        return match handle_publish(&request.params, publish).await {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": request.id, "result": result }),
+            Err(e) => error_response(request.id, e),
+        };
+    }
+
+    let command = match parse_command(&request.method, &request.params) {
+        Ok(command) => command,
+        Err(e) => return error_response(request.id, e),
+    };
+
+    let (reply, result) = oneshot::channel();
+    if commands.send(AdminRequest { command, reply }).await.is_err() {
+        return error_response(request.id, AdminError::server("node is shutting down"));
+    }
+
+    match result.await {
+        Ok(Ok(result)) => json!({ "jsonrpc": "2.0", "id": request.id, "result": result }),
+        Ok(Err(e)) => error_response(request.id, e),
+        Err(_) => error_response(request.id, AdminError::server("node is shutting down")),
+    }
+}
+
+async fn handle_publish(params: &Value, publish: &mpsc::Sender<Command>) -> Result<Value, AdminError> {
+    let topic = string_param(params, 0, "topic")?.to_string();
+    let data = string_param(params, 1, "data")?.as_bytes().to_vec();
+
+    let (reply, result) = oneshot::channel();
+    publish
+        .send(Command::PublishMessage { topic, data, reply })
+        .await
+        .map_err(|_| AdminError::server("node is shutting down"))?;
+
+    match result.await {
+        Ok(Ok(message_id)) => Ok(Value::String(message_id.to_string())),
+        Ok(Err(e)) => Err(AdminError::server(e.to_string())),
+        Err(_) => Err(AdminError::server("node is shutting down")),
+    }
+}
+
+fn parse_command(method: &str, params: &Value) -> Result<AdminCommand, AdminError> {
+    match method {
+        "listConnectedPeers" => Ok(AdminCommand::ListConnectedPeers),
+        "listSubscribedTopics" => Ok(AdminCommand::ListSubscribedTopics),
+        "topicPeers" => Ok(AdminCommand::TopicPeers),
+        "listListenAddrs" => Ok(AdminCommand::ListListenAddrs),
+        "dumpState" => Ok(AdminCommand::DumpState),
+        "dial" => {
+            let addr = string_param(params, 0, "multiaddr")?;
+            let addr = addr
+                .parse()
+                .map_err(|e| AdminError::new(INVALID_PARAMS, format!("invalid multiaddr: {e}")))?;
+
+            Ok(AdminCommand::Dial(addr))
+        }
+        "disconnect" => Ok(AdminCommand::Disconnect(peer_id_param(params)?)),
+        "blockPeer" => Ok(AdminCommand::BlockPeer(peer_id_param(params)?)),
+        "hasSeenMessage" => {
+            // Message ids are hex encoded, the way `publish` returns them.
+            let message_id = string_param(params, 0, "messageId")?;
+            let message_id = hex::decode(message_id)
+                .map_err(|e| AdminError::new(INVALID_PARAMS, format!("invalid message id: {e}")))?;
+
+            Ok(AdminCommand::HasSeenMessage(MessageId::new(&message_id)))
+        }
+        _ => Err(AdminError::new(
+            METHOD_NOT_FOUND,
+            format!("unknown method {method:?}"),
+        )),
+    }
+}
+
+/// Reads a string parameter, given either at `position` or by `name`.
+fn string_param<'a>(params: &'a Value, position: usize, name: &str) -> Result<&'a str, AdminError> {
+    let value = match params {
+        Value::Array(values) => values.get(position),
+        Value::Object(values) => values.get(name),
+        _ => None,
+    };
+
+    value
+        .and_then(Value::as_str)
+        .ok_or_else(|| AdminError::new(INVALID_PARAMS, format!("missing string parameter {name:?}")))
+}
+
+fn peer_id_param(params: &Value) -> Result<PeerId, AdminError> {
+    string_param(params, 0, "peerId")?
+        .parse()
+        .map_err(|e| AdminError::new(INVALID_PARAMS, format!("invalid peer id: {e}")))
+}
+
+fn error_response(id: Value, error: AdminError) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": error.code, "message": error.message },
+    })
+}
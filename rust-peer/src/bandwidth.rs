@@ -0,0 +1,211 @@
+use futures::{ready, AsyncRead, AsyncWrite, Future};
+use futures_timer::Delay;
+use libp2p::core::muxing::{StreamMuxer, StreamMuxerBox, StreamMuxerEvent, SubstreamBox};
+use std::io;
+use std::num::NonZeroU64;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+/// Counts the bytes going through every connection, and optionally caps their combined rate.
+///
+/// The cap is a token bucket shared by all streams of all connections, in both directions. A
+/// stream that finds the bucket empty waits before its next read or write, which makes the remote
+/// wait in turn once the muxer's flow control windows fill up.
+#[derive(Clone)]
+pub struct Bandwidth {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    inbound: AtomicU64,
+    outbound: AtomicU64,
+    /// Number of times a stream had to wait for the cap.
+    throttled: AtomicU64,
+    limit: Option<Mutex<TokenBucket>>,
+}
+
+struct TokenBucket {
+    bytes_per_sec: f64,
+    /// Goes negative when a read or write used more than was available, delaying the next one.
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    /// How long to wait until the bucket has tokens again, if it is empty.
+    fn wait(&mut self) -> Option<Duration> {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        // Allows bursts of up to a second's worth of bytes.
+        self.tokens = (self.tokens + elapsed * self.bytes_per_sec).min(self.bytes_per_sec);
+        self.refilled = now;
+
+        (self.tokens <= 0.0)
+            .then(|| Duration::from_secs_f64((1.0 - self.tokens) / self.bytes_per_sec))
+    }
+}
+
+impl Bandwidth {
+    pub fn new(max_bytes_per_sec: Option<NonZeroU64>) -> Self {
+        let limit = max_bytes_per_sec.map(|rate| {
+            Mutex::new(TokenBucket {
+                bytes_per_sec: rate.get() as f64,
+                tokens: rate.get() as f64,
+                refilled: Instant::now(),
+            })
+        });
+
+        Self {
+            inner: Arc::new(Inner {
+                inbound: AtomicU64::new(0),
+                outbound: AtomicU64::new(0),
+                throttled: AtomicU64::new(0),
+                limit,
+            }),
+        }
+    }
+
+    pub fn wrap(&self, muxer: StreamMuxerBox) -> StreamMuxerBox {
+        StreamMuxerBox::new(Muxer {
+            inner: muxer,
+            bandwidth: self.clone(),
+        })
+    }
+
+    /// Total bytes received and sent so far.
+    pub fn totals(&self) -> (u64, u64) {
+        (
+            self.inner.inbound.load(Ordering::Relaxed),
+            self.inner.outbound.load(Ordering::Relaxed),
+        )
+    }
+
+    /// How often streams had to wait for the cap since the last call.
+    pub fn take_throttled(&self) -> u64 {
+        self.inner.throttled.swap(0, Ordering::Relaxed)
+    }
+
+    fn wait(&self) -> Option<Duration> {
+        let wait = self.inner.limit.as_ref()?.lock().unwrap().wait();
+        if wait.is_some() {
+            self.inner.throttled.fetch_add(1, Ordering::Relaxed);
+        }
+
+        wait
+    }
+
+    fn consume(&self, counter: &AtomicU64, bytes: usize) {
+        counter.fetch_add(bytes as u64, Ordering::Relaxed);
+        if let Some(limit) = &self.inner.limit {
+            limit.lock().unwrap().tokens -= bytes as f64;
+        }
+    }
+
+    /// Resolves once the cap allows the next read or write, keeping the pending delay in `delay`.
+    fn poll_ready(&self, delay: &mut Option<Delay>, cx: &mut Context<'_>) -> Poll<()> {
+        loop {
+            if let Some(pending) = delay {
+                ready!(Pin::new(pending).poll(cx));
+                *delay = None;
+            }
+            match self.wait() {
+                Some(wait) => *delay = Some(Delay::new(wait)),
+                None => return Poll::Ready(()),
+            }
+        }
+    }
+}
+
+struct Muxer {
+    inner: StreamMuxerBox,
+    bandwidth: Bandwidth,
+}
+
+impl Muxer {
+    fn stream(&self, inner: SubstreamBox) -> SubstreamBox {
+        SubstreamBox::new(Stream {
+            inner,
+            bandwidth: self.bandwidth.clone(),
+            read_delay: None,
+            write_delay: None,
+        })
+    }
+}
+
+impl StreamMuxer for Muxer {
+    type Substream = SubstreamBox;
+    type Error = io::Error;
+
+    fn poll_inbound(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Self::Substream, Self::Error>> {
+        let stream = ready!(Pin::new(&mut self.inner).poll_inbound(cx))?;
+        Poll::Ready(Ok(self.stream(stream)))
+    }
+
+    fn poll_outbound(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Self::Substream, Self::Error>> {
+        let stream = ready!(Pin::new(&mut self.inner).poll_outbound(cx))?;
+        Poll::Ready(Ok(self.stream(stream)))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+
+    fn poll(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<StreamMuxerEvent, Self::Error>> {
+        Pin::new(&mut self.inner).poll(cx)
+    }
+}
+
+struct Stream {
+    inner: SubstreamBox,
+    bandwidth: Bandwidth,
+    read_delay: Option<Delay>,
+    write_delay: Option<Delay>,
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.bandwidth.poll_ready(&mut this.read_delay, cx));
+
+        let n = ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.bandwidth.consume(&this.bandwidth.inner.inbound, n);
+
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.bandwidth.poll_ready(&mut this.write_delay, cx));
+
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        this.bandwidth.consume(&this.bandwidth.inner.outbound, n);
+
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
@@ -0,0 +1,76 @@
+use anyhow::{Context, Result};
+use libp2p::PeerId;
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// Peers banned with `--blocklist-file` or the admin API.
+///
+/// Enforcing the ban is left to `allow_block_list`, which refuses to dial blocked peers and closes
+/// their connections as soon as they are established. This only keeps track of who is blocked and
+/// persists it.
+pub struct Blocklist {
+    path: Option<PathBuf>,
+    peers: HashSet<PeerId>,
+}
+
+impl Blocklist {
+    /// Loads the peer ids in `path`, one per line. Empty lines and lines starting with `#` are
+    /// ignored, and a missing file is treated as an empty one, so it can be created by blocking
+    /// the first peer.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let mut peers = HashSet::new();
+
+        if let Some(path) = path.filter(|path| path.exists()) {
+            let contents = fs::read_to_string(path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            for (i, line) in contents.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let peer_id = line.parse().with_context(|| {
+                    format!("Invalid peer id on line {} of {}", i + 1, path.display())
+                })?;
+                peers.insert(peer_id);
+            }
+            info!(peers = peers.len(), path = %path.display(), "Loaded blocked peers");
+        }
+
+        Ok(Self {
+            path: path.map(Path::to_path_buf),
+            peers,
+        })
+    }
+
+    pub fn contains(&self, peer_id: &PeerId) -> bool {
+        self.peers.contains(peer_id)
+    }
+
+    pub fn peers(&self) -> impl Iterator<Item = &PeerId> {
+        self.peers.iter()
+    }
+
+    /// Adds `peer_id`, appending it to the file if there is one. Returns false if it was blocked
+    /// already.
+    pub fn block(&mut self, peer_id: PeerId) -> Result<bool> {
+        if self.peers.contains(&peer_id) {
+            return Ok(false);
+        }
+
+        if let Some(path) = &self.path {
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("Failed to open {}", path.display()))?;
+            writeln!(file, "{peer_id}")
+                .with_context(|| format!("Failed to write {}", path.display()))?;
+        }
+        self.peers.insert(peer_id);
+
+        Ok(true)
+    }
+}
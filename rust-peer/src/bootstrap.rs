@@ -0,0 +1,299 @@
+use anyhow::{Context, Result};
+use futures::StreamExt;
+use libp2p::{
+    multiaddr::Protocol,
+    swarm::{dial_opts::DialOpts, ConnectionId, NetworkBehaviour, Swarm},
+    Multiaddr, PeerId,
+};
+use tracing::{debug, info, warn};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio_util::time::{delay_queue, DelayQueue};
+
+use crate::dial_queue::DialQueue;
+
+/// How long to wait before the first retry of a failed bootstrap dial. Doubles with every attempt,
+/// up to the configured maximum.
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Number of dials of a bootstrap address before we give up on it.
+const MAX_DIAL_ATTEMPTS: u32 = 6;
+
+/// The bootstrap peers, given with `--connect` and read from `--bootstrap-file`.
+///
+/// Every address is dialed on startup. Failed dials are retried with exponential backoff until
+/// [`MAX_DIAL_ATTEMPTS`] is reached, after which the address is logged and left alone. Once we
+/// have been connected to a bootstrap peer, we keep redialing it whenever the connection drops,
+/// backing off up to `max_backoff` between attempts.
+///
+/// The addresses of a peer are dialed happy-eyeballs style: QUIC first, then TCP, WebRTC and the
+/// rest, each `stagger` after the previous one. Dials that are still due once we are connected are
+/// skipped, and connections of dials that lost the race are closed.
+pub struct Bootstrap {
+    connect: Vec<Multiaddr>,
+    file: Option<PathBuf>,
+    addrs: HashSet<Multiaddr>,
+    max_backoff: Duration,
+    stagger: Duration,
+    /// Bootstrap peers we have been connected to, and the address we reached them on.
+    peers: HashMap<PeerId, Multiaddr>,
+    /// Pending bootstrap dials and the attempt they are.
+    dials: HashMap<ConnectionId, (Multiaddr, u32)>,
+    retries: DelayQueue<(Multiaddr, u32)>,
+    retry_keys: HashMap<Multiaddr, delay_queue::Key>,
+}
+
+impl Bootstrap {
+    pub fn new(
+        connect: Vec<Multiaddr>,
+        file: Option<PathBuf>,
+        max_backoff: Duration,
+        stagger: Duration,
+    ) -> Result<Self> {
+        let mut bootstrap = Self {
+            connect,
+            file,
+            addrs: HashSet::new(),
+            max_backoff,
+            stagger,
+            peers: HashMap::new(),
+            dials: HashMap::new(),
+            retries: DelayQueue::new(),
+            retry_keys: HashMap::new(),
+        };
+        bootstrap.addrs = bootstrap.read_addrs()?;
+
+        Ok(bootstrap)
+    }
+
+    /// Dials every bootstrap address.
+    pub fn dial_all<B: NetworkBehaviour>(&mut self, swarm: &mut Swarm<B>, dial_queue: &mut DialQueue) {
+        self.dial_staggered(swarm, dial_queue, self.addrs.clone().into_iter().collect());
+    }
+
+    /// Re-reads the bootstrap file and dials any addresses that weren't in it before. Addresses
+    /// that were removed from the file are no longer retried.
+    pub fn reload<B: NetworkBehaviour>(&mut self, swarm: &mut Swarm<B>, dial_queue: &mut DialQueue) -> Result<()> {
+        let Some(file) = &self.file else {
+            info!("No bootstrap file configured, nothing to reload");
+            return Ok(());
+        };
+        info!(file = %file.display(), "Reloading bootstrap peers");
+
+        let addrs = self.read_addrs()?;
+        let added = addrs.difference(&self.addrs).cloned().collect::<Vec<_>>();
+        self.addrs = addrs;
+        self.peers.retain(|_, addr| self.addrs.contains(addr));
+
+        self.dial_staggered(swarm, dial_queue, added);
+
+        Ok(())
+    }
+
+    /// Schedules a retry if `connection_id` was a bootstrap dial.
+    pub fn on_dial_failure(&mut self, connection_id: ConnectionId) {
+        let Some((addr, attempt)) = self.dials.remove(&connection_id) else {
+            return;
+        };
+
+        // Peers we have been connected to before are retried indefinitely.
+        let reconnecting = self.peers.values().any(|a| *a == addr);
+        if attempt >= MAX_DIAL_ATTEMPTS && !reconnecting {
+            warn!(%addr, attempt, "Giving up on bootstrap peer");
+            return;
+        }
+
+        self.schedule_retry(addr, attempt);
+    }
+
+    /// Returns whether the connection is a bootstrap dial that lost the race against another
+    /// connection to the peer, and should be closed.
+    pub fn on_connection_established(
+        &mut self,
+        peer_id: PeerId,
+        connection_id: ConnectionId,
+        num_established: u32,
+    ) -> bool {
+        if let Some((addr, _)) = self.dials.remove(&connection_id) {
+            if num_established > 1 {
+                debug!(%peer_id, "Closing redundant connection to bootstrap peer via {addr}");
+                return true;
+            }
+            self.peers.insert(peer_id, addr);
+        }
+
+        // The peer is back, e.g. because it dialed us, so there's no need to redial it.
+        if let Some(key) = self
+            .peers
+            .get(&peer_id)
+            .and_then(|addr| self.retry_keys.remove(addr))
+        {
+            self.retries.remove(&key);
+        }
+
+        false
+    }
+
+    pub fn is_bootstrap_peer(&self, peer_id: &PeerId) -> bool {
+        self.peers.contains_key(peer_id)
+    }
+
+    /// Schedules a redial if this was the last connection to a bootstrap peer.
+    pub fn on_connection_closed(&mut self, peer_id: PeerId, num_established: u32) {
+        if num_established > 0 {
+            return;
+        }
+        let Some(addr) = self.peers.get(&peer_id).cloned() else {
+            return;
+        };
+
+        info!(%peer_id, "Lost connection to bootstrap peer, redialing");
+        self.schedule_retry(addr, 0);
+    }
+
+    /// Resolves with the next bootstrap address whose retry delay has passed, or `None` if no
+    /// retries are scheduled.
+    pub async fn next_retry(&mut self) -> Option<(Multiaddr, u32)> {
+        let (addr, attempt) = self.retries.next().await?.into_inner();
+        self.retry_keys.remove(&addr);
+
+        Some((addr, attempt))
+    }
+
+    pub fn dial<B: NetworkBehaviour>(
+        &mut self,
+        swarm: &mut Swarm<B>,
+        dial_queue: &mut DialQueue,
+        addr: Multiaddr,
+        attempt: u32,
+    ) {
+        if !self.addrs.contains(&addr) {
+            debug!(%addr, "Not retrying, it is no longer a bootstrap peer");
+            return;
+        }
+        if let Some(peer_id) = self.peer_id(&addr).or_else(|| addr_peer_id(&addr)) {
+            if swarm.is_connected(&peer_id) {
+                return;
+            }
+        }
+
+        let opts = DialOpts::from(addr.clone());
+        let connection_id = opts.connection_id();
+
+        match dial_queue.dial(swarm, opts) {
+            Ok(()) => {
+                self.dials.insert(connection_id, (addr, attempt));
+            }
+            Err(e) => warn!(%addr, %e, "Failed to dial bootstrap peer"),
+        }
+    }
+
+    /// Dials the first address of every peer right away, and the others of the same peer in
+    /// order of preference, `stagger` apart.
+    fn dial_staggered<B: NetworkBehaviour>(
+        &mut self,
+        swarm: &mut Swarm<B>,
+        dial_queue: &mut DialQueue,
+        addrs: Vec<Multiaddr>,
+    ) {
+        let mut by_peer = HashMap::<Option<PeerId>, Vec<Multiaddr>>::new();
+        for addr in addrs {
+            by_peer.entry(addr_peer_id(&addr)).or_default().push(addr);
+        }
+
+        for (peer_id, mut addrs) in by_peer {
+            // Without a peer id we can't tell which addresses belong to the same peer.
+            if peer_id.is_none() || self.stagger.is_zero() {
+                for addr in addrs {
+                    self.dial(swarm, dial_queue, addr, 1);
+                }
+                continue;
+            }
+
+            addrs.sort_by_key(transport_preference);
+            let mut addrs = addrs.into_iter();
+            if let Some(first) = addrs.next() {
+                self.dial(swarm, dial_queue, first, 1);
+            }
+            for (i, addr) in (1..).zip(addrs) {
+                self.schedule(addr, 1, self.stagger.saturating_mul(i));
+            }
+        }
+    }
+
+    /// Schedules the dial following `attempt`, which is `0` for the first redial of a peer we lost
+    /// the connection to.
+    fn schedule_retry(&mut self, addr: Multiaddr, attempt: u32) {
+        let delay = INITIAL_RETRY_DELAY
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_backoff);
+        debug!(%addr, ?delay, "Retrying bootstrap peer");
+
+        self.schedule(addr, attempt + 1, delay);
+    }
+
+    fn schedule(&mut self, addr: Multiaddr, attempt: u32, delay: Duration) {
+        if let Some(key) = self.retry_keys.remove(&addr) {
+            self.retries.remove(&key);
+        }
+        let key = self.retries.insert((addr.clone(), attempt), delay);
+        self.retry_keys.insert(addr, key);
+    }
+
+    fn peer_id(&self, addr: &Multiaddr) -> Option<PeerId> {
+        self.peers
+            .iter()
+            .find_map(|(peer_id, a)| (a == addr).then_some(*peer_id))
+    }
+
+    /// The `--connect` addresses merged with the ones in the bootstrap file.
+    fn read_addrs(&self) -> Result<HashSet<Multiaddr>> {
+        let mut addrs = self.connect.iter().cloned().collect::<HashSet<_>>();
+
+        let Some(file) = &self.file else {
+            return Ok(addrs);
+        };
+        let contents = std::fs::read_to_string(file)
+            .with_context(|| format!("Failed to read bootstrap file {}", file.display()))?;
+
+        // One multiaddr per line, blank lines and `#` comments are ignored.
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            match line.parse() {
+                Ok(addr) => {
+                    addrs.insert(addr);
+                }
+                Err(e) => warn!(?line, file = %file.display(), %e, "Ignoring invalid address"),
+            }
+        }
+
+        Ok(addrs)
+    }
+}
+
+fn addr_peer_id(addr: &Multiaddr) -> Option<PeerId> {
+    addr.iter().find_map(|protocol| match protocol {
+        Protocol::P2p(peer_id) => Some(peer_id),
+        _ => None,
+    })
+}
+
+/// Lower is dialed first. QUIC needs the fewest round trips, WebRTC the most.
+fn transport_preference(addr: &Multiaddr) -> u8 {
+    let protocols = addr.iter().collect::<Vec<_>>();
+
+    if protocols.iter().any(|p| matches!(p, Protocol::QuicV1)) {
+        0
+    } else if protocols.iter().any(|p| matches!(p, Protocol::Ws(_) | Protocol::Wss(_))) {
+        3
+    } else if protocols.iter().any(|p| matches!(p, Protocol::Tcp(_))) {
+        1
+    } else if protocols.iter().any(|p| matches!(p, Protocol::WebRTCDirect)) {
+        2
+    } else {
+        3
+    }
+}
@@ -0,0 +1,232 @@
+use anyhow::{bail, Context, Result};
+use libp2p_webrtc::tokio::Certificate;
+use tracing::{debug, info, warn};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::fs;
+
+/// How long WebRTC certificates are used for before being rotated, and for how long the previous
+/// certificate is kept around afterwards.
+///
+/// libp2p-webrtc presents a single certificate per transport and can't swap it at runtime, so a
+/// rotation takes effect on the next start. Connections established before that are unaffected,
+/// since the certificate only matters during the DTLS handshake.
+///
+/// For the same reason we can't accept the previous certificate alongside the new one: a listener
+/// only ever matches the certificate hash of its own certificate. Browsers holding an address with
+/// the old hash have to pick up the new one, e.g. from the peer discovery topic, so rotate with
+/// enough lead time before the old certificate expires.
+#[derive(Debug, Clone, Copy)]
+pub struct RotationPolicy {
+    pub max_age: Duration,
+    pub grace: Duration,
+}
+
+/// Environment variable holding a PEM encoded certificate to use instead of the file.
+pub const CERT_PEM_ENV: &str = "WEBRTC_CERT_PEM";
+
+/// Every certificate in the format of [`Certificate::serialize_pem`] starts with this block.
+const EXPIRES_BEGIN: &str = "-----BEGIN EXPIRES-----";
+
+/// Reads the WebRTC certificate at `path`, creating it if it doesn't exist yet.
+///
+/// A certificate given via [`CERT_PEM_ENV`] takes precedence over the file. Whoever provides it is
+/// responsible for rotating it, so the rotation policy doesn't apply. Either can be a bundle of
+/// several certificates, see [`parse_bundle`].
+///
+/// If the certificate is older than the policy allows, a new one is generated and the old one
+/// is moved next to it (see [`previous_certificate_path`]), where it stays until the grace window
+/// has passed.
+pub async fn read_or_create_certificate(
+    path: &Path,
+    rotation: Option<RotationPolicy>,
+) -> Result<Certificate> {
+    if let Ok(pem) = std::env::var(CERT_PEM_ENV) {
+        let cert = parse_bundle(&pem, &format!("${CERT_PEM_ENV}"))?;
+
+        info!("Using certificate from ${CERT_PEM_ENV}");
+
+        return Ok(cert);
+    }
+
+    if !path.exists() {
+        let cert = generate_certificate(path).await?;
+
+        info!(
+            "Generated new certificate and wrote it to {}",
+            path.display()
+        );
+
+        return Ok(cert);
+    }
+
+    let Some(rotation) = rotation else {
+        return read_certificate(path).await;
+    };
+
+    let age = certificate_age(path).await?;
+    if age <= rotation.max_age {
+        prune_previous_certificate(path, age, rotation.grace).await;
+        return read_certificate(path).await;
+    }
+
+    let previous = previous_certificate_path(path);
+    fs::rename(path, &previous).await?;
+    let cert = generate_certificate(path).await?;
+
+    info!(
+        "Rotated certificate {} ({} days old), the previous one is kept in {} for {} days",
+        path.display(),
+        age.as_secs() / SECS_PER_DAY,
+        previous.display(),
+        rotation.grace.as_secs() / SECS_PER_DAY,
+    );
+
+    Ok(cert)
+}
+
+/// Whether the certificate at `path` has outlived the rotation policy.
+pub async fn rotation_due(path: &Path, rotation: RotationPolicy) -> bool {
+    if std::env::var_os(CERT_PEM_ENV).is_some() {
+        return false;
+    }
+
+    match certificate_age(path).await {
+        Ok(age) => age > rotation.max_age,
+        Err(e) => {
+            debug!(path = %path.display(), %e, "Failed to read certificate age");
+            false
+        }
+    }
+}
+
+/// Where the previous certificate is kept after a rotation, e.g. `cert.prev.pem` for `cert.pem`.
+pub fn previous_certificate_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path.extension().unwrap_or_default().to_string_lossy();
+
+    path.with_file_name(format!("{stem}.prev.{extension}"))
+}
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+async fn read_certificate(path: &Path) -> Result<Certificate> {
+    let pem = fs::read_to_string(&path).await?;
+    let cert = parse_bundle(&pem, &path.display().to_string())?;
+
+    info!(path = %path.display(), "Using existing certificate");
+
+    Ok(cert)
+}
+
+/// Parses the certificates concatenated in `pem`, each in the format of
+/// [`Certificate::serialize_pem`], and returns the first, which is the one we present and
+/// advertise.
+///
+/// The others are checked and logged, but can't be accepted: libp2p-webrtc presents a single
+/// certificate, see [`RotationPolicy`]. Browsers still holding the hash of one of them have to
+/// pick up our new address.
+fn parse_bundle(pem: &str, source: &str) -> Result<Certificate> {
+    let starts = pem.match_indices(EXPIRES_BEGIN).map(|(start, _)| start).collect::<Vec<_>>();
+    if starts.is_empty() {
+        bail!("No certificate found in {source}");
+    }
+
+    let mut certs = starts
+        .iter()
+        .zip(starts.iter().skip(1).copied().chain([pem.len()]))
+        .enumerate()
+        .map(|(i, (start, end))| {
+            Certificate::from_pem(&pem[*start..end])
+                .with_context(|| format!("Invalid certificate {} in {source}", i + 1))
+        })
+        .collect::<Result<Vec<_>>>()?
+        .into_iter();
+    let active = certs.next().expect("There is at least one certificate");
+
+    for cert in certs {
+        let certhash = libp2p::multiaddr::Protocol::Certhash(cert.fingerprint().to_multihash());
+        warn!(
+            %certhash,
+            "Ignoring additional certificate in {source}, only the first one can be presented"
+        );
+    }
+
+    Ok(active)
+}
+
+async fn generate_certificate(path: &Path) -> Result<Certificate> {
+    let cert = Certificate::generate(&mut rand::thread_rng())?;
+    fs::write(&path, &cert.serialize_pem().as_bytes()).await?;
+
+    Ok(cert)
+}
+
+/// Age of the certificate, based on when the file was last written.
+async fn certificate_age(path: &Path) -> Result<Duration> {
+    let modified = fs::metadata(path).await?.modified()?;
+
+    Ok(SystemTime::now()
+        .duration_since(modified)
+        .unwrap_or_default())
+}
+
+/// Removes the previous certificate once the current one has been in use for longer than the
+/// grace window.
+async fn prune_previous_certificate(path: &Path, current_age: Duration, grace: Duration) {
+    let previous = previous_certificate_path(path);
+    if current_age <= grace || !previous.exists() {
+        return;
+    }
+
+    match fs::remove_file(&previous).await {
+        Ok(()) => info!(path = %previous.display(), "Removed previous certificate"),
+        Err(e) => warn!(path = %previous.display(), %e, "Failed to remove previous certificate"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pem() -> (Certificate, String) {
+        let cert = Certificate::generate(&mut rand::thread_rng()).unwrap();
+        let pem = cert.serialize_pem();
+
+        (cert, pem)
+    }
+
+    #[test]
+    fn parses_a_single_certificate() {
+        let (cert, pem) = pem();
+
+        let parsed = parse_bundle(&pem, "cert.pem").unwrap();
+        assert_eq!(parsed.fingerprint(), cert.fingerprint());
+    }
+
+    #[test]
+    fn presents_the_first_of_two_certificates() {
+        let (first, first_pem) = pem();
+        let (second, second_pem) = pem();
+
+        let parsed = parse_bundle(&(first_pem + &second_pem), "cert.pem").unwrap();
+        assert_eq!(parsed.fingerprint(), first.fingerprint());
+        assert_ne!(parsed.fingerprint(), second.fingerprint());
+    }
+
+    #[test]
+    fn fails_on_an_empty_file() {
+        let error = parse_bundle("", "cert.pem").unwrap_err();
+        assert_eq!(error.to_string(), "No certificate found in cert.pem");
+    }
+
+    #[test]
+    fn fails_on_a_corrupt_second_certificate() {
+        let (_, first_pem) = pem();
+        let (_, second_pem) = pem();
+        let corrupt = second_pem.replacen("-----BEGIN CERTIFICATE-----", "-----BEGIN CERTIFICATE-----\n!!!!", 1);
+
+        let error = parse_bundle(&(first_pem + &corrupt), "cert.pem").unwrap_err();
+        assert_eq!(error.to_string(), "Invalid certificate 2 in cert.pem");
+    }
+}
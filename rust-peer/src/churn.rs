@@ -0,0 +1,109 @@
+use libp2p::PeerId;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Churn is sampled this often, which makes the rate one of events per minute.
+pub const CHURN_INTERVAL: Duration = Duration::from_secs(60);
+/// Weight of the latest sample in the moving average. Higher reacts faster but is noisier.
+const SMOOTHING: f64 = 0.3;
+/// A peer causing more than this share of the events of a sample dominates the churn.
+const DOMINANT_SHARE: f64 = 0.5;
+
+/// The outcome of a churn sample.
+pub struct Sample {
+    /// Moving average of connects and disconnects per minute.
+    pub rate: f64,
+    pub alert: Option<Alert>,
+}
+
+/// The churn rate is above `--churn-alert-threshold`.
+pub struct Alert {
+    /// The peer behind most of the churn, with its share of the events, if there is one.
+    pub dominant_peer: Option<(PeerId, f64)>,
+    /// Whether the dominant peer is backed off now.
+    pub backed_off: bool,
+}
+
+/// Tracks an exponential moving average of connects and disconnects per minute, which is a sign of
+/// a crash looping peer or an attack once it rises above `--churn-alert-threshold`.
+///
+/// With `--churn-backoff-seconds`, a peer that causes most of the churn while the rate is too high
+/// is refused for that long.
+pub struct ChurnMonitor {
+    threshold: f64,
+    backoff: Option<Duration>,
+    rate: Option<f64>,
+    /// Events per peer since the last sample.
+    events: HashMap<PeerId, u32>,
+    backed_off: HashMap<PeerId, Instant>,
+}
+
+impl ChurnMonitor {
+    pub fn new(threshold: f64, backoff: Option<Duration>) -> Self {
+        Self {
+            threshold,
+            backoff,
+            rate: None,
+            events: HashMap::new(),
+            backed_off: HashMap::new(),
+        }
+    }
+
+    /// Records a connection to `peer_id` being established or closed.
+    pub fn on_connection_event(&mut self, peer_id: PeerId) {
+        *self.events.entry(peer_id).or_default() += 1;
+    }
+
+    /// Folds the events since the last call into the average, called every [`CHURN_INTERVAL`].
+    /// Peers for which `is_exempt` returns true, like bootstrap peers, are never backed off.
+    pub fn sample(&mut self, is_exempt: impl Fn(&PeerId) -> bool) -> Sample {
+        let events = std::mem::take(&mut self.events);
+        let total = events.values().sum::<u32>() as f64;
+        let rate = match self.rate {
+            Some(rate) => SMOOTHING * total + (1.0 - SMOOTHING) * rate,
+            None => total,
+        };
+        self.rate = Some(rate);
+
+        if rate <= self.threshold {
+            return Sample { rate, alert: None };
+        }
+
+        let dominant_peer = events
+            .into_iter()
+            .max_by_key(|(_, count)| *count)
+            .map(|(peer_id, count)| (peer_id, count as f64 / total))
+            .filter(|(_, share)| *share > DOMINANT_SHARE);
+        let backed_off = match (dominant_peer, self.backoff) {
+            (Some((peer_id, _)), Some(backoff)) if !is_exempt(&peer_id) => {
+                self.backed_off.insert(peer_id, Instant::now() + backoff);
+                true
+            }
+            _ => false,
+        };
+
+        Sample {
+            rate,
+            alert: Some(Alert {
+                dominant_peer,
+                backed_off,
+            }),
+        }
+    }
+
+    /// Removes and returns the peers whose backoff is over.
+    pub fn expired_backoffs(&mut self) -> Vec<PeerId> {
+        let now = Instant::now();
+        let expired = self
+            .backed_off
+            .iter()
+            .filter(|(_, until)| **until <= now)
+            .map(|(peer_id, _)| *peer_id)
+            .collect::<Vec<_>>();
+        for peer_id in &expired {
+            self.backed_off.remove(peer_id);
+        }
+
+        expired
+    }
+}
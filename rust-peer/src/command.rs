@@ -0,0 +1,48 @@
+use libp2p::gossipsub;
+use std::fmt;
+use tokio::sync::oneshot;
+
+/// Commands an application embedding the peer can send to [`crate::run`].
+#[derive(Debug)]
+pub enum Command {
+    /// Publishes `data` on the gossipsub `topic`, replying with the id of the published message.
+    PublishMessage {
+        topic: String,
+        data: Vec<u8>,
+        reply: oneshot::Sender<Result<gossipsub::MessageId, PublishError>>,
+    },
+}
+
+#[derive(Debug)]
+pub enum PublishError {
+    Gossipsub(gossipsub::PublishError),
+    /// The topic is publishing faster than its `--topic-rate` and its queue is full.
+    Throttled,
+    /// Publishing is disabled by `--relay-only`.
+    RelayOnly,
+}
+
+impl From<gossipsub::PublishError> for PublishError {
+    fn from(e: gossipsub::PublishError) -> Self {
+        Self::Gossipsub(e)
+    }
+}
+
+impl fmt::Display for PublishError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Gossipsub(e) => write!(f, "{e}"),
+            Self::Throttled => write!(f, "publish queue of the topic is full"),
+            Self::RelayOnly => write!(f, "publishing is disabled by --relay-only"),
+        }
+    }
+}
+
+impl std::error::Error for PublishError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Gossipsub(e) => Some(e),
+            Self::Throttled | Self::RelayOnly => None,
+        }
+    }
+}
@@ -0,0 +1,112 @@
+use libp2p::gossipsub::{self, IdentTopic, MessageId, PublishError, TopicHash};
+use libp2p::PeerId;
+use miniz_oxide::deflate::compress_to_vec;
+use miniz_oxide::inflate::{decompress_to_vec_with_limit, DecompressError};
+use std::collections::HashSet;
+
+/// Appended to a topic for the topic that carries its messages compressed, with
+/// `--enable-compression`.
+///
+/// Messages on gossipsub go to every subscriber alike, so support is negotiated per topic rather
+/// than per peer: peers that can decompress subscribe to `<topic>/deflate` next to `<topic>`, and
+/// receive there the raw DEFLATE (RFC 1951) payloads browsers produce with
+/// `CompressionStream("deflate-raw")`. We only publish compressed while every peer we know on the
+/// plain topic is on the compressed one too, and otherwise fall back to the plain topic, so peers
+/// without support never get bytes they can't read.
+///
+/// We only know the topics of our direct peers though, and subscribers further away may only be on
+/// the plain topic. So every peer receiving a compressed message [`bridge`]s it to the plain topic
+/// while one of its own peers is only on that.
+///
+/// Message ids are computed over the payload as sent, so compressed messages are content-addressed
+/// by their compressed bytes. Messages bridged by several peers are content-addressed by the same
+/// plain bytes, and so only delivered once, unless the message ids include the source.
+pub const COMPRESSED_SUFFIX: &str = "/deflate";
+
+/// Balances speed and size for the small payloads of chat and discovery messages.
+const LEVEL: u8 = 6;
+
+/// The topic that carries the compressed messages of `topic`.
+pub fn compressed_topic(topic: &str) -> IdentTopic {
+    IdentTopic::new(format!("{topic}{COMPRESSED_SUFFIX}"))
+}
+
+/// The plain topic of a compressed `topic`, if it is one.
+pub fn plain_topic(topic: &TopicHash) -> Option<TopicHash> {
+    topic
+        .as_str()
+        .strip_suffix(COMPRESSED_SUFFIX)
+        .map(TopicHash::from_raw)
+}
+
+/// Subscribes to the compressed topic of `topic`, to receive the compressed messages on it.
+pub fn subscribe(gossipsub: &mut gossipsub::Behaviour, topic: &str) -> Result<bool, gossipsub::SubscriptionError> {
+    gossipsub.subscribe(&compressed_topic(topic))
+}
+
+/// Publishes `data` on the compressed topic of `topic` if all its subscribers we know support
+/// that, and compression makes the message any smaller. Publishes on `topic` otherwise.
+pub fn publish(
+    gossipsub: &mut gossipsub::Behaviour,
+    topic: IdentTopic,
+    data: Vec<u8>,
+) -> Result<MessageId, PublishError> {
+    let compressed_topic = compressed_topic(topic.hash().as_str());
+    if !all_support_compression(gossipsub, &topic.hash(), &compressed_topic.hash()) {
+        return gossipsub.publish(topic, data);
+    }
+
+    let compressed = compress_to_vec(&data, LEVEL);
+    if compressed.len() >= data.len() {
+        return gossipsub.publish(topic, data);
+    }
+    gossipsub.publish(compressed_topic, compressed)
+}
+
+/// Re-publishes a message we received on the compressed topic of `topic`, decompressed to `data`,
+/// on `topic`, if any of its subscribers we know aren't on the compressed topic. None if all are.
+pub fn bridge(
+    gossipsub: &mut gossipsub::Behaviour,
+    topic: &TopicHash,
+    data: Vec<u8>,
+) -> Option<Result<MessageId, PublishError>> {
+    let (plain_subscribers, compressed_subscribers) =
+        subscribers(gossipsub, topic, &compressed_topic(topic.as_str()).hash());
+    if plain_subscribers.is_subset(&compressed_subscribers) {
+        return None;
+    }
+
+    Some(gossipsub.publish(IdentTopic::new(topic.as_str()), data))
+}
+
+/// Decompresses a payload received on a compressed topic, failing if it decompresses to more than
+/// `limit` bytes.
+pub fn decompress(data: &[u8], limit: usize) -> Result<Vec<u8>, DecompressError> {
+    decompress_to_vec_with_limit(data, limit)
+}
+
+fn all_support_compression(gossipsub: &gossipsub::Behaviour, plain: &TopicHash, compressed: &TopicHash) -> bool {
+    let (plain_subscribers, compressed_subscribers) = subscribers(gossipsub, plain, compressed);
+
+    !compressed_subscribers.is_empty() && plain_subscribers.is_subset(&compressed_subscribers)
+}
+
+/// The peers we know on the `plain` and on the `compressed` topic.
+fn subscribers<'a>(
+    gossipsub: &'a gossipsub::Behaviour,
+    plain: &TopicHash,
+    compressed: &TopicHash,
+) -> (HashSet<&'a PeerId>, HashSet<&'a PeerId>) {
+    let mut plain_subscribers = HashSet::new();
+    let mut compressed_subscribers = HashSet::new();
+    for (peer_id, topics) in gossipsub.all_peers() {
+        if topics.contains(&plain) {
+            plain_subscribers.insert(peer_id);
+        }
+        if topics.contains(&compressed) {
+            compressed_subscribers.insert(peer_id);
+        }
+    }
+
+    (plain_subscribers, compressed_subscribers)
+}
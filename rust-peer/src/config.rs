@@ -0,0 +1,81 @@
+use anyhow::{bail, Context, Result};
+use clap::parser::ValueSource;
+use clap::Parser;
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+/// Id of the argument pointing at the configuration file.
+const CONFIG_ARG: &str = "config";
+
+/// Parses the command line, filling in anything not given there (or via the environment) from the
+/// TOML file passed with `--config`.
+///
+/// The file uses the long flag names as keys, e.g. `listen_address = "0.0.0.0"` or
+/// `connect = ["/ip4/..."]`, so every flag is supported without having to mirror `Opt` here.
+pub fn parse_with_config_file<T: Parser>() -> Result<T> {
+    let matches = T::command().get_matches();
+
+    let Some(path) = matches.get_one::<PathBuf>(CONFIG_ARG).cloned() else {
+        return Ok(T::from_arg_matches(&matches)?);
+    };
+
+    let contents = std::fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read config file {}", path.display()))?;
+    let table = toml::from_str::<toml::Table>(&contents)
+        .with_context(|| format!("Failed to parse config file {}", path.display()))?;
+
+    let mut file_args = Vec::new();
+    for (key, value) in table {
+        let id = key.replace('-', "_");
+        if id == CONFIG_ARG {
+            continue;
+        }
+
+        // Values given on the command line or via the environment take precedence.
+        if matches!(
+            matches.try_contains_id(&id).ok().and_then(|_| matches.value_source(&id)),
+            Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+        ) {
+            continue;
+        }
+
+        push_args(&mut file_args, &format!("--{}", id.replace('_', "-")), &value)
+            .with_context(|| format!("Invalid value for {key:?} in {}", path.display()))?;
+    }
+
+    let binary = std::env::args_os().next().unwrap_or_default();
+
+    // Validate the file on its own first, so errors point at the file rather than the command line.
+    T::command()
+        .try_get_matches_from(std::iter::once(binary.clone()).chain(file_args.iter().cloned()))
+        .map_err(|e| anyhow::anyhow!("Invalid config file {}: {e}", path.display()))?;
+
+    let args = std::iter::once(binary)
+        .chain(file_args)
+        .chain(std::env::args_os().skip(1));
+
+    Ok(T::parse_from(args))
+}
+
+fn push_args(args: &mut Vec<OsString>, flag: &str, value: &toml::Value) -> Result<()> {
+    match value {
+        toml::Value::Boolean(true) => args.push(flag.into()),
+        toml::Value::Boolean(false) => {}
+        toml::Value::Array(values) => {
+            for value in values {
+                push_args(args, flag, value)?;
+            }
+        }
+        toml::Value::String(s) => {
+            args.push(flag.into());
+            args.push(s.into());
+        }
+        toml::Value::Integer(_) | toml::Value::Float(_) | toml::Value::Datetime(_) => {
+            args.push(flag.into());
+            args.push(value.to_string().into());
+        }
+        toml::Value::Table(_) => bail!("tables are not supported"),
+    }
+
+    Ok(())
+}
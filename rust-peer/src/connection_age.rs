@@ -0,0 +1,53 @@
+use libp2p::{swarm::ConnectionId, PeerId};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+/// Remembers when connections were established, so connections that outlived
+/// `--max-connection-age-seconds` can be recycled. Some relayed connections go half-dead without
+/// ever being closed.
+///
+/// Connections upgraded through hole punching are exempt, since they are expensive to set up again
+/// and might not succeed a second time. So are those of peers the caller protects, e.g. our relays,
+/// whose connection carries our reservation and every circuit through it.
+pub struct ConnectionAges {
+    max_age: Duration,
+    established: HashMap<ConnectionId, (PeerId, Instant)>,
+    hole_punched: HashSet<ConnectionId>,
+}
+
+impl ConnectionAges {
+    pub fn new(max_age: Duration) -> Self {
+        Self {
+            max_age,
+            established: HashMap::new(),
+            hole_punched: HashSet::new(),
+        }
+    }
+
+    pub fn on_connection_established(&mut self, peer_id: PeerId, connection_id: ConnectionId) {
+        self.established
+            .insert(connection_id, (peer_id, Instant::now()));
+    }
+
+    pub fn on_hole_punched(&mut self, connection_id: ConnectionId) {
+        self.hole_punched.insert(connection_id);
+    }
+
+    pub fn on_connection_closed(&mut self, connection_id: ConnectionId) {
+        self.established.remove(&connection_id);
+        self.hole_punched.remove(&connection_id);
+    }
+
+    /// Connections older than the maximum age, along with their peer and age, skipping those of
+    /// protected peers.
+    pub fn expired(&self, is_protected: impl Fn(&PeerId) -> bool) -> Vec<(ConnectionId, PeerId, Duration)> {
+        self.established
+            .iter()
+            .filter(|(connection_id, (peer_id, _))| !self.hole_punched.contains(connection_id) && !is_protected(peer_id))
+            .filter_map(|(connection_id, (peer_id, established))| {
+                let age = established.elapsed();
+                (age > self.max_age).then_some((*connection_id, *peer_id, age))
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,164 @@
+use libp2p::{
+    core::Endpoint,
+    swarm::{
+        dummy, ConnectionDenied, ConnectionId, FromSwarm, NetworkBehaviour, SwarmEvent, THandler,
+        THandlerInEvent, THandlerOutEvent, ToSwarm,
+    },
+    Multiaddr, PeerId,
+};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::task::{Context, Poll};
+use tracing::{info_span, Span};
+
+/// Tracing spans following each connection from the dial, or the incoming attempt, to its close,
+/// so all log lines about one connection can be correlated.
+///
+/// A `dial` or `incoming` span lasts until the connection is upgraded, after which a child
+/// `connection` span takes over. Protocol events about a peer, like identify, get a child span of
+/// the peer's most recent connection.
+///
+/// It is a behaviour so it gets to see every dial and incoming connection as it starts, including
+/// the ones we dial ourselves, for which the swarm doesn't emit [`SwarmEvent::Dialing`]. That
+/// also makes it the place to count the connection attempts in flight.
+#[derive(Default)]
+pub struct ConnectionSpans {
+    pending: HashMap<ConnectionId, (Endpoint, Span)>,
+    established: HashMap<ConnectionId, (PeerId, Span)>,
+}
+
+impl ConnectionSpans {
+    /// The span to handle `event` in, which is [`Span::none`] for events that aren't about a
+    /// single connection.
+    pub fn span_for<E>(&mut self, event: &SwarmEvent<E>) -> Span {
+        match event {
+            SwarmEvent::Dialing { connection_id, .. }
+            | SwarmEvent::IncomingConnection { connection_id, .. } => self
+                .pending
+                .get(connection_id)
+                .map(|(_, span)| span.clone())
+                .unwrap_or_else(Span::none),
+            SwarmEvent::ConnectionEstablished {
+                peer_id,
+                connection_id,
+                ..
+            } => {
+                let parent = self
+                    .pending
+                    .remove(connection_id)
+                    .map(|(_, span)| span)
+                    .unwrap_or_else(Span::none);
+                let span = info_span!(parent: &parent, "connection", ?connection_id, %peer_id);
+                self.established
+                    .insert(*connection_id, (*peer_id, span.clone()));
+                span
+            }
+            SwarmEvent::OutgoingConnectionError { connection_id, .. }
+            | SwarmEvent::IncomingConnectionError { connection_id, .. } => self
+                .pending
+                .remove(connection_id)
+                .map(|(_, span)| span)
+                .unwrap_or_else(Span::none),
+            SwarmEvent::ConnectionClosed { connection_id, .. } => self
+                .established
+                .remove(connection_id)
+                .map(|(_, span)| span)
+                .unwrap_or_else(Span::none),
+            _ => Span::none(),
+        }
+    }
+
+    /// The number of dials and of incoming connections that were neither established nor failed
+    /// yet.
+    pub fn pending(&self) -> (usize, usize) {
+        let dials = self
+            .pending
+            .values()
+            .filter(|(endpoint, _)| endpoint.is_dialer())
+            .count();
+
+        (dials, self.pending.len() - dials)
+    }
+
+    pub fn connection(&self, connection_id: &ConnectionId) -> Span {
+        self.established
+            .get(connection_id)
+            .map(|(_, span)| span.clone())
+            .unwrap_or_else(Span::none)
+    }
+
+    /// The span of the most recently established connection to `peer_id`.
+    pub fn peer(&self, peer_id: &PeerId) -> Span {
+        self.established
+            .iter()
+            .filter(|(_, (peer, _))| peer == peer_id)
+            .max_by_key(|(connection_id, _)| **connection_id)
+            .map(|(_, (_, span))| span.clone())
+            .unwrap_or_else(Span::none)
+    }
+}
+
+impl NetworkBehaviour for ConnectionSpans {
+    type ConnectionHandler = dummy::ConnectionHandler;
+    type ToSwarm = Infallible;
+
+    fn handle_pending_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        _: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<(), ConnectionDenied> {
+        let span = info_span!("incoming", ?connection_id, remote = %remote_addr);
+        self.pending.insert(connection_id, (Endpoint::Listener, span));
+
+        Ok(())
+    }
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        _: ConnectionId,
+        _: PeerId,
+        _: &Multiaddr,
+        _: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        Ok(dummy::ConnectionHandler)
+    }
+
+    fn handle_pending_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        maybe_peer: Option<PeerId>,
+        addresses: &[Multiaddr],
+        _: Endpoint,
+    ) -> Result<Vec<Multiaddr>, ConnectionDenied> {
+        let span = info_span!("dial", ?connection_id, peer_id = ?maybe_peer, ?addresses);
+        self.pending.insert(connection_id, (Endpoint::Dialer, span));
+
+        Ok(vec![])
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        _: ConnectionId,
+        _: PeerId,
+        _: &Multiaddr,
+        _: Endpoint,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        Ok(dummy::ConnectionHandler)
+    }
+
+    fn on_swarm_event(&mut self, _: FromSwarm) {}
+
+    fn on_connection_handler_event(
+        &mut self,
+        _: PeerId,
+        _: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        match event {}
+    }
+
+    fn poll(&mut self, _: &mut Context<'_>) -> Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
+        Poll::Pending
+    }
+}
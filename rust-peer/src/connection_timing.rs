@@ -0,0 +1,86 @@
+use libp2p::PeerId;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::transport_stats::TRANSPORTS;
+
+/// Percentiles are taken over this many of the most recent samples per transport.
+const MAX_SAMPLES: usize = 1000;
+
+/// How long connections take to set up, by transport, for the percentiles in the stats log. The
+/// histograms in the metrics cover the whole uptime instead.
+///
+/// Establishment is measured from the dial, or the incoming connection attempt, until the
+/// connection is upgraded, which includes the WebRTC or TLS handshake. Time to first identify is
+/// measured from then until the peer's first identify arrives, and only for the first connection
+/// to a peer.
+#[derive(Default)]
+pub struct ConnectionTimings {
+    established: HashMap<&'static str, VecDeque<Duration>>,
+    identified: HashMap<&'static str, VecDeque<Duration>>,
+    awaiting_identify: HashMap<PeerId, (&'static str, Instant)>,
+}
+
+impl ConnectionTimings {
+    pub fn on_connection_established(
+        &mut self,
+        peer_id: PeerId,
+        transport: &'static str,
+        established_in: Duration,
+        first: bool,
+    ) {
+        push(self.established.entry(transport).or_default(), established_in);
+        if first {
+            self.awaiting_identify
+                .insert(peer_id, (transport, Instant::now()));
+        }
+    }
+
+    /// Records an identify from `peer_id`, returning the transport and time to first identify if
+    /// it's the first one.
+    pub fn on_identify(&mut self, peer_id: &PeerId) -> Option<(&'static str, Duration)> {
+        let (transport, established) = self.awaiting_identify.remove(peer_id)?;
+        let elapsed = established.elapsed();
+        push(self.identified.entry(transport).or_default(), elapsed);
+
+        Some((transport, elapsed))
+    }
+
+    pub fn on_peer_disconnected(&mut self, peer_id: &PeerId) {
+        self.awaiting_identify.remove(peer_id);
+    }
+
+    /// E.g. `webrtc=180/450/900ms identify=40/120/300ms quic=...`, with p50/p95/p99 for every
+    /// transport that has samples.
+    pub fn summary(&self) -> String {
+        TRANSPORTS
+            .iter()
+            .filter_map(|transport| {
+                let established = percentiles(self.established.get(transport)?);
+                let identified = self
+                    .identified
+                    .get(transport)
+                    .map(percentiles)
+                    .unwrap_or_else(|| "-".to_string());
+                Some(format!("{transport}={established} identify={identified}"))
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+fn push(samples: &mut VecDeque<Duration>, sample: Duration) {
+    if samples.len() == MAX_SAMPLES {
+        samples.pop_front();
+    }
+    samples.push_back(sample);
+}
+
+/// p50/p95/p99 in milliseconds.
+fn percentiles(samples: &VecDeque<Duration>) -> String {
+    let mut sorted = samples.iter().copied().collect::<Vec<_>>();
+    sorted.sort();
+    let percentile = |p: f64| sorted[((sorted.len() - 1) as f64 * p).round() as usize].as_millis();
+
+    format!("{}/{}/{}ms", percentile(0.5), percentile(0.95), percentile(0.99))
+}
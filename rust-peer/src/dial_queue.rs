@@ -0,0 +1,106 @@
+use libp2p::swarm::{dial_opts::DialOpts, ConnectionId, DialError, NetworkBehaviour, Swarm};
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::num::NonZeroUsize;
+use tracing::debug;
+
+/// Dials waiting for a free slot beyond this many are refused.
+const MAX_QUEUED: usize = 1024;
+
+/// Why [`DialQueue::dial`] failed.
+#[derive(Debug)]
+pub enum DialQueueError {
+    /// [`MAX_QUEUED`] dials are waiting already.
+    Full,
+    Dial(DialError),
+}
+
+impl fmt::Display for DialQueueError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DialQueueError::Full => write!(f, "too many dials queued"),
+            DialQueueError::Dial(e) => e.fmt(f),
+        }
+    }
+}
+
+/// Keeps at most `--dial-concurrency` of our own dials in flight, so dialing a large bootstrap
+/// file or discovery cache can't exhaust file descriptors and memory. Dials beyond that wait in
+/// order and start as earlier ones finish.
+///
+/// All the dials we start ourselves go through the queue: for bootstrap peers, stored peers,
+/// discovered peers, mDNS and the admin API. Dials by the behaviours, like Kademlia, start right
+/// away and don't count against the limit.
+pub struct DialQueue {
+    concurrency: usize,
+    in_flight: HashSet<ConnectionId>,
+    queued: VecDeque<DialOpts>,
+}
+
+impl DialQueue {
+    pub fn new(concurrency: NonZeroUsize) -> Self {
+        Self {
+            concurrency: concurrency.get(),
+            in_flight: HashSet::new(),
+            queued: VecDeque::new(),
+        }
+    }
+
+    /// Starts the dial if there is a free slot and queues it otherwise. The connection id of
+    /// `opts` stays the same either way.
+    pub fn dial<B: NetworkBehaviour>(
+        &mut self,
+        swarm: &mut Swarm<B>,
+        opts: DialOpts,
+    ) -> Result<(), DialQueueError> {
+        if self.in_flight.len() < self.concurrency {
+            return self.start(swarm, opts).map_err(DialQueueError::Dial);
+        }
+        if self.queued.len() >= MAX_QUEUED {
+            return Err(DialQueueError::Full);
+        }
+
+        self.queued.push_back(opts);
+
+        Ok(())
+    }
+
+    /// Frees the slot of `connection_id` once it was established or failed, and starts the next
+    /// queued dials. Returns the queued dials that failed to start.
+    pub fn on_dial_finished<B: NetworkBehaviour>(
+        &mut self,
+        swarm: &mut Swarm<B>,
+        connection_id: ConnectionId,
+    ) -> Vec<ConnectionId> {
+        if !self.in_flight.remove(&connection_id) {
+            return Vec::new();
+        }
+
+        let mut failed = Vec::new();
+        while self.in_flight.len() < self.concurrency {
+            let Some(opts) = self.queued.pop_front() else {
+                break;
+            };
+            let connection_id = opts.connection_id();
+            if let Err(e) = self.start(swarm, opts) {
+                debug!(?connection_id, "Failed to start queued dial: {e}");
+                failed.push(connection_id);
+            }
+        }
+
+        failed
+    }
+
+    /// The number of dials in flight and of those waiting for a slot.
+    pub fn depth(&self) -> (usize, usize) {
+        (self.in_flight.len(), self.queued.len())
+    }
+
+    fn start<B: NetworkBehaviour>(&mut self, swarm: &mut Swarm<B>, opts: DialOpts) -> Result<(), DialError> {
+        let connection_id = opts.connection_id();
+        swarm.dial(opts)?;
+        self.in_flight.insert(connection_id);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,244 @@
+use libp2p::{
+    identity::{Keypair, PublicKey, SigningError},
+    Multiaddr, PeerId,
+};
+use libp2p::gossipsub::MessageId;
+use prost::Message;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
+use std::time::Duration;
+
+use crate::seen_messages::SeenMessages;
+use crate::Peer;
+
+/// Prepended to the signed bytes, so a discovery signature can't be passed off as one made for
+/// another purpose with the same key.
+const SIGNING_DOMAIN: &[u8] = b"universal-connectivity-peer-discovery:";
+
+/// Bounded cache of the most recently identified peers and their listen addresses, which we
+/// periodically publish on the peer discovery topic so late joiners can find them.
+pub struct DiscoveryCache {
+    peers: VecDeque<CachedPeer>,
+    capacity: usize,
+}
+
+struct CachedPeer {
+    peer_id: PeerId,
+    public_key: PublicKey,
+    addrs: Vec<Multiaddr>,
+}
+
+impl DiscoveryCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            peers: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Records the addresses of a peer, replacing any earlier entry for it. Once the cache is full
+    /// the least recently seen peer is dropped.
+    pub fn insert(&mut self, public_key: PublicKey, addrs: Vec<Multiaddr>) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let peer_id = public_key.to_peer_id();
+        self.peers.retain(|p| p.peer_id != peer_id);
+
+        if self.peers.len() == self.capacity {
+            self.peers.pop_back();
+        }
+        self.peers.push_front(CachedPeer {
+            peer_id,
+            public_key,
+            addrs,
+        });
+    }
+
+    /// The cached peers and their addresses, most recently seen first.
+    pub fn peers(&self) -> impl Iterator<Item = (&PeerId, &Vec<Multiaddr>)> {
+        self.peers.iter().map(|p| (&p.peer_id, &p.addrs))
+    }
+
+    /// Encodes every cached peer as a discovery `Peer` message, most recently seen first.
+    ///
+    /// We can only sign for ourselves, so these are unsigned. Peers verifying signatures ignore
+    /// them, others, like browsers, still learn about each other.
+    pub fn to_messages(&self) -> Vec<Peer> {
+        self.peers
+            .iter()
+            .map(|p| Peer {
+                public_key: p.public_key.encode_protobuf(),
+                addrs: p.addrs.iter().map(|a| a.to_vec()).collect(),
+                signature: Vec::new(),
+                ttl: 0,
+            })
+            .collect()
+    }
+}
+
+/// Our own discovery `Peer` message, signed with `keypair`, for peers to re-publish up to `ttl`
+/// times.
+pub fn signed_peer(keypair: &Keypair, addrs: &[Multiaddr], ttl: u32) -> Result<Peer, SigningError> {
+    let public_key = keypair.public().encode_protobuf();
+    let addrs = addrs.iter().map(|a| a.to_vec()).collect::<Vec<_>>();
+    let signature = keypair.sign(&signing_payload(&public_key, &addrs))?;
+
+    Ok(Peer {
+        public_key,
+        addrs,
+        signature,
+        ttl,
+    })
+}
+
+/// Decodes a discovery `Peer` message into the advertised peer id and its parseable addresses.
+///
+/// Anyone can relay a message about any peer, so unless `allow_unsigned` is set, only messages
+/// the advertised peer signed itself are accepted.
+pub fn decode_peer(data: &[u8], allow_unsigned: bool) -> Result<(PeerId, Vec<Multiaddr>), &'static str> {
+    let peer = Peer::decode(data).map_err(|_| "invalid protobuf")?;
+    let public_key = verify(&peer, allow_unsigned)?;
+
+    let peer_id = public_key.to_peer_id();
+    let addrs = peer
+        .addrs
+        .into_iter()
+        .filter_map(|addr| Multiaddr::try_from(addr).ok())
+        .collect();
+
+    Ok((peer_id, addrs))
+}
+
+/// Records we remember having re-published, see [`DiscoveryRelay`].
+const MAX_RELAYED: usize = 10_000;
+
+/// Re-publishes signed discovery messages for peers that aren't in the same mesh as their
+/// publisher, like browsers only connected to us.
+///
+/// Every copy we re-publish is a new gossipsub message, which gossipsub's deduplication doesn't
+/// catch. If every node re-published every copy it received, one message would turn into about
+/// N^ttl. So we re-publish each record, i.e. a public key and its addresses, at most once per
+/// `window`, no matter how many copies of it reach us.
+///
+/// The ttl is the only part of a message we change, and the signature can't cover it: it has to
+/// shrink at every hop, and we can't sign for the advertised peer. Anyone relaying a message could
+/// raise it, up to `u32::MAX`, so we never pass on more hops than `--discovery-ttl` allows our own
+/// messages.
+pub struct DiscoveryRelay {
+    max_ttl: u32,
+    relayed: SeenMessages,
+}
+
+impl DiscoveryRelay {
+    pub fn new(max_ttl: u32, window: Duration) -> Self {
+        Self {
+            max_ttl,
+            relayed: SeenMessages::new(window, MAX_RELAYED),
+        }
+    }
+
+    /// The discovery message `data` with its ttl decremented, to re-publish. Returns `None` for
+    /// messages that shouldn't travel any further, records we re-published within the window, our
+    /// own messages, and unsigned ones, which would otherwise let anyone have their claims
+    /// amplified by us.
+    pub fn relayed(&mut self, data: &[u8], local_peer_id: &PeerId) -> Option<Vec<u8>> {
+        let mut peer = Peer::decode(data).ok()?;
+        let ttl = peer.ttl.min(self.max_ttl);
+        if ttl == 0 || peer.signature.is_empty() {
+            return None;
+        }
+        if verify(&peer, false).ok()?.to_peer_id() == *local_peer_id {
+            return None;
+        }
+
+        let mut s = DefaultHasher::new();
+        signing_payload(&peer.public_key, &peer.addrs).hash(&mut s);
+        if !self.relayed.insert(MessageId::from(s.finish().to_string())) {
+            return None;
+        }
+        peer.ttl = ttl - 1;
+
+        Some(peer.encode_to_vec())
+    }
+}
+
+/// The public key of the advertised peer, if it signed the message or `allow_unsigned` is set.
+fn verify(peer: &Peer, allow_unsigned: bool) -> Result<PublicKey, &'static str> {
+    let public_key =
+        PublicKey::try_decode_protobuf(&peer.public_key).map_err(|_| "invalid public key")?;
+
+    if peer.signature.is_empty() {
+        if !allow_unsigned {
+            return Err("unsigned");
+        }
+    } else if !public_key.verify(
+        &signing_payload(&peer.public_key, &peer.addrs),
+        &peer.signature,
+    ) {
+        return Err("invalid signature");
+    }
+
+    Ok(public_key)
+}
+
+/// The message without its signature, which is what gets signed.
+fn signing_payload(public_key: &[u8], addrs: &[Vec<u8>]) -> Vec<u8> {
+    let unsigned = Peer {
+        public_key: public_key.to_vec(),
+        addrs: addrs.to_vec(),
+        signature: Vec::new(),
+        ttl: 0,
+    };
+
+    [SIGNING_DOMAIN, &unsigned.encode_to_vec()].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relays_each_record_once_per_window_with_a_clamped_ttl() {
+        let keypair = Keypair::generate_ed25519();
+        let addrs = ["/ip4/192.0.2.1/tcp/4001".parse().unwrap()];
+        let mut relay = DiscoveryRelay::new(3, Duration::from_secs(60));
+        let local_peer_id = PeerId::random();
+
+        let inflated = signed_peer(&keypair, &addrs, u32::MAX).unwrap().encode_to_vec();
+        let relayed = relay.relayed(&inflated, &local_peer_id).expect("signed record is relayed");
+        assert_eq!(Peer::decode(relayed.as_slice()).unwrap().ttl, 2);
+
+        // Copies of the same record, whatever their ttl, aren't relayed again.
+        assert_eq!(relay.relayed(&inflated, &local_peer_id), None);
+        let copy = signed_peer(&keypair, &addrs, 2).unwrap().encode_to_vec();
+        assert_eq!(relay.relayed(&copy, &local_peer_id), None);
+
+        let moved = ["/ip4/192.0.2.2/tcp/4001".parse().unwrap()];
+        let moved = signed_peer(&keypair, &moved, 1).unwrap().encode_to_vec();
+        let relayed = relay.relayed(&moved, &local_peer_id).expect("changed record is relayed");
+        assert_eq!(Peer::decode(relayed.as_slice()).unwrap().ttl, 0);
+    }
+
+    #[test]
+    fn does_not_relay_unsigned_exhausted_or_own_records() {
+        let keypair = Keypair::generate_ed25519();
+        let addrs = ["/ip4/192.0.2.1/tcp/4001".parse().unwrap()];
+        let mut relay = DiscoveryRelay::new(3, Duration::from_secs(60));
+
+        let exhausted = signed_peer(&keypair, &addrs, 0).unwrap().encode_to_vec();
+        assert_eq!(relay.relayed(&exhausted, &PeerId::random()), None);
+
+        let mut unsigned = signed_peer(&keypair, &addrs, 3).unwrap();
+        unsigned.signature.clear();
+        assert_eq!(relay.relayed(&unsigned.encode_to_vec(), &PeerId::random()), None);
+
+        let own = signed_peer(&keypair, &addrs, 3).unwrap().encode_to_vec();
+        assert_eq!(relay.relayed(&own, &keypair.public().to_peer_id()), None);
+
+        let mut no_relaying = DiscoveryRelay::new(0, Duration::from_secs(60));
+        assert_eq!(no_relaying.relayed(&own, &PeerId::random()), None);
+    }
+}
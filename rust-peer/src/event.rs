@@ -0,0 +1,35 @@
+use libp2p::{autonat, gossipsub, PeerId};
+use tokio::sync::mpsc;
+use tracing::debug;
+
+/// Events of interest to an application embedding the peer, emitted by [`crate::run`].
+#[derive(Debug, Clone)]
+pub enum NetworkEvent {
+    /// We established our first connection to a peer.
+    PeerConnected(PeerId),
+    /// We closed our last connection to a peer.
+    PeerDisconnected(PeerId),
+    /// A gossipsub message was received on one of our topics.
+    MessageReceived {
+        source: Option<PeerId>,
+        topic: gossipsub::TopicHash,
+        data: Vec<u8>,
+    },
+    NatStatusChanged(autonat::NatStatus),
+    /// A peer acknowledged a file we sent it.
+    FileDelivered {
+        peer: PeerId,
+        file_id: String,
+        bytes_received: u64,
+    },
+    /// Sending a file failed before it was fully written, e.g. because the peer disconnected.
+    TransferAborted { peer: PeerId, file_id: String },
+}
+
+/// Sends `event` without waiting for the receiver, so a slow consumer can't stall the swarm.
+/// Events that don't fit into the channel are dropped.
+pub fn emit(sender: &mpsc::Sender<NetworkEvent>, event: NetworkEvent) {
+    if let Err(e) = sender.try_send(event) {
+        debug!(%e, "Dropped network event");
+    }
+}
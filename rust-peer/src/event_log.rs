@@ -0,0 +1,430 @@
+use anyhow::{Context as _, Result};
+use base64::Engine;
+use libp2p::core::{ConnectedPoint, Endpoint as Role};
+use libp2p::gossipsub::{self, MessageId, TopicHash};
+use libp2p::swarm::{ConnectionId, SwarmEvent};
+use libp2p::{Multiaddr, PeerId};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, LineWriter, Write};
+use std::num::NonZeroU32;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+use crate::BehaviourEvent;
+
+#[derive(Serialize)]
+struct Record<'a> {
+    /// Milliseconds since the Unix epoch.
+    timestamp_ms: u128,
+    /// Milliseconds since recording started, which is easier to line up with other logs.
+    elapsed_ms: u128,
+    kind: &'a str,
+    event: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    replayable: Option<Replayable>,
+}
+
+/// The part of a [`Record`] that is read back for `--replay-events`.
+#[derive(Deserialize)]
+struct ReplayRecord {
+    replayable: Option<Replayable>,
+}
+
+/// The events that are recorded in full, next to their `Debug` representation: the connection
+/// lifecycle, and the gossipsub messages and subscriptions the discovery and subscription handlers
+/// act on. Only these can be replayed.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Replayable {
+    ConnectionEstablished {
+        peer_id: PeerId,
+        connection_id: usize,
+        endpoint: Endpoint,
+        num_established: NonZeroU32,
+        established_in_ms: u64,
+    },
+    ConnectionClosed {
+        peer_id: PeerId,
+        connection_id: usize,
+        endpoint: Endpoint,
+        num_established: u32,
+    },
+    Message {
+        propagation_source: PeerId,
+        /// Hex encoded, like gossipsub displays it.
+        message_id: String,
+        source: Option<PeerId>,
+        #[serde(with = "base64_bytes")]
+        data: Vec<u8>,
+        sequence_number: Option<u64>,
+        topic: String,
+    },
+    Subscribed {
+        peer_id: PeerId,
+        topic: String,
+    },
+    Unsubscribed {
+        peer_id: PeerId,
+        topic: String,
+    },
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Endpoint {
+    Dialer {
+        address: Multiaddr,
+        /// Whether we dialed as the listener, for a hole punch.
+        as_listener: bool,
+    },
+    Listener {
+        local_addr: Multiaddr,
+        send_back_addr: Multiaddr,
+    },
+}
+
+impl Replayable {
+    fn from_event(event: &SwarmEvent<BehaviourEvent>) -> Option<Self> {
+        Some(match event {
+            SwarmEvent::ConnectionEstablished {
+                peer_id,
+                connection_id,
+                endpoint,
+                num_established,
+                established_in,
+                ..
+            } => Self::ConnectionEstablished {
+                peer_id: *peer_id,
+                connection_id: connection_number(connection_id),
+                endpoint: endpoint.into(),
+                num_established: *num_established,
+                established_in_ms: established_in.as_millis() as u64,
+            },
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                connection_id,
+                endpoint,
+                num_established,
+                ..
+            } => Self::ConnectionClosed {
+                peer_id: *peer_id,
+                connection_id: connection_number(connection_id),
+                endpoint: endpoint.into(),
+                num_established: *num_established,
+            },
+            SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(gossipsub::Event::Message {
+                propagation_source,
+                message_id,
+                message,
+            })) => Self::Message {
+                propagation_source: *propagation_source,
+                message_id: hex::encode(&message_id.0),
+                source: message.source,
+                data: message.data.clone(),
+                sequence_number: message.sequence_number,
+                topic: message.topic.to_string(),
+            },
+            SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(gossipsub::Event::Subscribed { peer_id, topic })) => {
+                Self::Subscribed {
+                    peer_id: *peer_id,
+                    topic: topic.to_string(),
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(gossipsub::Event::Unsubscribed { peer_id, topic })) => {
+                Self::Unsubscribed {
+                    peer_id: *peer_id,
+                    topic: topic.to_string(),
+                }
+            }
+            _ => return None,
+        })
+    }
+
+    /// The event as the swarm would have emitted it. Connections closed are replayed without a
+    /// cause, and established without the dial errors that came before.
+    fn into_event(self) -> Result<SwarmEvent<BehaviourEvent>> {
+        Ok(match self {
+            Self::ConnectionEstablished {
+                peer_id,
+                connection_id,
+                endpoint,
+                num_established,
+                established_in_ms,
+            } => SwarmEvent::ConnectionEstablished {
+                peer_id,
+                connection_id: ConnectionId::new_unchecked(connection_id),
+                endpoint: endpoint.into(),
+                num_established,
+                concurrent_dial_errors: None,
+                established_in: Duration::from_millis(established_in_ms),
+            },
+            Self::ConnectionClosed {
+                peer_id,
+                connection_id,
+                endpoint,
+                num_established,
+            } => SwarmEvent::ConnectionClosed {
+                peer_id,
+                connection_id: ConnectionId::new_unchecked(connection_id),
+                endpoint: endpoint.into(),
+                num_established,
+                cause: None,
+            },
+            Self::Message {
+                propagation_source,
+                message_id,
+                source,
+                data,
+                sequence_number,
+                topic,
+            } => SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(gossipsub::Event::Message {
+                propagation_source,
+                message_id: MessageId(hex::decode(message_id).context("Invalid message id")?),
+                message: gossipsub::Message {
+                    source,
+                    data,
+                    sequence_number,
+                    topic: TopicHash::from_raw(topic),
+                },
+            })),
+            Self::Subscribed { peer_id, topic } => {
+                SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(gossipsub::Event::Subscribed {
+                    peer_id,
+                    topic: TopicHash::from_raw(topic),
+                }))
+            }
+            Self::Unsubscribed { peer_id, topic } => {
+                SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(gossipsub::Event::Unsubscribed {
+                    peer_id,
+                    topic: TopicHash::from_raw(topic),
+                }))
+            }
+        })
+    }
+}
+
+impl From<&ConnectedPoint> for Endpoint {
+    fn from(endpoint: &ConnectedPoint) -> Self {
+        match endpoint {
+            ConnectedPoint::Dialer { address, role_override } => Self::Dialer {
+                address: address.clone(),
+                as_listener: role_override.is_listener(),
+            },
+            ConnectedPoint::Listener {
+                local_addr,
+                send_back_addr,
+            } => Self::Listener {
+                local_addr: local_addr.clone(),
+                send_back_addr: send_back_addr.clone(),
+            },
+        }
+    }
+}
+
+impl From<Endpoint> for ConnectedPoint {
+    fn from(endpoint: Endpoint) -> Self {
+        match endpoint {
+            Endpoint::Dialer { address, as_listener } => Self::Dialer {
+                address,
+                role_override: if as_listener { Role::Listener } else { Role::Dialer },
+            },
+            Endpoint::Listener {
+                local_addr,
+                send_back_addr,
+            } => Self::Listener {
+                local_addr,
+                send_back_addr,
+            },
+        }
+    }
+}
+
+/// Connection ids only expose their number through `Display`.
+fn connection_number(connection_id: &ConnectionId) -> usize {
+    connection_id
+        .to_string()
+        .parse()
+        .expect("Connection ids display as their number")
+}
+
+/// Appends every swarm event to a file as a line of JSON, for `--record-events`.
+///
+/// Most events carry types that can't be serialized, like errors, so every event is stored as its
+/// `Debug` representation, next to its kind and when we got it. That is enough to follow the
+/// sequence of events a bug needs. The events in [`Replayable`] are stored in full as well, so
+/// [`EventReplay`] can feed them back through `run`.
+pub struct EventRecorder {
+    file: LineWriter<File>,
+    started: Instant,
+}
+
+impl EventRecorder {
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open {} to record events", path.display()))?;
+
+        Ok(Self {
+            file: LineWriter::new(file),
+            started: Instant::now(),
+        })
+    }
+
+    pub fn record(&mut self, event: &SwarmEvent<BehaviourEvent>) {
+        let debug = format!("{event:?}");
+        let kind = debug
+            .split(|c: char| !c.is_alphanumeric())
+            .next()
+            .unwrap_or_default();
+        let record = Record {
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis(),
+            elapsed_ms: self.started.elapsed().as_millis(),
+            kind,
+            event: debug.clone(),
+            replayable: Replayable::from_event(event),
+        };
+
+        let mut line = serde_json::to_vec(&record).expect("Serializing to JSON doesn't fail");
+        line.push(b'\n');
+        if let Err(e) = self.file.write_all(&line) {
+            warn!(%e, "Failed to record swarm event");
+        }
+    }
+}
+
+/// The events of a `--record-events` file that can be replayed, for `--replay-events`.
+///
+/// They are fed back through `run` one after another, as fast as it takes them, in place of the
+/// events of the swarm. The swarm is never polled while replaying, so it doesn't connect
+/// anywhere and the handlers only act on our own state, which makes the processing deterministic.
+pub struct EventReplay {
+    events: std::vec::IntoIter<Replayable>,
+}
+
+impl EventReplay {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("Failed to open {} to replay events", path.display()))?;
+
+        let mut events = Vec::new();
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.with_context(|| format!("Failed to read {}", path.display()))?;
+            let record = serde_json::from_str::<ReplayRecord>(&line)
+                .with_context(|| format!("Invalid event record on line {} of {}", i + 1, path.display()))?;
+            events.extend(record.replayable);
+        }
+
+        Ok(Self {
+            events: events.into_iter(),
+        })
+    }
+
+    /// The next recorded event, none once all were replayed.
+    pub fn next_event(&mut self) -> Option<Result<SwarmEvent<BehaviourEvent>>> {
+        self.events.next().map(Replayable::into_event)
+    }
+}
+
+/// Stores bytes as base64, which is a lot shorter in JSON than an array of numbers.
+mod base64_bytes {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&base64::engine::general_purpose::STANDARD.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        base64::engine::general_purpose::STANDARD
+            .decode(encoded)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replay(events: Vec<SwarmEvent<BehaviourEvent>>) -> (Vec<String>, Vec<String>) {
+        let path = std::env::temp_dir().join(format!("events-{}.jsonl", rand::random::<u64>()));
+        let mut recorder = EventRecorder::create(&path).unwrap();
+        for event in &events {
+            recorder.record(event);
+        }
+        drop(recorder);
+        let mut replay = EventReplay::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let replayed = std::iter::from_fn(|| replay.next_event())
+            .map(|event| format!("{:?}", event.unwrap()))
+            .collect();
+        let recorded = events.iter().map(|event| format!("{event:?}")).collect();
+        (recorded, replayed)
+    }
+
+    #[test]
+    fn replays_what_it_records() {
+        let peer_id = PeerId::random();
+        let topic = TopicHash::from_raw("universal-connectivity");
+        let address = "/ip4/192.0.2.1/tcp/9090".parse::<Multiaddr>().unwrap();
+
+        let events = vec![
+            SwarmEvent::ConnectionEstablished {
+                peer_id,
+                connection_id: ConnectionId::new_unchecked(7),
+                endpoint: ConnectedPoint::Dialer {
+                    address: address.clone(),
+                    role_override: Role::Listener,
+                },
+                num_established: NonZeroU32::MIN,
+                concurrent_dial_errors: None,
+                established_in: Duration::from_millis(42),
+            },
+            SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(gossipsub::Event::Subscribed {
+                peer_id,
+                topic: topic.clone(),
+            })),
+            SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(gossipsub::Event::Message {
+                propagation_source: peer_id,
+                message_id: MessageId::new(b"\x01\xff"),
+                message: gossipsub::Message {
+                    source: Some(PeerId::random()),
+                    data: b"hello".to_vec(),
+                    sequence_number: Some(3),
+                    topic: topic.clone(),
+                },
+            })),
+            SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(gossipsub::Event::Unsubscribed { peer_id, topic })),
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                connection_id: ConnectionId::new_unchecked(7),
+                endpoint: ConnectedPoint::Listener {
+                    local_addr: address.clone(),
+                    send_back_addr: address,
+                },
+                num_established: 0,
+                cause: None,
+            },
+        ];
+
+        let (recorded, replayed) = replay(events);
+        assert_eq!(replayed, recorded);
+    }
+
+    #[test]
+    fn skips_events_that_are_not_replayable() {
+        let events = vec![SwarmEvent::Dialing {
+            peer_id: None,
+            connection_id: ConnectionId::new_unchecked(1),
+        }];
+
+        let (_, replayed) = replay(events);
+        assert!(replayed.is_empty());
+    }
+}
@@ -0,0 +1,78 @@
+use libp2p::{gossipsub, PeerId};
+use std::collections::HashSet;
+use tracing::debug;
+
+/// The peers we added as gossipsub explicit peers, which gossipsub itself doesn't expose.
+///
+/// Gossipsub keeps redialing explicit peers, so they are dropped again once we lose the connection
+/// to them, and their number is capped.
+pub struct ExplicitPeers {
+    peers: HashSet<PeerId>,
+    max: usize,
+}
+
+impl ExplicitPeers {
+    pub fn new(max: usize) -> Self {
+        Self {
+            peers: HashSet::new(),
+            max,
+        }
+    }
+
+    pub fn add(&mut self, gossipsub: &mut gossipsub::Behaviour, peer_id: PeerId) {
+        if self.peers.contains(&peer_id) {
+            return;
+        }
+        if self.peers.len() >= self.max {
+            debug!(%peer_id, "Not adding explicit peer, already at --max-explicit-peers");
+            return;
+        }
+
+        gossipsub.add_explicit_peer(&peer_id);
+        self.peers.insert(peer_id);
+    }
+
+    pub fn peers(&self) -> impl Iterator<Item = &PeerId> {
+        self.peers.iter()
+    }
+
+    pub fn remove(&mut self, gossipsub: &mut gossipsub::Behaviour, peer_id: &PeerId) {
+        if self.peers.remove(peer_id) {
+            gossipsub.remove_explicit_peer(peer_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::identity::Keypair;
+
+    fn gossipsub() -> gossipsub::Behaviour {
+        gossipsub::Behaviour::new(
+            gossipsub::MessageAuthenticity::Signed(Keypair::generate_ed25519()),
+            gossipsub::Config::default(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn removed_on_disconnect_and_added_again_on_reconnect() {
+        let mut gossipsub = gossipsub();
+        let mut explicit_peers = ExplicitPeers::new(1);
+        let peer = PeerId::random();
+        let other = PeerId::random();
+
+        explicit_peers.add(&mut gossipsub, peer);
+        explicit_peers.add(&mut gossipsub, other);
+        assert_eq!(explicit_peers.peers().collect::<Vec<_>>(), [&peer]);
+
+        // Disconnected: the slot is freed up for other peers.
+        explicit_peers.remove(&mut gossipsub, &peer);
+        assert_eq!(explicit_peers.peers().count(), 0);
+
+        // Reconnected and subscribed again.
+        explicit_peers.add(&mut gossipsub, peer);
+        assert_eq!(explicit_peers.peers().collect::<Vec<_>>(), [&peer]);
+    }
+}
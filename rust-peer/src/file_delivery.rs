@@ -0,0 +1,67 @@
+use crate::file_exchange::FileAck;
+use libp2p::{request_response::InboundRequestId, PeerId};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Follows the files we serve from being queued, through being written, to being acknowledged.
+///
+/// Acknowledging is optional, so files that aren't acknowledged within `ack_timeout` are
+/// forgotten.
+pub struct FileDeliveries {
+    ack_timeout: Duration,
+    sending: HashMap<InboundRequestId, (PeerId, String, u64)>,
+    awaiting_ack: HashMap<(PeerId, String), (u64, Instant)>,
+}
+
+impl FileDeliveries {
+    pub fn new(ack_timeout: Duration) -> Self {
+        Self {
+            ack_timeout,
+            sending: HashMap::new(),
+            awaiting_ack: HashMap::new(),
+        }
+    }
+
+    pub fn on_response_queued(
+        &mut self,
+        request_id: InboundRequestId,
+        peer_id: PeerId,
+        file_id: String,
+        bytes: u64,
+    ) {
+        self.sending.insert(request_id, (peer_id, file_id, bytes));
+    }
+
+    pub fn on_response_sent(&mut self, request_id: InboundRequestId) {
+        if let Some((peer_id, file_id, bytes)) = self.sending.remove(&request_id) {
+            self.awaiting_ack
+                .insert((peer_id, file_id), (bytes, Instant::now()));
+        }
+    }
+
+    /// Returns the file whose transfer failed, if `request_id` was one we were sending, e.g.
+    /// because the requester disconnected before it was fully written.
+    pub fn on_inbound_failure(&mut self, request_id: InboundRequestId) -> Option<String> {
+        let (_, file_id, _) = self.sending.remove(&request_id)?;
+
+        Some(file_id)
+    }
+
+    /// Returns the number of bytes we sent, or `None` for acknowledgements of files we didn't
+    /// send or sent too long ago.
+    pub fn on_ack(&mut self, peer_id: PeerId, ack: &FileAck) -> Option<u64> {
+        let (bytes_sent, _) = self.awaiting_ack.remove(&(peer_id, ack.file_id.clone()))?;
+
+        Some(bytes_sent)
+    }
+
+    pub fn on_peer_disconnected(&mut self, peer_id: &PeerId) {
+        self.awaiting_ack.retain(|(peer, _), _| peer != peer_id);
+    }
+
+    pub fn evict_expired(&mut self) {
+        let ack_timeout = self.ack_timeout;
+        self.awaiting_ack
+            .retain(|_, (_, sent)| sent.elapsed() < ack_timeout);
+    }
+}
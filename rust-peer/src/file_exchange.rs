@@ -0,0 +1,275 @@
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use libp2p::{request_response, StreamProtocol};
+use std::io;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncReadExt as _;
+
+pub const FILE_EXCHANGE_PROTOCOL: StreamProtocol =
+    StreamProtocol::new("/universal-connectivity-file/1");
+/// Optional follow-up to a file response, with which the requester confirms what it received.
+pub const FILE_ACK_PROTOCOL: StreamProtocol =
+    StreamProtocol::new("/universal-connectivity-file-ack/1");
+
+/// Upper bound for a request (a file id), so a peer can't make us buffer an endless stream.
+const MAX_REQUEST_SIZE: u64 = 1024;
+/// Upper bound for a response body we receive, and so for the files we serve.
+pub const MAX_RESPONSE_SIZE: u64 = 10 * 1024 * 1024;
+
+const RESPONSE_FILE: u8 = 0;
+const RESPONSE_ERROR: u8 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileRequest {
+    pub file_id: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileResponse {
+    /// A file we serve, of `size` bytes when we looked. It is only read when the response is
+    /// written, one chunk at a time.
+    File { path: PathBuf, size: u64 },
+    /// The contents of a file we received.
+    Received(Vec<u8>),
+    /// The file could not be served, with a human readable reason.
+    Error(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileAck {
+    pub file_id: String,
+    pub bytes_received: u64,
+}
+
+/// Codec for the file exchange protocol.
+///
+/// A request is the UTF-8 encoded file id. A response is a single tag byte
+/// followed by either the file contents or a UTF-8 error message. Both sides
+/// close their write half when done, so no length prefix is needed.
+///
+/// Files are streamed from disk in chunks of `chunk_size`. Each chunk is only read once the previous
+/// one was written, so a slow reader holds up the read rather than making us buffer the file.
+#[derive(Debug, Clone)]
+pub struct FileExchangeCodec {
+    chunk_size: NonZeroUsize,
+}
+
+impl FileExchangeCodec {
+    pub fn new(chunk_size: NonZeroUsize) -> Self {
+        Self { chunk_size }
+    }
+}
+
+#[async_trait]
+impl request_response::Codec for FileExchangeCodec {
+    type Protocol = StreamProtocol;
+    type Request = FileRequest;
+    type Response = FileResponse;
+
+    async fn read_request<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<FileRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let mut buf = Vec::new();
+        io.take(MAX_REQUEST_SIZE).read_to_end(&mut buf).await?;
+
+        let file_id =
+            String::from_utf8(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        Ok(FileRequest { file_id })
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+    ) -> io::Result<FileResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let mut tag = [0u8; 1];
+        io.read_exact(&mut tag).await?;
+
+        let mut buf = Vec::new();
+        io.take(MAX_RESPONSE_SIZE).read_to_end(&mut buf).await?;
+        // A file cut off at the limit mustn't pass for the whole file.
+        if io.read(&mut [0u8; 1]).await? > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("file response exceeds {MAX_RESPONSE_SIZE} bytes"),
+            ));
+        }
+
+        match tag[0] {
+            RESPONSE_FILE => Ok(FileResponse::Received(buf)),
+            RESPONSE_ERROR => Ok(FileResponse::Error(
+                String::from_utf8_lossy(&buf).into_owned(),
+            )),
+            other => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown file response tag {other}"),
+            )),
+        }
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        FileRequest { file_id }: FileRequest,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        io.write_all(file_id.as_bytes()).await?;
+        io.close().await?;
+
+        Ok(())
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        response: FileResponse,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        match response {
+            FileResponse::File { path, .. } => {
+                let mut file = tokio::fs::File::open(&path).await?;
+                io.write_all(&[RESPONSE_FILE]).await?;
+
+                let mut chunk = vec![0; self.chunk_size.get()];
+                loop {
+                    let n = file.read(&mut chunk).await?;
+                    if n == 0 {
+                        break;
+                    }
+                    io.write_all(&chunk[..n]).await?;
+                }
+            }
+            FileResponse::Received(body) => {
+                io.write_all(&[RESPONSE_FILE]).await?;
+                io.write_all(&body).await?;
+            }
+            FileResponse::Error(reason) => {
+                io.write_all(&[RESPONSE_ERROR]).await?;
+                io.write_all(reason.as_bytes()).await?;
+            }
+        }
+        io.close().await?;
+
+        Ok(())
+    }
+}
+
+/// Codec for the file acknowledgement protocol.
+///
+/// A request is the number of bytes received as a big-endian `u64`, followed by the UTF-8 encoded
+/// file id. The response is empty, the stream is just closed.
+#[derive(Debug, Clone, Default)]
+pub struct FileAckCodec;
+
+#[async_trait]
+impl request_response::Codec for FileAckCodec {
+    type Protocol = StreamProtocol;
+    type Request = FileAck;
+    type Response = ();
+
+    async fn read_request<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<FileAck>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let mut bytes_received = [0u8; 8];
+        io.read_exact(&mut bytes_received).await?;
+
+        let mut buf = Vec::new();
+        io.take(MAX_REQUEST_SIZE).read_to_end(&mut buf).await?;
+        let file_id =
+            String::from_utf8(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        Ok(FileAck {
+            file_id,
+            bytes_received: u64::from_be_bytes(bytes_received),
+        })
+    }
+
+    async fn read_response<T>(&mut self, _: &StreamProtocol, _: &mut T) -> io::Result<()>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        Ok(())
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        FileAck {
+            file_id,
+            bytes_received,
+        }: FileAck,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        io.write_all(&bytes_received.to_be_bytes()).await?;
+        io.write_all(file_id.as_bytes()).await?;
+        io.close().await?;
+
+        Ok(())
+    }
+
+    async fn write_response<T>(&mut self, _: &StreamProtocol, io: &mut T, (): ()) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        io.close().await?;
+
+        Ok(())
+    }
+}
+
+/// Resolves a requested file id to a path inside `file_dir`.
+///
+/// Only plain file names are accepted, anything containing a path separator or
+/// `..` is rejected so peers can't read outside of the served directory.
+pub fn resolve_file_path(file_dir: &Path, file_id: &str) -> Option<PathBuf> {
+    if file_id.is_empty()
+        || file_id == "."
+        || file_id == ".."
+        || file_id.contains(['/', '\\', '\0'])
+    {
+        return None;
+    }
+
+    Some(file_dir.join(file_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::io::Cursor;
+    use request_response::Codec;
+
+    fn read_response(body: Vec<u8>) -> io::Result<FileResponse> {
+        let mut io = Cursor::new([&[RESPONSE_FILE][..], &body].concat());
+        let mut codec = FileExchangeCodec::new(NonZeroUsize::new(1024).unwrap());
+        futures::executor::block_on(codec.read_response(&FILE_EXCHANGE_PROTOCOL, &mut io))
+    }
+
+    #[test]
+    fn reads_files_up_to_the_limit() {
+        let body = vec![1; MAX_RESPONSE_SIZE as usize];
+        assert_eq!(read_response(body.clone()).unwrap(), FileResponse::Received(body));
+    }
+
+    #[test]
+    fn fails_on_files_over_the_limit() {
+        let error = read_response(vec![1; MAX_RESPONSE_SIZE as usize + 1]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,172 @@
+use anyhow::{Context, Result};
+use libp2p::multiaddr::{Multiaddr, Protocol};
+use maxminddb::{geoip2, MaxMindDBError, Reader};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use tracing::{debug, info};
+
+use crate::ip_filter::remote_ip;
+
+/// Lookups we keep before starting over.
+const MAX_CACHED: usize = 10_000;
+
+/// Where a remote IP address is located, as far as the databases know.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Origin {
+    /// ISO 3166-1 country code.
+    pub country: Option<String>,
+    /// Autonomous system number.
+    pub asn: Option<u32>,
+}
+
+/// Looks up the origin of remote IP addresses in the MaxMind DB files of `--geoip-db`, e.g.
+/// GeoLite2-Country and GeoLite2-ASN.
+///
+/// Everything is offline: the files are read into memory once at startup, and each IP address is
+/// only looked up in them the first time we see it.
+pub struct GeoIp {
+    databases: Vec<Reader<Vec<u8>>>,
+    cache: HashMap<IpAddr, Origin>,
+}
+
+impl GeoIp {
+    pub fn open(paths: &[PathBuf]) -> Result<Self> {
+        let databases = paths
+            .iter()
+            .map(|path| {
+                let database = Reader::open_readfile(path)
+                    .with_context(|| format!("Failed to open GeoIP database {}", path.display()))?;
+                info!(
+                    path = %path.display(),
+                    database_type = database.metadata.database_type,
+                    node_count = database.metadata.node_count,
+                    "Opened GeoIP database"
+                );
+
+                Ok(database)
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            databases,
+            cache: HashMap::new(),
+        })
+    }
+
+    /// The origin of the peer at `addr`, or none for relayed addresses, whose IP is the relay's.
+    pub fn origin(&mut self, addr: &Multiaddr) -> Option<Origin> {
+        if addr.iter().any(|protocol| protocol == Protocol::P2pCircuit) {
+            return None;
+        }
+        let ip = remote_ip(addr)?;
+
+        if let Some(origin) = self.cache.get(&ip) {
+            return Some(origin.clone());
+        }
+        if self.cache.len() >= MAX_CACHED {
+            self.cache.clear();
+        }
+
+        // IPv4 databases can't look up IPv6 addresses, not even the IPv4-mapped ones.
+        let lookup_ip = match ip {
+            IpAddr::V6(ip) => ip.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(IpAddr::V6(ip)),
+            ip => ip,
+        };
+        let mut origin = Origin::default();
+        for database in &self.databases {
+            if lookup_ip.is_ipv6() && database.metadata.ip_version == 4 {
+                continue;
+            }
+            let (country, asn) = match (database.lookup::<geoip2::Country>(lookup_ip), database.lookup::<geoip2::Asn>(lookup_ip)) {
+                (Ok(country), Ok(asn)) => (country, asn),
+                (Err(MaxMindDBError::AddressNotFoundError(_)), _) => continue,
+                (Err(e), _) | (_, Err(e)) => {
+                    debug!(%ip, %e, "GeoIP lookup failed");
+                    continue;
+                }
+            };
+            origin.country = origin.country.or_else(|| {
+                country
+                    .country
+                    .and_then(|country| country.iso_code)
+                    .or_else(|| country.registered_country.and_then(|country| country.iso_code))
+                    .map(str::to_string)
+            });
+            origin.asn = origin.asn.or(asn.autonomous_system_number);
+        }
+        self.cache.insert(ip, origin.clone());
+
+        Some(origin)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    /// Opens databases from `testdata/geoip`, which each map 127.0.0.0/8 and nothing else:
+    ///
+    /// - `country.mmdb`: IPv6, country DE.
+    /// - `asn.mmdb`: IPv4, autonomous system 64512.
+    /// - `registered-country.mmdb`: IPv6, only the registered country FR.
+    fn geoip(names: &[&str]) -> Result<GeoIp> {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/geoip");
+        GeoIp::open(&names.iter().map(|name| dir.join(name)).collect::<Vec<_>>())
+    }
+
+    fn origin(country: Option<&str>, asn: Option<u32>) -> Option<Origin> {
+        Some(Origin {
+            country: country.map(str::to_string),
+            asn,
+        })
+    }
+
+    #[test]
+    fn combines_the_databases() {
+        let mut geoip = geoip(&["country.mmdb", "asn.mmdb"]).unwrap();
+
+        let addr = "/ip4/127.0.0.1/tcp/1234".parse().unwrap();
+        assert_eq!(geoip.origin(&addr), origin(Some("DE"), Some(64512)));
+    }
+
+    #[test]
+    fn falls_back_to_the_registered_country() {
+        let mut geoip = geoip(&["registered-country.mmdb"]).unwrap();
+
+        let addr = "/ip4/127.1.2.3/udp/9091/quic-v1".parse().unwrap();
+        assert_eq!(geoip.origin(&addr), origin(Some("FR"), None));
+    }
+
+    #[test]
+    fn looks_up_ipv4_mapped_addresses_as_ipv4() {
+        let mut geoip = geoip(&["country.mmdb", "asn.mmdb"]).unwrap();
+
+        let addr = "/ip6/::ffff:127.0.0.1/tcp/1234".parse().unwrap();
+        assert_eq!(geoip.origin(&addr), origin(Some("DE"), Some(64512)));
+        // Answered from the cache the second time.
+        assert_eq!(geoip.origin(&addr), origin(Some("DE"), Some(64512)));
+    }
+
+    #[test]
+    fn knows_nothing_about_other_addresses() {
+        let mut geoip = geoip(&["country.mmdb", "asn.mmdb"]).unwrap();
+
+        let unknown = "/ip4/192.0.2.1/tcp/1234".parse().unwrap();
+        assert_eq!(geoip.origin(&unknown), origin(None, None));
+        let ipv6 = "/ip6/2001:db8::1/tcp/1234".parse().unwrap();
+        assert_eq!(geoip.origin(&ipv6), origin(None, None));
+        let relayed = "/ip4/127.0.0.1/tcp/1234/p2p/12D3KooWDpJ7As7BWAwRMfu1VU2WCqNjvq387JEYKDBj4kx6nXTN/p2p-circuit"
+            .parse()
+            .unwrap();
+        assert_eq!(geoip.origin(&relayed), None);
+    }
+
+    #[test]
+    fn fails_on_files_that_are_not_databases() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml");
+        let error = GeoIp::open(&[path]).err().unwrap();
+        assert!(format!("{error:#}").contains("Failed to open GeoIP database"), "{error:#}");
+    }
+}
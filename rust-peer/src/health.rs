@@ -0,0 +1,102 @@
+use anyhow::Result;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info};
+
+use crate::http::{read_request_path, write_response};
+
+/// State of the node shared between the main loop and the health server.
+#[derive(Clone)]
+pub struct Health {
+    pub connections: Arc<AtomicUsize>,
+    pub listeners: Arc<AtomicUsize>,
+    started: Instant,
+    /// Milliseconds since `started` at which the main loop last reported in.
+    heartbeat: Arc<AtomicU64>,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self {
+            connections: Arc::new(AtomicUsize::new(0)),
+            listeners: Arc::new(AtomicUsize::new(0)),
+            started: Instant::now(),
+            heartbeat: Arc::new(AtomicU64::new(0)),
+        }
+    }
+}
+
+impl Health {
+    /// Records that the main loop is still running.
+    pub fn heartbeat(&self) {
+        self.heartbeat
+            .store(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn since_heartbeat(&self) -> Duration {
+        let heartbeat = Duration::from_millis(self.heartbeat.load(Ordering::Relaxed));
+
+        self.started.elapsed().saturating_sub(heartbeat)
+    }
+}
+
+/// Serves `/live` and `/ready` on `addr`.
+///
+/// `/live` fails once the main loop hasn't reported in for `liveness_timeout`, `/ready` fails
+/// while we have no listeners or no connections.
+pub async fn serve(addr: SocketAddr, health: Health, liveness_timeout: Duration) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!(%addr, "Serving health checks on /live and /ready");
+
+    loop {
+        let (stream, remote) = listener.accept().await?;
+        let health = health.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = handle_request(stream, &health, liveness_timeout).await {
+                debug!(%remote, %e, "Failed to handle health request");
+            }
+        });
+    }
+}
+
+async fn handle_request(
+    mut stream: TcpStream,
+    health: &Health,
+    liveness_timeout: Duration,
+) -> Result<()> {
+    let Some(path) = read_request_path(&mut stream).await? else {
+        return write_response(&mut stream, "400 Bad Request", "text/plain", "").await;
+    };
+
+    let problem = match path.as_str() {
+        "/live" => (health.since_heartbeat() > liveness_timeout)
+            .then_some("event loop is not responding"),
+        "/ready" => {
+            if health.listeners.load(Ordering::Relaxed) == 0 {
+                Some("no active listeners")
+            } else if health.connections.load(Ordering::Relaxed) == 0 {
+                Some("no connected peers")
+            } else {
+                None
+            }
+        }
+        _ => return write_response(&mut stream, "404 Not Found", "text/plain", "").await,
+    };
+
+    match problem {
+        None => write_response(&mut stream, "200 OK", "text/plain", "ok\n").await,
+        Some(problem) => {
+            write_response(
+                &mut stream,
+                "503 Service Unavailable",
+                "text/plain",
+                &format!("{problem}\n"),
+            )
+            .await
+        }
+    }
+}
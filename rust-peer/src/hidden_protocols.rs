@@ -0,0 +1,460 @@
+use libp2p::{
+    core::{upgrade::{InboundUpgrade, UpgradeInfo}, Endpoint},
+    swarm::{
+        handler::{
+            ConnectionEvent, FullyNegotiatedInbound, InboundUpgradeSend, ListenUpgradeError,
+            ProtocolsChange, UpgradeInfoSend,
+        },
+        ConnectionDenied, ConnectionHandler, ConnectionHandlerEvent, ConnectionId, FromSwarm,
+        NetworkBehaviour, Stream, SubstreamProtocol, THandler, THandlerInEvent, THandlerOutEvent,
+        ToSwarm,
+    },
+    Multiaddr, PeerId,
+};
+use std::collections::HashSet;
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tracing::warn;
+
+/// Protocols from `--identify-hide-protocols`, which we keep supporting but leave out of the
+/// protocol list identify sends to peers.
+///
+/// Identify reports whatever protocols the swarm says the connection supports, and has no way to
+/// leave some out. So the behaviours owning hidden protocols are wrapped in [`Unadvertised`],
+/// which keeps them out of the first protocol list of every connection and only adds them right
+/// after, and identify is wrapped in [`FilteredIdentify`], which ignores that addition.
+pub type HiddenProtocols = Arc<HashSet<String>>;
+
+/// A behaviour whose hidden protocols are announced to the connection separately from the others,
+/// so [`FilteredIdentify`] can tell them apart.
+pub struct Unadvertised<B> {
+    inner: B,
+    hidden: HiddenProtocols,
+}
+
+impl<B> Unadvertised<B> {
+    pub fn new(inner: B, hidden: &HiddenProtocols) -> Self {
+        Self {
+            inner,
+            hidden: hidden.clone(),
+        }
+    }
+}
+
+impl<B> Deref for Unadvertised<B> {
+    type Target = B;
+
+    fn deref(&self) -> &B {
+        &self.inner
+    }
+}
+
+impl<B> DerefMut for Unadvertised<B> {
+    fn deref_mut(&mut self) -> &mut B {
+        &mut self.inner
+    }
+}
+
+impl<B: NetworkBehaviour> NetworkBehaviour for Unadvertised<B> {
+    type ConnectionHandler = UnadvertisedHandler<THandler<B>>;
+    type ToSwarm = B::ToSwarm;
+
+    fn handle_pending_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<(), ConnectionDenied> {
+        self.inner
+            .handle_pending_inbound_connection(connection_id, local_addr, remote_addr)
+    }
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        let handler = self.inner.handle_established_inbound_connection(
+            connection_id,
+            peer,
+            local_addr,
+            remote_addr,
+        )?;
+
+        Ok(UnadvertisedHandler::new(handler, &self.hidden))
+    }
+
+    fn handle_pending_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        maybe_peer: Option<PeerId>,
+        addresses: &[Multiaddr],
+        effective_role: Endpoint,
+    ) -> Result<Vec<Multiaddr>, ConnectionDenied> {
+        self.inner.handle_pending_outbound_connection(
+            connection_id,
+            maybe_peer,
+            addresses,
+            effective_role,
+        )
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        addr: &Multiaddr,
+        role_override: Endpoint,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        let handler = self.inner.handle_established_outbound_connection(
+            connection_id,
+            peer,
+            addr,
+            role_override,
+        )?;
+
+        Ok(UnadvertisedHandler::new(handler, &self.hidden))
+    }
+
+    fn on_swarm_event(&mut self, event: FromSwarm) {
+        self.inner.on_swarm_event(event)
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        peer_id: PeerId,
+        connection_id: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        self.inner
+            .on_connection_handler_event(peer_id, connection_id, event)
+    }
+
+    fn poll(&mut self, cx: &mut Context<'_>) -> Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
+        self.inner.poll(cx)
+    }
+}
+
+/// Leaves the hidden protocols out of the protocols it listens on until the connection reported
+/// the others. Streams can't be opened before that, so none of them are refused.
+pub struct UnadvertisedHandler<H> {
+    inner: H,
+    hidden: HiddenProtocols,
+    announced: bool,
+}
+
+impl<H> UnadvertisedHandler<H> {
+    fn new(inner: H, hidden: &HiddenProtocols) -> Self {
+        Self {
+            inner,
+            hidden: hidden.clone(),
+            announced: false,
+        }
+    }
+}
+
+impl<H: ConnectionHandler> ConnectionHandler for UnadvertisedHandler<H> {
+    type FromBehaviour = H::FromBehaviour;
+    type ToBehaviour = H::ToBehaviour;
+    type InboundProtocol = UnadvertisedUpgrade<H::InboundProtocol>;
+    type OutboundProtocol = H::OutboundProtocol;
+    type InboundOpenInfo = H::InboundOpenInfo;
+    type OutboundOpenInfo = H::OutboundOpenInfo;
+
+    fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol, Self::InboundOpenInfo> {
+        let hidden = (!self.announced).then(|| self.hidden.clone());
+
+        self.inner
+            .listen_protocol()
+            .map_upgrade(|inner| UnadvertisedUpgrade { inner, hidden })
+    }
+
+    fn connection_keep_alive(&self) -> bool {
+        self.inner.connection_keep_alive()
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<
+        ConnectionHandlerEvent<Self::OutboundProtocol, Self::OutboundOpenInfo, Self::ToBehaviour>,
+    > {
+        self.inner.poll(cx)
+    }
+
+    fn poll_close(&mut self, cx: &mut Context<'_>) -> Poll<Option<Self::ToBehaviour>> {
+        self.inner.poll_close(cx)
+    }
+
+    fn on_behaviour_event(&mut self, event: Self::FromBehaviour) {
+        self.inner.on_behaviour_event(event)
+    }
+
+    fn on_connection_event(
+        &mut self,
+        event: ConnectionEvent<
+            Self::InboundProtocol,
+            Self::OutboundProtocol,
+            Self::InboundOpenInfo,
+            Self::OutboundOpenInfo,
+        >,
+    ) {
+        let event = match event {
+            ConnectionEvent::FullyNegotiatedInbound(FullyNegotiatedInbound { protocol, info }) => {
+                ConnectionEvent::FullyNegotiatedInbound(FullyNegotiatedInbound { protocol, info })
+            }
+            ConnectionEvent::ListenUpgradeError(ListenUpgradeError { info, error }) => {
+                ConnectionEvent::ListenUpgradeError(ListenUpgradeError { info, error })
+            }
+            ConnectionEvent::FullyNegotiatedOutbound(e) => ConnectionEvent::FullyNegotiatedOutbound(e),
+            ConnectionEvent::DialUpgradeError(e) => ConnectionEvent::DialUpgradeError(e),
+            ConnectionEvent::AddressChange(e) => ConnectionEvent::AddressChange(e),
+            ConnectionEvent::LocalProtocolsChange(change) => {
+                // The first one lists the protocols the connection started out with, the hidden
+                // ones are reported after it.
+                self.announced = true;
+                ConnectionEvent::LocalProtocolsChange(change)
+            }
+            ConnectionEvent::RemoteProtocolsChange(change) => {
+                ConnectionEvent::RemoteProtocolsChange(change)
+            }
+            // The events carry the type of our inbound upgrade, so each one has to be rebuilt for
+            // the inner handler, which only works for the variants we know. This covers all of
+            // them in this libp2p version, and new ones have to be added here when upgrading.
+            _ => {
+                warn!("Dropping a connection event unknown to the hidden protocols wrapper");
+                return;
+            }
+        };
+
+        self.inner.on_connection_event(event)
+    }
+}
+
+/// An inbound upgrade that doesn't list the `hidden` protocols, if set.
+pub struct UnadvertisedUpgrade<U> {
+    inner: U,
+    hidden: Option<HiddenProtocols>,
+}
+
+impl<U: UpgradeInfoSend> UpgradeInfo for UnadvertisedUpgrade<U> {
+    type Info = U::Info;
+    type InfoIter = std::vec::IntoIter<U::Info>;
+
+    fn protocol_info(&self) -> Self::InfoIter {
+        self.inner
+            .protocol_info()
+            .filter(|info| {
+                self.hidden
+                    .as_ref()
+                    .is_none_or(|hidden| !hidden.contains(info.as_ref()))
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+}
+
+impl<U: InboundUpgradeSend> InboundUpgrade<Stream> for UnadvertisedUpgrade<U> {
+    type Output = U::Output;
+    type Error = U::Error;
+    type Future = U::Future;
+
+    fn upgrade_inbound(self, stream: Stream, info: U::Info) -> Self::Future {
+        InboundUpgradeSend::upgrade_inbound(self.inner, stream, info)
+    }
+}
+
+/// Identify, minus the [`HiddenProtocols`].
+///
+/// Ignores changes to the local protocols that only consist of hidden ones, which is how
+/// [`Unadvertised`] reports them. Identify never learns about them as a result, so it neither
+/// lists nor pushes them.
+pub struct FilteredIdentify<B> {
+    inner: B,
+    hidden: HiddenProtocols,
+}
+
+impl<B> FilteredIdentify<B> {
+    pub fn new(inner: B, hidden: &HiddenProtocols) -> Self {
+        Self {
+            inner,
+            hidden: hidden.clone(),
+        }
+    }
+}
+
+impl<B> Deref for FilteredIdentify<B> {
+    type Target = B;
+
+    fn deref(&self) -> &B {
+        &self.inner
+    }
+}
+
+impl<B> DerefMut for FilteredIdentify<B> {
+    fn deref_mut(&mut self) -> &mut B {
+        &mut self.inner
+    }
+}
+
+impl<B: NetworkBehaviour> NetworkBehaviour for FilteredIdentify<B> {
+    type ConnectionHandler = FilteredHandler<THandler<B>>;
+    type ToSwarm = B::ToSwarm;
+
+    fn handle_pending_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<(), ConnectionDenied> {
+        self.inner
+            .handle_pending_inbound_connection(connection_id, local_addr, remote_addr)
+    }
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        let inner = self.inner.handle_established_inbound_connection(
+            connection_id,
+            peer,
+            local_addr,
+            remote_addr,
+        )?;
+
+        Ok(FilteredHandler {
+            inner,
+            hidden: self.hidden.clone(),
+        })
+    }
+
+    fn handle_pending_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        maybe_peer: Option<PeerId>,
+        addresses: &[Multiaddr],
+        effective_role: Endpoint,
+    ) -> Result<Vec<Multiaddr>, ConnectionDenied> {
+        self.inner.handle_pending_outbound_connection(
+            connection_id,
+            maybe_peer,
+            addresses,
+            effective_role,
+        )
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        addr: &Multiaddr,
+        role_override: Endpoint,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        let inner = self.inner.handle_established_outbound_connection(
+            connection_id,
+            peer,
+            addr,
+            role_override,
+        )?;
+
+        Ok(FilteredHandler {
+            inner,
+            hidden: self.hidden.clone(),
+        })
+    }
+
+    fn on_swarm_event(&mut self, event: FromSwarm) {
+        self.inner.on_swarm_event(event)
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        peer_id: PeerId,
+        connection_id: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        self.inner
+            .on_connection_handler_event(peer_id, connection_id, event)
+    }
+
+    fn poll(&mut self, cx: &mut Context<'_>) -> Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
+        self.inner.poll(cx)
+    }
+}
+
+pub struct FilteredHandler<H> {
+    inner: H,
+    hidden: HiddenProtocols,
+}
+
+impl<H> FilteredHandler<H> {
+    fn only_hidden(&self, change: &ProtocolsChange) -> bool {
+        let protocols = match change.clone() {
+            ProtocolsChange::Added(added) => added.collect::<Vec<_>>(),
+            ProtocolsChange::Removed(removed) => removed.collect::<Vec<_>>(),
+        };
+
+        !protocols.is_empty()
+            && protocols
+                .iter()
+                .all(|protocol| self.hidden.contains(protocol.as_ref()))
+    }
+}
+
+impl<H: ConnectionHandler> ConnectionHandler for FilteredHandler<H> {
+    type FromBehaviour = H::FromBehaviour;
+    type ToBehaviour = H::ToBehaviour;
+    type InboundProtocol = H::InboundProtocol;
+    type OutboundProtocol = H::OutboundProtocol;
+    type InboundOpenInfo = H::InboundOpenInfo;
+    type OutboundOpenInfo = H::OutboundOpenInfo;
+
+    fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol, Self::InboundOpenInfo> {
+        self.inner.listen_protocol()
+    }
+
+    fn connection_keep_alive(&self) -> bool {
+        self.inner.connection_keep_alive()
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<
+        ConnectionHandlerEvent<Self::OutboundProtocol, Self::OutboundOpenInfo, Self::ToBehaviour>,
+    > {
+        self.inner.poll(cx)
+    }
+
+    fn poll_close(&mut self, cx: &mut Context<'_>) -> Poll<Option<Self::ToBehaviour>> {
+        self.inner.poll_close(cx)
+    }
+
+    fn on_behaviour_event(&mut self, event: Self::FromBehaviour) {
+        self.inner.on_behaviour_event(event)
+    }
+
+    fn on_connection_event(
+        &mut self,
+        event: ConnectionEvent<
+            Self::InboundProtocol,
+            Self::OutboundProtocol,
+            Self::InboundOpenInfo,
+            Self::OutboundOpenInfo,
+        >,
+    ) {
+        if let ConnectionEvent::LocalProtocolsChange(change) = &event {
+            if self.only_hidden(change) {
+                return;
+            }
+        }
+
+        self.inner.on_connection_event(event)
+    }
+}
@@ -0,0 +1,26 @@
+use libp2p::{identify, Multiaddr, PeerId, StreamProtocol};
+use std::collections::{HashMap, HashSet};
+
+/// Remembers what each peer last told us via identify, so repeated identical exchanges don't have
+/// to be logged.
+#[derive(Default)]
+pub struct IdentifyLog {
+    seen: HashMap<PeerId, (HashSet<StreamProtocol>, HashSet<Multiaddr>)>,
+}
+
+impl IdentifyLog {
+    /// Records the info received from `peer_id`, returning whether its protocols or listen
+    /// addresses differ from last time.
+    pub fn changed(&mut self, peer_id: PeerId, info: &identify::Info) -> bool {
+        let current = (
+            info.protocols.iter().cloned().collect(),
+            info.listen_addrs.iter().cloned().collect(),
+        );
+
+        self.seen.insert(peer_id, current.clone()) != Some(current)
+    }
+
+    pub fn remove(&mut self, peer_id: &PeerId) {
+        self.seen.remove(peer_id);
+    }
+}
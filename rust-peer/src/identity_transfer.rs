@@ -0,0 +1,79 @@
+use anyhow::{bail, Context, Result};
+use base64::Engine;
+use libp2p::identity;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use tracing::info;
+
+/// Prints the identity at `path` to stdout as base64 encoded protobuf, the format
+/// `import-identity` and `LOCAL_KEY_BASE64` take. Asks for confirmation first unless `yes` is set,
+/// since anyone with the key can impersonate the node.
+pub fn export(path: &Path, yes: bool) -> Result<()> {
+    let bytes = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let identity = identity::Keypair::from_protobuf_encoding(&bytes)
+        .with_context(|| format!("Invalid identity in {}", path.display()))?;
+
+    if !yes && !confirm(&format!(
+        "This prints the private key of {} to stdout. Continue? [y/N] ",
+        identity.public().to_peer_id()
+    ))? {
+        bail!("Aborted");
+    }
+
+    println!("{}", base64::engine::general_purpose::STANDARD.encode(bytes));
+
+    Ok(())
+}
+
+/// Writes the base64 encoded identity `encoded`, or the one read from stdin if not given, to
+/// `path`. An existing identity is only replaced if `force` is set.
+pub fn import(path: &Path, encoded: Option<String>, force: bool) -> Result<()> {
+    let encoded = match encoded {
+        Some(encoded) => encoded,
+        None => io::stdin()
+            .lock()
+            .lines()
+            .next()
+            .context("Expected the identity on stdin")??,
+    };
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(encoded.trim())
+        .context("Invalid base64")?;
+    let identity =
+        identity::Keypair::from_protobuf_encoding(&bytes).context("Invalid identity")?;
+
+    if path.exists() && !force {
+        bail!("{} already exists, pass --force to replace it", path.display());
+    }
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    file.write_all(&bytes)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+
+    info!(
+        "Imported {} identity {} to {}",
+        identity.key_type(),
+        identity.public().to_peer_id(),
+        path.display()
+    );
+
+    Ok(())
+}
+
+fn confirm(prompt: &str) -> Result<bool> {
+    eprint!("{prompt}");
+    io::stderr().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
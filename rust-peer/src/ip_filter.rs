@@ -0,0 +1,200 @@
+use ipnet::IpNet;
+use libp2p::{
+    core::Endpoint,
+    multiaddr::{Multiaddr, Protocol},
+    swarm::{
+        dummy, ConnectionDenied, ConnectionId, FromSwarm, NetworkBehaviour, THandler,
+        THandlerInEvent, THandlerOutEvent, ToSwarm,
+    },
+    PeerId,
+};
+use std::convert::Infallible;
+use std::fmt;
+use std::net::IpAddr;
+use std::task::{Context, Poll};
+
+/// Decides which remote IP addresses may be connected to us, based on `--allow-cidr` and
+/// `--deny-cidr`.
+///
+/// Connections from and to denied addresses are refused before they are established, so the rest
+/// of the node never sees them.
+pub struct IpFilter {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+impl IpFilter {
+    pub fn new(allow: Vec<IpNet>, deny: Vec<IpNet>) -> Self {
+        Self { allow, deny }
+    }
+
+    /// Whether a connection to `addr` may stay open. Denied ranges win over allowed ones, and
+    /// without an allowlist every range that isn't denied is allowed.
+    ///
+    /// Relayed addresses are always allowed, since their IP is the relay's rather than the peer's.
+    /// IPv4-mapped IPv6 addresses are matched against the IPv4 ranges too, so e.g. a denied
+    /// `10.0.0.0/8` can't be bypassed by connecting from `::ffff:10.0.0.1`.
+    pub fn allows(&self, addr: &Multiaddr) -> bool {
+        if addr.iter().any(|protocol| protocol == Protocol::P2pCircuit) {
+            return true;
+        }
+        let Some(ip) = remote_ip(addr) else {
+            return true;
+        };
+        let mapped = match ip {
+            IpAddr::V6(ip) => ip.to_ipv4_mapped().map(IpAddr::V4),
+            IpAddr::V4(_) => None,
+        };
+        let contains = |net: &IpNet| net.contains(&ip) || mapped.is_some_and(|mapped| net.contains(&mapped));
+
+        if self.deny.iter().any(contains) {
+            return false;
+        }
+
+        self.allow.is_empty() || self.allow.iter().any(contains)
+    }
+}
+
+/// Why [`IpFilter`] denied a connection.
+#[derive(Debug)]
+pub struct DeniedIp;
+
+impl fmt::Display for DeniedIp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "remote IP address is not allowed by --allow-cidr and --deny-cidr")
+    }
+}
+
+impl std::error::Error for DeniedIp {}
+
+impl IpFilter {
+    fn check(&self, addr: &Multiaddr) -> Result<(), ConnectionDenied> {
+        if self.allows(addr) {
+            Ok(())
+        } else {
+            Err(ConnectionDenied::new(DeniedIp))
+        }
+    }
+}
+
+impl NetworkBehaviour for IpFilter {
+    type ConnectionHandler = dummy::ConnectionHandler;
+    type ToSwarm = Infallible;
+
+    fn handle_pending_inbound_connection(
+        &mut self,
+        _: ConnectionId,
+        _: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<(), ConnectionDenied> {
+        self.check(remote_addr)
+    }
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        _: ConnectionId,
+        _: PeerId,
+        _: &Multiaddr,
+        _: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        Ok(dummy::ConnectionHandler)
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        _: ConnectionId,
+        _: PeerId,
+        addr: &Multiaddr,
+        _: Endpoint,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.check(addr)?;
+
+        Ok(dummy::ConnectionHandler)
+    }
+
+    fn on_swarm_event(&mut self, _: FromSwarm) {}
+
+    fn on_connection_handler_event(
+        &mut self,
+        _: PeerId,
+        _: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        match event {}
+    }
+
+    fn poll(&mut self, _: &mut Context<'_>) -> Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
+        Poll::Pending
+    }
+}
+
+pub fn remote_ip(addr: &Multiaddr) -> Option<IpAddr> {
+    addr.iter().find_map(|protocol| match protocol {
+        Protocol::Ip4(ip) => Some(IpAddr::V4(ip)),
+        Protocol::Ip6(ip) => Some(IpAddr::V6(ip)),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(allow: &[&str], deny: &[&str]) -> IpFilter {
+        let nets = |nets: &[&str]| nets.iter().map(|net| net.parse().unwrap()).collect();
+        IpFilter::new(nets(allow), nets(deny))
+    }
+
+    fn allows(filter: &IpFilter, addr: &str) -> bool {
+        filter.allows(&addr.parse().unwrap())
+    }
+
+    #[test]
+    fn allows_everything_not_denied_without_an_allowlist() {
+        let filter = filter(&[], &["10.0.0.0/8", "2001:db8::/32"]);
+
+        assert!(!allows(&filter, "/ip4/10.1.2.3/tcp/4001"));
+        assert!(!allows(&filter, "/ip6/2001:db8::1/udp/4001/quic-v1"));
+        assert!(allows(&filter, "/ip4/192.0.2.1/tcp/4001"));
+        assert!(allows(&filter, "/ip6/2001:db9::1/tcp/4001"));
+    }
+
+    #[test]
+    fn only_allows_the_allowlist() {
+        let filter = filter(&["192.0.2.0/24", "2001:db8::/32"], &[]);
+
+        assert!(allows(&filter, "/ip4/192.0.2.200/tcp/4001"));
+        assert!(allows(&filter, "/ip6/2001:db8:1::1/tcp/4001"));
+        assert!(!allows(&filter, "/ip4/198.51.100.1/tcp/4001"));
+        assert!(!allows(&filter, "/ip6/2001:db9::1/tcp/4001"));
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let filter = filter(&["192.0.2.0/24", "2001:db8::/32"], &["192.0.2.128/25", "2001:db8:bad::/48"]);
+
+        assert!(allows(&filter, "/ip4/192.0.2.1/tcp/4001"));
+        assert!(!allows(&filter, "/ip4/192.0.2.129/tcp/4001"));
+        assert!(allows(&filter, "/ip6/2001:db8::1/tcp/4001"));
+        assert!(!allows(&filter, "/ip6/2001:db8:bad::1/tcp/4001"));
+    }
+
+    #[test]
+    fn matches_ipv4_mapped_ipv6_against_ipv4_ranges() {
+        let deny = filter(&[], &["10.0.0.0/8"]);
+        assert!(!allows(&deny, "/ip6/::ffff:10.0.0.1/tcp/4001"));
+        assert!(allows(&deny, "/ip6/::ffff:192.0.2.1/tcp/4001"));
+
+        let allow = filter(&["192.0.2.0/24"], &[]);
+        assert!(allows(&allow, "/ip6/::ffff:192.0.2.1/tcp/4001"));
+        assert!(!allows(&allow, "/ip6/::ffff:10.0.0.1/tcp/4001"));
+    }
+
+    #[test]
+    fn always_allows_relayed_addresses() {
+        let filter = filter(&[], &["10.0.0.0/8"]);
+        let relayed = format!("/ip4/10.0.0.1/tcp/4001/p2p/{}/p2p-circuit", libp2p::PeerId::random());
+
+        assert!(allows(&filter, &relayed));
+    }
+}
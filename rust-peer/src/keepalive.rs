@@ -0,0 +1,162 @@
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use libp2p::core::ConnectedPoint;
+use libp2p::swarm::ConnectionId;
+use libp2p::{request_response, PeerId, StreamProtocol};
+use std::collections::{HashMap, HashSet};
+use std::io;
+
+/// Probes peers we only reach through a relay, echoing back a random nonce.
+pub const KEEPALIVE_PROTOCOL: StreamProtocol =
+    StreamProtocol::new("/universal-connectivity-keepalive/1");
+
+/// Notices relayed connections that died without either side closing them.
+///
+/// Relayed WebRTC connections in particular can linger after the circuit is gone, because the
+/// muxer on top still looks healthy. A request has to make it all the way through the circuit to
+/// the peer and back, so a few unanswered ones in a row mean the circuit is dead.
+///
+/// Request-response picks the connection a request goes over, so only peers whose connections are
+/// all relayed are probed.
+pub struct KeepAlive {
+    max_misses: u32,
+    /// Connections by peer, with whether they are relayed.
+    connections: HashMap<PeerId, HashMap<ConnectionId, bool>>,
+    misses: HashMap<PeerId, u32>,
+    /// Peers that don't speak the keep-alive protocol, like browsers running another
+    /// implementation, and which therefore can't be probed.
+    unsupported: HashSet<PeerId>,
+}
+
+impl KeepAlive {
+    pub fn new(max_misses: u32) -> Self {
+        Self {
+            max_misses,
+            connections: HashMap::new(),
+            misses: HashMap::new(),
+            unsupported: HashSet::new(),
+        }
+    }
+
+    pub fn on_connection_established(
+        &mut self,
+        peer_id: PeerId,
+        connection_id: ConnectionId,
+        endpoint: &ConnectedPoint,
+    ) {
+        self.connections
+            .entry(peer_id)
+            .or_default()
+            .insert(connection_id, endpoint.is_relayed());
+    }
+
+    pub fn on_connection_closed(&mut self, peer_id: &PeerId, connection_id: &ConnectionId) {
+        let Some(connections) = self.connections.get_mut(peer_id) else {
+            return;
+        };
+        connections.remove(connection_id);
+
+        if connections.is_empty() {
+            self.connections.remove(peer_id);
+            self.misses.remove(peer_id);
+            self.unsupported.remove(peer_id);
+        }
+    }
+
+    /// The peers to send a probe to.
+    pub fn peers_to_probe(&self) -> Vec<PeerId> {
+        self.connections
+            .iter()
+            .filter(|(peer_id, connections)| {
+                !self.unsupported.contains(*peer_id) && connections.values().all(|relayed| *relayed)
+            })
+            .map(|(peer_id, _)| *peer_id)
+            .collect()
+    }
+
+    pub fn on_answered(&mut self, peer_id: &PeerId) {
+        self.misses.remove(peer_id);
+    }
+
+    pub fn on_unsupported(&mut self, peer_id: PeerId) {
+        self.unsupported.insert(peer_id);
+    }
+
+    /// Records an unanswered probe. Returns the number of misses in a row and the relayed
+    /// connections to close once there are `max_misses` of them.
+    pub fn on_missed(&mut self, peer_id: PeerId) -> Option<(u32, Vec<ConnectionId>)> {
+        let misses = self.misses.entry(peer_id).or_default();
+        *misses += 1;
+        if *misses < self.max_misses {
+            return None;
+        }
+        let misses = self.misses.remove(&peer_id)?;
+
+        let connections = self
+            .connections
+            .get(&peer_id)?
+            .iter()
+            .filter(|(_, relayed)| **relayed)
+            .map(|(connection_id, _)| *connection_id)
+            .collect();
+
+        Some((misses, connections))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct KeepAliveCodec;
+
+#[async_trait]
+impl request_response::Codec for KeepAliveCodec {
+    type Protocol = StreamProtocol;
+    type Request = u64;
+    type Response = u64;
+
+    async fn read_request<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<u64>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_nonce(io).await
+    }
+
+    async fn read_response<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<u64>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_nonce(io).await
+    }
+
+    async fn write_request<T>(&mut self, _: &StreamProtocol, io: &mut T, nonce: u64) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_nonce(io, nonce).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        nonce: u64,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_nonce(io, nonce).await
+    }
+}
+
+async fn read_nonce<T: AsyncRead + Unpin + Send>(io: &mut T) -> io::Result<u64> {
+    let mut nonce = [0u8; 8];
+    io.read_exact(&mut nonce).await?;
+
+    Ok(u64::from_be_bytes(nonce))
+}
+
+async fn write_nonce<T: AsyncWrite + Unpin + Send>(io: &mut T, nonce: u64) -> io::Result<()> {
+    io.write_all(&nonce.to_be_bytes()).await?;
+    io.close().await?;
+
+    Ok(())
+}
@@ -0,0 +1,78 @@
+use libp2p::{
+    core::Endpoint,
+    swarm::{
+        dummy, ConnectionDenied, ConnectionId, FromSwarm, NetworkBehaviour, THandler,
+        THandlerInEvent, THandlerOutEvent, ToSwarm,
+    },
+    Multiaddr, PeerId,
+};
+use std::convert::Infallible;
+use std::fmt;
+use std::task::{Context, Poll};
+
+/// Denies every dial for `--listen-only`, whoever asks for it: our own code, Kademlia, the relay
+/// client or hole punching. The call sites we control skip dialing instead, so this is what
+/// catches the rest.
+pub struct ListenOnly;
+
+/// Why [`ListenOnly`] denied a dial.
+#[derive(Debug)]
+pub struct OutboundDisabled;
+
+impl fmt::Display for OutboundDisabled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "outbound dialing is disabled by --listen-only")
+    }
+}
+
+impl std::error::Error for OutboundDisabled {}
+
+impl NetworkBehaviour for ListenOnly {
+    type ConnectionHandler = dummy::ConnectionHandler;
+    type ToSwarm = Infallible;
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        _: ConnectionId,
+        _: PeerId,
+        _: &Multiaddr,
+        _: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        Ok(dummy::ConnectionHandler)
+    }
+
+    fn handle_pending_outbound_connection(
+        &mut self,
+        _: ConnectionId,
+        _: Option<PeerId>,
+        _: &[Multiaddr],
+        _: Endpoint,
+    ) -> Result<Vec<Multiaddr>, ConnectionDenied> {
+        Err(ConnectionDenied::new(OutboundDisabled))
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        _: ConnectionId,
+        _: PeerId,
+        _: &Multiaddr,
+        _: Endpoint,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        Err(ConnectionDenied::new(OutboundDisabled))
+    }
+
+    fn on_swarm_event(&mut self, _: FromSwarm) {}
+
+    fn on_connection_handler_event(
+        &mut self,
+        _: PeerId,
+        _: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        match event {}
+    }
+
+    fn poll(&mut self, _: &mut Context<'_>) -> Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
+        Poll::Pending
+    }
+}
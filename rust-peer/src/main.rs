@@ -1,57 +1,293 @@
-use anyhow::{Context, Result};
+mod admin;
+mod bandwidth;
+mod blocklist;
+mod bootstrap;
+mod cert;
+mod churn;
+mod command;
+mod compression;
+mod config;
+mod connection_age;
+mod connection_spans;
+mod connection_timing;
+mod dial_queue;
+mod discovery;
+mod event;
+mod event_log;
+mod explicit_peers;
+mod file_delivery;
+mod file_exchange;
+mod geoip;
+mod health;
+mod hidden_protocols;
+mod http;
+mod identify_log;
+mod identity_transfer;
+mod keepalive;
+mod listen_only;
+mod ip_filter;
+mod mesh_repair;
+mod memory_pruning;
+mod message_limits;
+mod message_validators;
+mod metrics;
+mod new_topics;
+mod observed_addrs;
+mod peerstore;
+mod probe;
+mod publish_throttle;
+mod relay_bytes;
+mod relay_failover;
+mod relay_stats;
+mod rtt;
+mod scoring;
+mod seen_messages;
+mod socket_activation;
+mod state_dump;
+mod topic_census;
+mod topic_query;
+mod transport_stats;
+
+use anyhow::{bail, Context, Result};
+use base64::Engine;
 use clap::Parser;
-use futures::future::{select, Either};
+use futures::stream::FuturesUnordered;
 use futures::StreamExt;
 // use futures::stream::StreamExt;
-use libp2p::request_response::{self, ProtocolSupport};
 use libp2p::{
-    core::muxing::StreamMuxerBox,
+    autonat,
+    core::{
+        muxing::StreamMuxerBox,
+        transport::{ListenerId, OptionalTransport},
+        upgrade,
+    },
+    dns,
     yamux, noise,
     tcp,
     ping,
+    quic,
     dcutr,
-    dns, gossipsub, identify, identity,
+    gossipsub, identify, identity,
+    kad::{self, store::MemoryStore},
+    mdns,
+    upnp,
+    connection_limits::{self, ConnectionLimits},
     memory_connection_limits,
+    allow_block_list,
     multiaddr::{Multiaddr, Protocol},
-    quic, relay,
-    swarm::{NetworkBehaviour, Swarm, SwarmEvent},
-    PeerId, StreamProtocol, SwarmBuilder, Transport
+    relay,
+    request_response::{self, ProtocolSupport},
+    swarm::{
+        behaviour::toggle::Toggle, ConnectionDenied, DialError, ListenError, NetworkBehaviour,
+        Swarm, SwarmEvent,
+    },
+    websocket,
+    PeerId, Transport
 };
+use libp2p::swarm::dial_opts::{DialOpts, PeerCondition};
+use hickory_resolver::config::NameServerConfigGroup;
 use libp2p_webrtc as webrtc;
 // use libp2p::Transport;
 use libp2p_webrtc::tokio::Certificate;
-use log::{debug, error, info, warn};
+use tracing::{debug, error, info, info_span, trace, warn, Span};
+use tracing_subscriber::EnvFilter;
 use prost::Message;
-use std::net::IpAddr;
-use std::path::Path;
+use prometheus_client::registry::Registry;
+use std::net::{IpAddr, SocketAddr};
+use std::num::{NonZeroU32, NonZeroU64, NonZeroUsize};
+use std::path::{Path, PathBuf};
 use std::{
-    collections::hash_map::DefaultHasher,
+    collections::{hash_map::DefaultHasher, HashSet},
     hash::{Hash, Hasher},
+    sync::{atomic::Ordering, Arc, Mutex},
     time::{Duration, Instant},
 };
 use tokio::fs;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+
+use crate::admin::{AdminCommand, AdminError, AdminRequest};
+use crate::bandwidth::Bandwidth;
+use crate::blocklist::Blocklist;
+use crate::bootstrap::Bootstrap;
+use crate::cert::RotationPolicy;
+use crate::churn::{Alert, ChurnMonitor, CHURN_INTERVAL};
+use crate::command::Command;
+use crate::connection_age::ConnectionAges;
+use crate::connection_spans::ConnectionSpans;
+use crate::connection_timing::ConnectionTimings;
+use crate::dial_queue::DialQueue;
+use crate::discovery::{DiscoveryCache, DiscoveryRelay};
+use crate::event::NetworkEvent;
+use crate::event_log::{EventRecorder, EventReplay};
+use crate::explicit_peers::ExplicitPeers;
+use crate::health::Health;
+use crate::hidden_protocols::{FilteredIdentify, HiddenProtocols, Unadvertised};
+use crate::identify_log::IdentifyLog;
+use crate::geoip::GeoIp;
+use crate::ip_filter::{DeniedIp, IpFilter};
+use crate::listen_only::{ListenOnly, OutboundDisabled};
+use crate::keepalive::{KeepAlive, KeepAliveCodec, KEEPALIVE_PROTOCOL};
+use crate::topic_query::{TopicQueryCodec, TOPIC_QUERY_PROTOCOL};
+use crate::file_delivery::FileDeliveries;
+use crate::file_exchange::{
+    FileAckCodec, FileExchangeCodec, FileRequest, FileResponse, FILE_ACK_PROTOCOL,
+    FILE_EXCHANGE_PROTOCOL,
+};
+use crate::memory_pruning::MemoryPruner;
+use crate::mesh_repair::MeshRepair;
+use crate::message_limits::MessageSizeLimits;
+use crate::message_validators::MessageValidators;
+use crate::metrics::Metrics;
+use crate::new_topics::NewTopics;
+use crate::observed_addrs::ObservedAddrs;
+use crate::peerstore::Peerstore;
+use crate::publish_throttle::{PendingPublish, PublishThrottle};
+use crate::relay_bytes::RelayBytes;
+use crate::relay_failover::RelayFailover;
+use crate::relay_stats::RelayStats;
+use crate::rtt::RttTracker;
+use crate::scoring::ScoreMonitor;
+use crate::seen_messages::SeenMessages;
+use crate::socket_activation::SocketActivated;
+use crate::topic_census::{TopicCensus, TOPIC_CENSUS_TOPIC};
+use crate::transport_stats::TransportStats;
 
 include!(concat!(env!("OUT_DIR"), "/decontact.rs"));
 
+/// Default topic for `--publish-status`.
+const STATUS_TOPIC: &str = "universal-connectivity-status";
 const TICK_INTERVAL: Duration = Duration::from_secs(15);
+/// How long the main loop may go without reporting in before the liveness check fails. It wakes
+/// up at least once per tick.
+const LIVENESS_TIMEOUT: Duration = Duration::from_secs(3 * TICK_INTERVAL.as_secs());
+/// How long a peer has to acknowledge a file we sent it.
+const FILE_ACK_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long an address a peer observed us on counts towards `--observed-addr-confirmations`.
+/// Connected peers renew it with every periodic identify.
+const OBSERVED_ADDR_TTL: Duration = Duration::from_secs(5 * 60);
 const PORT_TCP: u16 = 1234;
 const PORT_WEBRTC: u16 = 9090;
 const PORT_QUIC: u16 = 9091;
+const PORT_WS: u16 = 4001;
 const LOCAL_KEY_PATH: &str = "./local_key";
 const LOCAL_CERT_PATH: &str = "./cert.pem";
+/// Environment variable holding the base64 encoded identity to use instead of `LOCAL_KEY_PATH`.
+const LOCAL_KEY_ENV: &str = "LOCAL_KEY_BASE64";
 const GOSSIPSUB_PEER_DISCOVERY: &str = "dcontact._peer-discovery._p2p._pubsub";
 const DCONTACT_TOPIC: &str = "/dContact/3/message/proto";
+const IDENTIFY_PROTOCOL_VERSION: &str = "/ipfs/0.1.0";
+/// Room for the gossipsub RPC framing around a message of the maximum size.
+const RPC_OVERHEAD: usize = 1024;
+/// Interval the relay rate limits apply to.
+const RATE_LIMIT_INTERVAL: Duration = Duration::from_secs(60);
+/// Below this many mesh peers on a topic the `browser` gossipsub profile grafts more peers.
+const MESH_N_LOW: usize = 1;
+/// Upper bound on the cached peers we dial per mesh health check to fill thin meshes.
+const MAX_MESH_REPAIR_DIALS_PER_TICK: usize = 5;
+/// Upper bound on the addresses we dial per discovery message, so a single message can't make us
+/// flood the network with dials.
+const MAX_DISCOVERY_DIALS_PER_MESSAGE: usize = 5;
+/// The protocols gossipsub speaks with its default configuration, which `--relay-only` hides.
+const GOSSIPSUB_PROTOCOLS: [&str; 2] = ["/meshsub/1.1.0", "/meshsub/1.0.0"];
+
+/// Key type of the node identity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum IdentityType {
+    Ed25519,
+    Secp256k1,
+    Ecdsa,
+}
+
+/// Resolver for `/dns` addresses. Everything but `system` uses DNS over HTTPS, which can't be
+/// hijacked by the local network.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum DnsResolver {
+    System,
+    Cloudflare,
+    Google,
+    Quad9,
+    /// A DNS over HTTPS server of our own, given as `https://<host>[:<port>]/dns-query`.
+    Https { host: String, port: u16 },
+}
+
+/// How gossipsub message ids are derived. Messages with the same id are only delivered and
+/// forwarded once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum MessageIdStrategy {
+    /// Hash of the payload. Identical payloads are suppressed even from different senders, which
+    /// keeps floods of repeated messages down but drops intentional repeats, like saying "hi"
+    /// twice.
+    Content,
+    /// Hash of the source peer and sequence number, so every published message is distinct.
+    /// Falls back to the payload for messages without a source, which `Permissive` validation
+    /// lets through.
+    SenderSequence,
+    /// A new random id every time a message is received, so nothing is ever suppressed: copies
+    /// arriving via different peers are all delivered and forwarded again, and can circulate
+    /// indefinitely. Only meant for testing.
+    Random,
+}
+
+/// Gossipsub mesh sizes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum GossipsubProfile {
+    /// Keeps a mesh going with a single peer. Suits nodes serving a handful of browsers, which are
+    /// often their only peers and come and go, but one bad peer can cut a topic off.
+    Browser,
+    /// The gossipsub defaults, with at least 4 mesh peers per topic. Suits server nodes with
+    /// plenty of other servers to peer with, where the extra mesh peers keep messages flowing
+    /// when some of them misbehave or go away.
+    Server,
+    /// The mesh sizes given with `--gossipsub-mesh-n`, `--gossipsub-mesh-n-low`,
+    /// `--gossipsub-mesh-n-high` and `--gossipsub-mesh-outbound-min`.
+    Custom,
+}
+
+/// The mesh sizes of a [`GossipsubProfile`].
+#[derive(Debug, Clone, Copy)]
+struct MeshParams {
+    mesh_n: usize,
+    mesh_n_low: usize,
+    mesh_n_high: usize,
+    mesh_outbound_min: usize,
+}
+
+/// Output format of the logs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum LogFormat {
+    Text,
+    Json,
+}
 
 #[derive(Debug, Parser)]
 #[clap(name = "universal connectivity rust peer")]
 struct Opt {
-    /// Address to listen on.
+    /// TOML file to read options from, keyed by their long flag names. Flags given on the command
+    /// line take precedence.
+    #[clap(long)]
+    config: Option<PathBuf>,
+
+    /// Addresses to listen on. Can be given multiple times, e.g. `0.0.0.0` and `::` to listen on
+    /// both IPv4 and IPv6. WebRTC binds `::` dual-stack, so it can't also listen on `::` after `0.0.0.0`
+    /// and the IPv4 listener is used for both.
     #[clap(long, default_value = "0.0.0.0")]
-    listen_address: IpAddr,
+    listen_address: Vec<IpAddr>,
 
-    /// If known, the external address of this node. Will be used to correctly advertise our external address across all transports.
-    #[clap(long, env)]
-    external_address: Option<IpAddr>,
+    /// If known, the external addresses of this node, at most one per IP version. Will be used to correctly advertise our external address across all transports.
+    #[clap(long, env, value_delimiter = ',')]
+    external_address: Vec<IpAddr>,
+
+    /// Address to advertise for WebRTC instead of the listen address, when a load balancer
+    /// forwards it, e.g. `/ip4/203.0.113.1/udp/443/webrtc-direct`. The certificate hash of the
+    /// listener is appended to it. Takes precedence over `--external-address`.
+    #[clap(long, value_parser = parse_advertise_webrtc)]
+    advertise_webrtc: Option<Multiaddr>,
+
+    /// Address to advertise for QUIC instead of the listen address, when a load balancer forwards
+    /// it, e.g. `/ip4/203.0.113.1/udp/443/quic-v1`. Takes precedence over `--external-address`.
+    #[clap(long, value_parser = parse_advertise_quic)]
+    advertise_quic: Option<Multiaddr>,
 
     /// Gossipsub peer discovery topic.
     #[clap(long, default_value = GOSSIPSUB_PEER_DISCOVERY)]
@@ -65,355 +301,3746 @@ struct Opt {
         long,
         default_value = "/dns4/ipfs.le-space.de/tcp/1235/p2p/12D3KooWAJjbRkp8FPF5MKgMU53aUTxWkqvDrs4zc1VMbwRwfsbE"
     )]
-    connect: Vec<Multiaddr>
+    connect: Vec<Multiaddr>,
+
+    /// File with additional bootstrap peers to connect to, one multiaddr per line. Re-read on SIGHUP.
+    #[clap(long)]
+    bootstrap_file: Option<PathBuf>,
+
+    /// Upper bound in seconds on the delay between redials of a bootstrap peer.
+    #[clap(long, default_value_t = 300)]
+    bootstrap_max_backoff_seconds: u64,
+
+    /// Delay in milliseconds between dialing the addresses of a bootstrap peer, QUIC first, then
+    /// TCP, WebRTC and WebSocket. The first connection wins, the others are closed. `0` dials all
+    /// of them at once.
+    #[clap(long, default_value_t = 250)]
+    dial_stagger_ms: u64,
+
+    /// Redial all bootstrap peers and rerun the Kademlia bootstrap whenever we have had no
+    /// connections for `--isolation-timeout-seconds`.
+    #[clap(long)]
+    bootstrap_on_empty: bool,
+
+    /// How long we may go without any connections before `--bootstrap-on-empty` kicks in.
+    #[clap(long, default_value_t = 60)]
+    isolation_timeout_seconds: u64,
+
+    /// Maximum size in bytes of gossipsub messages we publish or relay.
+    #[clap(long, default_value_t = 1024 * 1024)]
+    max_message_size: usize,
+
+    /// Maximum message size for a specific topic as `<topic>=<bytes>`, overriding
+    /// --max-message-size. Can be given multiple times.
+    #[clap(long, value_parser = message_limits::parse_topic_limit)]
+    topic_max_message_size: Vec<(String, usize)>,
+
+    /// Maximum rate at which we publish on a topic as `<topic>=<msgs_per_sec>`. Can be given
+    /// multiple times. The peer discovery topic is never throttled.
+    #[clap(long, value_parser = publish_throttle::parse_topic_rate)]
+    topic_rate: Vec<(String, f64)>,
+
+    /// Maximum number of messages queued per throttled topic, see `--topic-rate`. Messages that
+    /// don't fit are rejected.
+    #[clap(long, default_value_t = 64)]
+    topic_rate_queue_size: usize,
+
+    /// Only subscribe to topics we see messages on if they start with one of these prefixes. Any
+    /// topic is allowed if not set.
+    #[clap(long)]
+    topic_prefix: Vec<String>,
+
+    /// Maximum number of gossipsub topics to be subscribed to, including automatic subscriptions.
+    #[clap(long, default_value_t = 64)]
+    max_topics: usize,
+
+    /// Maximum number of new topics a peer may subscribe to per minute. We follow their
+    /// subscriptions once per tick, and peers exceeding this get their gossipsub score lowered.
+    #[clap(long, default_value_t = 10)]
+    max_new_topics_per_peer: usize,
+
+    /// Maximum number of peers to add as gossipsub explicit peers, which we always forward
+    /// messages to. Peers are removed again when we disconnect from them.
+    #[clap(long, default_value_t = 64)]
+    max_explicit_peers: usize,
+
+    /// How long to remember the ids of processed gossipsub messages, so duplicates arriving later
+    /// are not processed again.
+    #[clap(long, default_value_t = 300)]
+    seen_message_ttl_seconds: u64,
+
+    /// Maximum number of message ids to remember, see `--seen-message-ttl-seconds`.
+    #[clap(long, default_value_t = 10_000)]
+    seen_message_capacity: usize,
+
+    /// Run Kademlia in server mode so other peers can use us as a routing node. Defaults to client mode.
+    #[clap(long)]
+    kademlia_server_mode: bool,
+
+    /// Interval in seconds between Kademlia bootstraps, which refresh the routing table of a
+    /// long running node.
+    #[clap(long, default_value = "300")]
+    kad_bootstrap_interval_seconds: NonZeroU64,
+
+    /// Interval in seconds between publishing our own and recently identified peers on the peer
+    /// discovery topic.
+    #[clap(long, default_value = "15")]
+    discovery_interval_seconds: NonZeroU64,
+
+    /// Interval in seconds between checking for thin gossipsub meshes and low peer scores.
+    #[clap(long, default_value = "15")]
+    mesh_health_interval_seconds: NonZeroU64,
+
+    /// Interval in seconds between saving the peerstore, if it changed.
+    #[clap(long, default_value = "15")]
+    peerstore_save_interval_seconds: NonZeroU64,
+
+    /// Interval in seconds between updating the RTT and bandwidth metrics and logging connection
+    /// stats.
+    #[clap(long, default_value = "15")]
+    stats_interval_seconds: NonZeroU64,
+
+    /// Directory to serve files from over the file exchange protocol. File sharing is disabled if not set.
+    #[clap(long)]
+    file_dir: Option<PathBuf>,
+
+    /// Size in bytes of the chunks files are read from disk and sent in.
+    #[clap(long, default_value = "65536")]
+    file_chunk_size: NonZeroUsize,
+
+    /// How long a file exchange request may take, including sending the file. Files are up to
+    /// 10 MiB, which can take longer than the usual request timeout of 10 seconds over a slow link.
+    #[clap(long, default_value = "120")]
+    file_request_timeout_seconds: NonZeroU64,
+
+    /// Disable mDNS discovery of peers on the local network.
+    #[clap(long)]
+    disable_mdns: bool,
+
+    /// Ask the router to forward our TCP and UDP listen ports via UPnP, and advertise the mapped
+    /// addresses. Mappings are renewed before their lease runs out.
+    #[clap(long)]
+    enable_upnp: bool,
+
+    /// Neither listen on nor dial TCP addresses.
+    #[clap(long)]
+    disable_tcp: bool,
+
+    /// Neither listen on nor dial QUIC addresses.
+    #[clap(long)]
+    disable_quic: bool,
+
+    /// Neither listen on nor dial WebRTC addresses.
+    #[clap(long)]
+    disable_webrtc: bool,
+
+    /// Neither listen on nor dial WebSocket addresses.
+    #[clap(long)]
+    disable_websocket: bool,
+
+    /// Address of a relay to reserve a slot on, so we can be reached through it when behind a NAT.
+    /// Must include the relay's peer id. Can be given multiple times, the next relay is tried
+    /// whenever the reservation on the current one is lost.
+    #[clap(long)]
+    relay_address: Vec<Multiaddr>,
+
+    /// Maximum number of relay reservations we accept in total.
+    #[clap(long, default_value_t = 128)]
+    max_reservations: usize,
+
+    /// Maximum number of relay reservations we accept per peer.
+    #[clap(long, default_value_t = 4)]
+    max_reservations_per_peer: usize,
+
+    /// Maximum number of circuits we relay at the same time.
+    #[clap(long, default_value_t = 16)]
+    max_circuits: usize,
+
+    /// Maximum number of circuits we relay at the same time per peer.
+    #[clap(long, default_value_t = 4)]
+    max_circuits_per_peer: usize,
+
+    /// Maximum number of bytes relayed over a circuit, in both directions together, after which
+    /// we close it. Enough for hole punching and a little signalling, not for tunneling traffic.
+    #[clap(long, default_value_t = 1 << 17)]
+    max_circuit_bytes: u64,
+
+    /// Maximum number of bytes we relay for a peer over all its circuits. Once reached its
+    /// circuits are closed and new ones fail, until it went without circuits for an hour.
+    #[clap(long)]
+    max_relay_bytes_per_peer: Option<NonZeroU64>,
+
+    /// Relay reservations a single peer may request per minute. Peers sharing an IP address may
+    /// request twice that per minute together.
+    #[clap(long, default_value = "30")]
+    relay_reservation_rate: NonZeroU32,
+
+    /// Relayed circuits a single peer may open per minute. Peers sharing an IP address may open
+    /// twice that per minute together.
+    #[clap(long, default_value = "30")]
+    relay_circuit_rate: NonZeroU32,
+
+    /// Cap on the combined bandwidth of all connections in bytes per second, in and out together.
+    /// Unlimited if not set.
+    #[clap(long)]
+    max_bandwidth_bytes_per_sec: Option<NonZeroU64>,
+
+    /// Maximum number of established incoming connections.
+    #[clap(long, default_value_t = 256)]
+    max_established_incoming: u32,
+
+    /// Maximum number of established outgoing connections.
+    #[clap(long, default_value_t = 256)]
+    max_established_outgoing: u32,
+
+    /// Maximum number of pending connections, separately for each direction.
+    #[clap(long, default_value_t = 128)]
+    max_pending: u32,
+
+    /// Close connections once they are older than this, so half-dead connections get replaced.
+    /// Hole punched connections are exempt, as are those of bootstrap peers, our relays and peers
+    /// with a reservation on us. Disabled if not set.
+    #[clap(long)]
+    max_connection_age_seconds: Option<u64>,
+
+    /// Warn once the moving average of connects and disconnects per minute rises above this,
+    /// which points at a crash looping peer or an attack. Disabled if not set.
+    #[clap(long)]
+    churn_alert_threshold: Option<f64>,
+
+    /// While `--churn-alert-threshold` is exceeded, refuse connections from a peer causing most
+    /// of the churn for this long. Bootstrap peers are exempt. Disabled if not set.
+    #[clap(long, requires = "churn_alert_threshold")]
+    churn_backoff_seconds: Option<NonZeroU64>,
+
+    /// Close the least recently active connections once we use more than this fraction of the
+    /// memory limit of our cgroup, or of the system memory without one, e.g. `0.85`. Bootstrap
+    /// peers, our relay and peers with a reservation on our relay are kept. Disabled if not set.
+    #[clap(long)]
+    memory_high_watermark: Option<f64>,
+
+    /// Fraction of the memory limit to get back below when closing connections, see
+    /// `--memory-high-watermark`.
+    #[clap(long, default_value_t = 0.75)]
+    memory_low_watermark: f64,
+
+    /// Refuse new connections once we use more than this fraction of the memory available to us,
+    /// which is the cgroup memory limit when running in a container that has one, and the system
+    /// memory otherwise.
+    #[clap(long, default_value_t = 0.9)]
+    max_memory_percentage: f64,
+
+    /// Refuse new connections once we use more than this many bytes of memory. Takes precedence
+    /// over `--max-memory-percentage`.
+    #[clap(long)]
+    max_memory_bytes: Option<NonZeroUsize>,
+
+    /// Append every swarm event to this file as a line of JSON, to capture the event sequence
+    /// that leads up to a bug. Events are recorded as their debug representation, and connection
+    /// lifecycle events and gossipsub messages and subscriptions in full, for `--replay-events`.
+    #[clap(long)]
+    record_events: Option<PathBuf>,
+
+    /// Feed the events recorded in this file with `--record-events` back through the event
+    /// handlers instead of running on the network, then exit. Only connection lifecycle events
+    /// and gossipsub messages and subscriptions are replayed, and the swarm stays idle, so the
+    /// handlers' processing of them can be reproduced and debugged.
+    #[clap(long, conflicts_with = "record_events")]
+    replay_events: Option<PathBuf>,
+
+    /// Refuse connections from and to IP addresses in this range, e.g. `203.0.113.0/24`. Can be
+    /// given multiple times.
+    #[clap(long)]
+    deny_cidr: Vec<ipnet::IpNet>,
+
+    /// Only accept connections from and to IP addresses in this range. Can be given multiple
+    /// times. All ranges not denied with `--deny-cidr` are allowed if not set.
+    #[clap(long)]
+    allow_cidr: Vec<ipnet::IpNet>,
+
+    /// MaxMind DB file, e.g. GeoLite2-Country or GeoLite2-ASN, to tag established connections with
+    /// the country and autonomous system of the remote IP in logs, and the country in metrics. Can
+    /// be given multiple times. Lookups are offline. Disabled if not set.
+    #[clap(long)]
+    geoip_db: Vec<PathBuf>,
+
+    /// File to write a JSON snapshot of our connections, the gossipsub mesh and the routing table
+    /// to on SIGUSR1. Overwritten by every snapshot.
+    #[clap(long, default_value = "state-dump.json")]
+    state_dump_file: PathBuf,
+
+    /// File of peer ids to refuse connections with, one per line. Peers blocked via the admin API
+    /// are appended to it.
+    #[clap(long)]
+    blocklist_file: Option<PathBuf>,
+
+    /// Address to serve the `/live` and `/ready` health checks on. Disabled if not set.
+    #[clap(long)]
+    health_address: Option<SocketAddr>,
+
+    /// Address to serve Prometheus metrics on.
+    #[clap(long, default_value = "127.0.0.1:9100")]
+    metrics_address: SocketAddr,
+
+    /// Number of consecutive failed pings after which a peer is disconnected.
+    #[clap(long, default_value_t = 3)]
+    ping_max_failures: u32,
+
+    /// Interval in seconds between keep-alive probes to peers we are only connected to via a
+    /// relay. A probe not answered within the interval counts as a miss.
+    #[clap(long, default_value = "20")]
+    keepalive_interval_seconds: NonZeroU64,
+
+    /// Number of keep-alive probes in a row a peer may miss before its relayed connections are
+    /// closed.
+    #[clap(long, default_value = "3")]
+    keepalive_max_misses: NonZeroU32,
+
+    /// Periodically announce our topics on a dedicated topic, and count the announcements of
+    /// other peers, for a coarse estimate of each topic's subscribers across the network in the
+    /// admin API's `topicPeers`. Peers that don't take part are only counted while connected.
+    #[clap(long)]
+    topic_census: bool,
+
+    /// Only serve as a circuit relay: don't subscribe to any topic, publish any message or
+    /// exchange files. Gossipsub still runs but stays out of every mesh, and isn't advertised
+    /// over identify, so browsers don't pick us as a gossipsub peer.
+    #[clap(long, conflicts_with = "topic_census")]
+    relay_only: bool,
+
+    /// Compress discovery and published messages on topics whose subscribers all support it, by
+    /// also subscribing to `<topic>/deflate` and publishing raw DEFLATE payloads there. Falls back to
+    /// the plain topic while any subscriber doesn't. Compressed messages are always decompressed
+    /// on receipt, and re-published on the plain topic while any of our peers is only on that.
+    #[clap(long)]
+    enable_compression: bool,
+
+    /// Never dial out, not even to the bootstrap peers, and only accept incoming connections.
+    /// Helps telling inbound from outbound connectivity problems apart, and for honeypots.
+    #[clap(long)]
+    listen_only: bool,
+
+    /// Interval in seconds between `--topic-census` announcements. Announcements not renewed
+    /// within three intervals are forgotten.
+    #[clap(long, default_value = "60")]
+    topic_census_interval_seconds: NonZeroU64,
+
+    /// Publish a status message with our agent version, uptime, connected peers and relay
+    /// reservations on `--status-topic` every 15 seconds, for fleet monitoring.
+    #[clap(long, conflicts_with = "relay_only")]
+    publish_status: bool,
+
+    /// Topic for `--publish-status`.
+    #[clap(long, default_value = STATUS_TOPIC)]
+    status_topic: String,
+
+    /// How long to wait for connections to close on shutdown before exiting anyway.
+    #[clap(long, default_value_t = 5)]
+    shutdown_grace_seconds: u64,
+
+    /// Exit with an error when one of our `--listen-address` listeners closes, e.g. because its
+    /// interface went away, instead of trying to listen on its address again.
+    #[clap(long)]
+    exit_on_listener_loss: bool,
+
+    /// Seconds of inactivity after which a QUIC connection is considered dead. Longer timeouts are
+    /// kinder to mobile batteries, shorter ones detect dropped connections sooner.
+    #[clap(long, default_value_t = 30)]
+    quic_max_idle_timeout_seconds: u32,
+
+    /// Seconds of inactivity after which we send a QUIC keep-alive. Must be below the idle timeout
+    /// of both peers to keep idle connections open.
+    #[clap(long, default_value_t = 10)]
+    quic_keep_alive_seconds: u64,
+
+    /// Seconds a TCP or WebSocket connection may take to negotiate noise and yamux before it is
+    /// dropped, so stalled upgrades don't hold on to resources. WebRTC and QUIC have their own
+    /// handshake timeouts.
+    #[clap(long, default_value = "10")]
+    max_negotiation_timeout_seconds: NonZeroU64,
+
+    /// Seconds after which a connection without open substreams is closed. With the default of 0
+    /// idle connections are closed right away, which frees their memory soonest.
+    #[clap(long, default_value_t = 0)]
+    idle_connection_timeout_seconds: u64,
+
+    /// Number of events from a connection that may queue up before the connection is slowed down
+    /// to wait for the swarm to catch up.
+    #[clap(long, default_value_t = 7)]
+    per_connection_event_buffer_size: usize,
+
+    /// Accept TCP connections on the sockets systemd passes via `LISTEN_FDS` instead of binding
+    /// `--tcp-port`, so systemd can hold on to them across restarts. Without such sockets we bind
+    /// as usual.
+    #[clap(long)]
+    systemd_socket_activation: bool,
+
+    /// Port to listen on for TCP connections.
+    #[clap(long, default_value_t = PORT_TCP)]
+    tcp_port: u16,
+
+    /// UDP port to listen on for WebRTC connections.
+    #[clap(long, default_value_t = PORT_WEBRTC)]
+    webrtc_port: u16,
+
+    /// UDP port to listen on for QUIC connections.
+    #[clap(long, default_value_t = PORT_QUIC)]
+    quic_port: u16,
+
+    /// Port to listen on for WebSocket connections.
+    #[clap(long, default_value_t = PORT_WS)]
+    ws_port: u16,
+
+    /// PEM encoded TLS certificate chain for secure WebSockets. Requires --wss-key.
+    #[clap(long)]
+    wss_cert: Option<PathBuf>,
+
+    /// PEM encoded private key for the --wss-cert certificate.
+    #[clap(long)]
+    wss_key: Option<PathBuf>,
+
+    /// Number of distinct peers that have to observe us on an address via identify before we
+    /// advertise it as an external address.
+    #[clap(long, default_value = "3")]
+    observed_addr_confirmations: NonZeroUsize,
+
+    /// Number of recently identified peers to periodically publish on the peer discovery topic.
+    #[clap(long, default_value_t = 50)]
+    discovery_cache_size: usize,
+
+    /// Also dial peers from discovery messages that aren't signed by the advertised peer, like
+    /// the ones browsers publish. Anyone can publish those for any peer id.
+    #[clap(long)]
+    allow_unsigned_discovery: bool,
+
+    /// Number of times peers re-publish our discovery messages, to reach browsers that aren't in
+    /// our gossipsub mesh. Signed discovery messages from others are re-published with one hop
+    /// less, and no more than this many, until none are left. Each peer's record is re-published
+    /// at most once per `--discovery-interval-seconds`.
+    #[clap(long, default_value_t = 3)]
+    discovery_ttl: u32,
+
+    /// File to persist the addresses of identified peers in, so we can reconnect after a restart.
+    #[clap(long, default_value = "./peerstore.json")]
+    peerstore_path: PathBuf,
+
+    /// Maximum number of peers to keep in the peerstore. Beyond that, the peers seen least
+    /// recently are forgotten, except for bootstrap peers and peers we are connected to.
+    /// Unbounded if not set.
+    #[clap(long)]
+    peerstore_max_peers: Option<NonZeroUsize>,
+
+    /// Maximum number of our own dials in flight at once, to bootstrap, stored and discovered peers
+    /// and via the admin API. Further dials wait until earlier ones are established or failed.
+    #[clap(long, default_value = "64")]
+    dial_concurrency: NonZeroUsize,
+
+    /// Number of peers from the peerstore to dial on startup.
+    #[clap(long, default_value_t = 10)]
+    peerstore_dial_count: usize,
+
+    /// Disable gossipsub peer scoring, e.g. for debugging.
+    #[clap(long)]
+    gossipsub_score_disabled: bool,
+
+    /// Gossipsub mesh sizes to use, depending on whether most of our peers are browsers or servers.
+    #[clap(long, value_enum, default_value_t = GossipsubProfile::Browser)]
+    gossipsub_profile: GossipsubProfile,
+
+    /// Number of peers gossipsub aims for in each topic mesh, with `--gossipsub-profile custom`.
+    #[clap(long, default_value_t = 6)]
+    gossipsub_mesh_n: usize,
+
+    /// Below this many mesh peers gossipsub grafts more, and we dial cached peers, with
+    /// `--gossipsub-profile custom`.
+    #[clap(long, default_value_t = 4)]
+    gossipsub_mesh_n_low: usize,
+
+    /// Above this many mesh peers gossipsub prunes some, with `--gossipsub-profile custom`.
+    #[clap(long, default_value_t = 12)]
+    gossipsub_mesh_n_high: usize,
+
+    /// Number of mesh peers per topic that must be connections we dialed, which makes it harder to
+    /// take over our mesh with inbound connections, with `--gossipsub-profile custom`. At most half
+    /// of `--gossipsub-mesh-n`.
+    #[clap(long, default_value_t = 2)]
+    gossipsub_mesh_outbound_min: usize,
+
+    /// Interval between gossipsub heartbeats, which maintain the mesh and emit gossip.
+    #[clap(long, default_value = "1000")]
+    gossipsub_heartbeat_interval_ms: NonZeroU64,
+
+    /// Number of heartbeats to keep messages in the cache for, to answer IWANT requests.
+    #[clap(long, default_value_t = 5)]
+    gossipsub_history_length: usize,
+
+    /// Number of past heartbeats to gossip about. Must not exceed `--gossipsub-history-length`.
+    #[clap(long, default_value_t = 3)]
+    gossipsub_history_gossip: usize,
+
+    /// How long gossipsub remembers the ids of messages it has seen, and drops copies of them, in
+    /// seconds. Raise it if copies arriving late over slow browser connections get forwarded
+    /// again.
+    #[clap(long, default_value = "60")]
+    gossipsub_dup_cache_secs: NonZeroU64,
+
+    /// How gossipsub message ids are derived, which decides what counts as a duplicate.
+    #[clap(long, value_enum, default_value_t = MessageIdStrategy::Content)]
+    message_id_strategy: MessageIdStrategy,
+
+    /// Key type to use when generating a new identity. Existing identities are used as is.
+    #[clap(long, value_enum, default_value_t = IdentityType::Ed25519)]
+    identity_type: IdentityType,
+
+    /// Rotate the WebRTC certificate on startup once it is older than this many days. Disabled if not set.
+    ///
+    /// Rotation only happens on startup, and the previous certificate stops working right away:
+    /// libp2p-webrtc presents one certificate per UDP socket, so addresses with the old certificate
+    /// hash can't be served next to the new one. Browsers have to learn the new address, e.g. from
+    /// the peer discovery topic.
+    #[clap(long)]
+    cert_max_age_days: Option<u64>,
+
+    /// How many days to keep the previous WebRTC certificate file after a rotation, e.g. to roll
+    /// back. It isn't accepted for connections in the meantime, see `--cert-max-age-days`.
+    #[clap(long, default_value_t = 1)]
+    cert_grace_days: u64,
+
+    /// Unix socket path to serve the JSON-RPC admin API on. Disabled if not set.
+    #[clap(long)]
+    admin_socket: Option<PathBuf>,
+
+    /// Only log identify exchanges when a peer's protocols or addresses changed, others are logged
+    /// at trace level.
+    #[clap(long)]
+    quiet_peer_log: bool,
+
+    /// Resolver to use for `/dns` addresses, like the bootstrap peers: `system`, `cloudflare`,
+    /// `google`, `quad9`, or the URL of a DNS over HTTPS server, e.g.
+    /// `https://dns.example/dns-query`. The server's host name is looked up with the system
+    /// resolver once at startup.
+    #[clap(long, value_parser = parse_dns_resolver, default_value = "system")]
+    dns_resolver: DnsResolver,
+
+    /// Protocol version to advertise via identify.
+    #[clap(long, default_value = IDENTIFY_PROTOCOL_VERSION)]
+    identify_protocol_version: String,
+
+    /// Agent version to advertise via identify, e.g. to tell the nodes of a fleet apart.
+    #[clap(long, default_value_t = default_agent_version())]
+    identify_agent_version: String,
+
+    /// Protocols to leave out of the protocol list we send via identify, e.g. `/libp2p/dcutr`, to
+    /// present as a minimal node. Can be given multiple times or comma separated. We still speak
+    /// them, so this only keeps them from being advertised: a peer can find out by trying to
+    /// open a stream. Only the protocols of DCUtR, AutoNAT, Kademlia, the relay server, file
+    /// exchange, keep-alive and the topic query can be hidden.
+    #[clap(long, value_delimiter = ',')]
+    identify_hide_protocols: Vec<String>,
+
+    /// Log as human readable text or as one JSON object per line. The level is set with RUST_LOG.
+    #[clap(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    #[clap(subcommand)]
+    command: Option<Subcommand>,
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum Subcommand {
+    /// Dial a peer, print what it reports via identify and the round trip time as JSON, and exit.
+    /// Exits with a non-zero status if the peer can't be reached in time.
+    Probe {
+        /// Address of the peer to probe.
+        target: Multiaddr,
+
+        /// How long to wait for the connection, identify and ping to complete.
+        #[clap(long, default_value_t = 10)]
+        timeout_seconds: u64,
+    },
+    /// Print the identity as base64 encoded protobuf, for backups or moving it to another host.
+    ExportIdentity {
+        /// Don't ask for confirmation before printing the private key.
+        #[clap(long)]
+        yes: bool,
+    },
+    /// Replace the identity with a base64 encoded one, as printed by `export-identity`.
+    ImportIdentity {
+        /// The identity to import. Read from stdin if not given, which keeps it out of the shell
+        /// history.
+        key: Option<String>,
+
+        /// Overwrite an existing identity.
+        #[clap(long)]
+        force: bool,
+    },
+    /// Print the addresses browsers and other peers can dial us on, complete with the WebRTC
+    /// certificate hash and our peer id, built from the same options the node would run with.
+    PrintDialStrings,
 }
 
 /// An example WebRTC peer that will accept connections
 #[tokio::main]
 async fn main() -> Result<()> {
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let opt = config::parse_with_config_file::<Opt>()?;
+    init_logging(opt.log_format);
+
+    match opt.command {
+        Some(Subcommand::Probe { target, timeout_seconds }) => {
+            return probe::run(target, Duration::from_secs(timeout_seconds)).await;
+        }
+        Some(Subcommand::ExportIdentity { yes }) => {
+            return identity_transfer::export(Path::new(LOCAL_KEY_PATH), yes);
+        }
+        Some(Subcommand::ImportIdentity { ref key, force }) => {
+            return identity_transfer::import(Path::new(LOCAL_KEY_PATH), key.clone(), force);
+        }
+        Some(Subcommand::PrintDialStrings) => return print_dial_strings(&opt).await,
+        None => {}
+    }
 
-    let opt = Opt::parse();
-    let local_key = read_or_create_identity(Path::new(LOCAL_KEY_PATH))
+    let local_key = read_or_create_identity(Path::new(LOCAL_KEY_PATH), opt.identity_type)
         .await
         .context("Failed to read identity")?;
-    let webrtc_cert = read_or_create_certificate(Path::new(LOCAL_CERT_PATH))
+    let key_type = local_key.key_type();
+    let webrtc_cert = cert::read_or_create_certificate(Path::new(LOCAL_CERT_PATH), cert_rotation(&opt))
         .await
         .context("Failed to read certificate")?;
+    let wss_tls_config = match (&opt.wss_cert, &opt.wss_key) {
+        (Some(cert), Some(key)) => read_tls_config(cert, key).await,
+        (None, None) => None,
+        _ => {
+            warn!("Both --wss-cert and --wss-key are needed for secure WebSockets, falling back to plain /ws");
+            None
+        }
+    };
+    let wss_enabled = wss_tls_config.is_some();
 
-    let mut swarm = create_swarm(local_key, webrtc_cert, &opt)?;
-
-    let address_tcp = Multiaddr::from(opt.listen_address)
-        .with(Protocol::Tcp(PORT_TCP));
-
-    let address_webrtc = Multiaddr::from(opt.listen_address)
-         .with(Protocol::Udp(PORT_WEBRTC))
-         .with(Protocol::WebRTCDirect);
+    let mut registry = Registry::with_prefix("universal_connectivity");
+    let bandwidth = Bandwidth::new(opt.max_bandwidth_bytes_per_sec);
+    let relay_bytes = RelayBytes::new(opt.max_relay_bytes_per_peer);
+    let inherited_tcp = if opt.systemd_socket_activation {
+        socket_activation::inherited_tcp_listeners()?
+    } else {
+        Vec::new()
+    };
+    if opt.disable_tcp && !inherited_tcp.is_empty() {
+        bail!("--disable-tcp leaves the sockets from systemd unused");
+    }
+    let inherited_tcp_addrs = inherited_tcp.iter().map(|(address, _)| address.clone()).collect::<Vec<_>>();
+    let mut swarm = create_swarm(local_key.clone(), webrtc_cert, wss_tls_config, inherited_tcp, &bandwidth, &relay_bytes, &opt, &mut registry).await?;
+    let metrics = Metrics::new(&mut registry);
 
-    let address_quic = Multiaddr::from(opt.listen_address)
-        .with(Protocol::Udp(PORT_QUIC))
-        .with(Protocol::QuicV1);
+    let metrics_address = opt.metrics_address;
+    let registry = Arc::new(Mutex::new(registry));
+    tokio::spawn(async move {
+        if let Err(e) = metrics::serve(metrics_address, registry).await {
+            error!(error = format!("{e:#}"), "Metrics server failed");
+        }
+    });
 
-    swarm
-        .listen_on(address_tcp.clone())
-        .expect("listen on tcp");
-    swarm
-        .listen_on(address_webrtc.clone())
-        .expect("listen on webrtc");
-    swarm
-        .listen_on(address_quic.clone())
-        .expect("listen on quic");
+    let health = Health::default();
+    if let Some(health_address) = opt.health_address {
+        let health = health.clone();
+        tokio::spawn(async move {
+            if let Err(e) = health::serve(health_address, health, LIVENESS_TIMEOUT).await {
+                error!(error = format!("{e:#}"), "Health server failed");
+            }
+        });
+    }
 
-    for addr in opt.connect {
-        if let Err(e) = swarm.dial(addr.clone()) {
-            debug!("Failed to dial {addr}: {e}");
+    let addresses = opt
+        .listen_address
+        .iter()
+        .flat_map(|ip| listen_addresses(*ip, &opt, wss_enabled))
+        // The sockets from systemd replace our own TCP listeners.
+        .filter(|address| inherited_tcp_addrs.is_empty() || transport_stats::classify(address) != "tcp")
+        .chain(inherited_tcp_addrs.iter().cloned())
+        // A replay stays off the network.
+        .filter(|_| opt.replay_events.is_none())
+        .collect::<Vec<_>>();
+    let mut listeners = Vec::new();
+    for address in addresses {
+        match swarm.listen_on(address.clone()) {
+            Ok(listener) => listeners.push((listener, address)),
+            Err(e) => warn!(%address, error = format!("{:#}", anyhow::Error::from(e)), "Failed to listen"),
         }
     }
 
-    let peer_discovery = gossipsub::IdentTopic::new(GOSSIPSUB_PEER_DISCOVERY).hash();
-    let dcontact_topic = gossipsub::IdentTopic::new(DCONTACT_TOPIC).hash();
-
-    let mut tick = futures_timer::Delay::new(TICK_INTERVAL);
-
-    loop {
-        match select(swarm.next(), &mut tick).await {
-            Either::Left((event, _)) => match event.unwrap() {
-                SwarmEvent::NewListenAddr { address, .. } => {
-                    if let Some(external_ip) = opt.external_address {
-                        let external_address = address
-                            .replace(0, |_| Some(external_ip.into()))
-                            .expect("address.len > 1 and we always return `Some`");
-
-                        swarm.add_external_address(external_address);
-                    }
-
-                    let p2p_address = address.with(Protocol::P2p(*swarm.local_peer_id()));
-                    info!("Listening on {p2p_address}");
-                }
-                SwarmEvent::ConnectionEstablished { peer_id, .. } => {
-                    info!("Connected to {peer_id}");
-                }
-                SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
-                    warn!("Failed to dial {peer_id:?}: {error}");
-                }
-                SwarmEvent::IncomingConnectionError { error, .. } => {
-                    warn!("{:#}", anyhow::Error::from(error))
-                }
-                SwarmEvent::ConnectionClosed { peer_id, cause, .. } => {
-                    warn!("Connection to {peer_id} closed: {cause:?}");
-//                     swarm.behaviour_mut().kademlia.remove_peer(&peer_id);
-//                     info!("Removed {peer_id} from the routing table (if it was in there).");
-                }
-                SwarmEvent::Behaviour(BehaviourEvent::Relay(e)) => {
-                    debug!("{:?}", e);
-                }
-                SwarmEvent::Behaviour(BehaviourEvent::Dcutr(e)) => {
-                    info!("Connected to {:?}", e);
-                }
-
-                // Ping event
-          /*      SwarmEvent::Behaviour(BehaviourEvent::Ping(ping::Event {
-                    peer,
-                    result: Ok(rtt),
-                    ..
-                })) => {
-                     debug!("🏓 Ping {peer} in ");
-                    // debug!("🏓 Ping {peer} in {rtt:?}");
-
-                    // send msg
-                    self.event_sender
-                        .send(NetworkEvent::Pong {
-                            peer: peer.to_string(),
-                            rtt: rtt.as_millis() as u64,
-                        })
-                        .await
-                        .expect("Event receiver not to be dropped.");
-                } */
-
-                SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(
-                    libp2p::gossipsub::Event::Message {
-                        message_id: _,
-                        propagation_source: _,
-                        message,
-                    },
-                )) => {
-                         // subscribe to this topic so we can act as super peer to browsers
-                        let newTopic = gossipsub::IdentTopic::new(message.topic.to_string());
-                        //swarm.behaviour_mut().gossipsub.subscribe(&newTopic)?;
-                        if let Err(err) =
-                            swarm.behaviour_mut().gossipsub.subscribe(&newTopic)
-                        {
-                            error!("Failed to subscribe to topic: {err}");
-                        }
-                       info!(" subscribe to topic:  to {:?}", message.topic);
-//                     if message.topic == peer_discovery {
-//                         let peer = Peer::decode(&*message.data).unwrap();
-//                         //info!("Received peer from {:?}", peer.addrs);
-//                         for addr in &peer.addrs {
-//                             if let Ok(multiaddr) = Multiaddr::try_from(addr.clone()) {
-//                                 info!("Received address: {:?}", multiaddr.to_string());
-//
-//                                 if let Err(err) = swarm.behaviour_mut().gossipsub.publish(
-//                                                          gossipsub::IdentTopic::new(GOSSIPSUB_PEER_DISCOVERY),
-//                                                          &*message.data,)
-//                                 {error!("Failed to publish peer: {err}")}
-//                             } else {
-//                                         error!("Failed to parse multiaddress");
-//                             }
-//                         }
-//                     }
-
-//                     if message.topic == dcontact_topic {
-//                         let peer = Peer::decode(&*message.data).unwrap();
-//                         //info!("Received peer from {:?}", peer.addrs);
-//                         for addr in &peer.addrs {
-//                             if let Ok(multiaddr) = Multiaddr::try_from(addr.clone()) {
-//                                 info!("Received address: {:?}", multiaddr.to_string());
-//
-//                                 if let Err(err) = swarm.behaviour_mut().gossipsub.publish(
-//                                                          gossipsub::IdentTopic::new(DCONTACT_TOPIC),
-//                                                          &*message.data,)
-//                                 {error!("Failed to publish peer: {err}")}
-//                             } else {
-//                                 error!("Failed to parse multiaddress");
-//                             }
-//                         }
-//
-//                         continue;
-//                     }
+    log_startup_config(&swarm, &opt, key_type, &listeners);
 
-//                     error!("Unexpected gossipsub topic hash: {:?}", message.topic);
-                }
-                SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(
-                    libp2p::gossipsub::Event::Subscribed { peer_id, topic },
-                )) => {
-                        debug!("{peer_id} subscribed to {topic}");
+    // The binary only logs the events, an embedding application would act on them instead.
+    let (event_sender, events) = mpsc::channel::<NetworkEvent>(64);
+    tokio::spawn(print_events(events));
 
-                         // Indiscriminately add the peer to the routing table
-                        swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+    let (command_sender, commands) = mpsc::channel::<Command>(16);
 
-                }
+    let validators = MessageValidators::builtin(&opt.dcontact_topic, &opt.gossipsub_peer_discovery, &opt.status_topic);
 
-                SwarmEvent::Behaviour(BehaviourEvent::Identify(e)) => {
-                    info!("BehaviourEvent::Identify {:?}", e);
+    run(swarm, local_key, &opt, metrics, bandwidth, relay_bytes, health, listeners, validators, event_sender, commands, command_sender).await
+}
 
-                    if let identify::Event::Error { peer_id, error } = e {
-                        match error {
-                            libp2p::swarm::StreamUpgradeError::Timeout => {
-                                info!("Removed {peer_id} from the routing table (if it was in there).");
-                            }
-                            _ => {
-                                debug!("{error}");
-                            }
-                        }
-                    } else if let identify::Event::Received {
-                        peer_id,
-                        info:
-                            identify::Info {
-                                listen_addrs,
-                                protocols,
-                                observed_addr,
-                                ..
-                            },
-                    } = e
-                    {
-                        debug!("identify::Event::Received observed_addr: {}", observed_addr);
-                        swarm.add_external_address(observed_addr);
-                    }
-                },
-                _ => {},
-            },
-            Either::Right(_) => {
-                tick = futures_timer::Delay::new(TICK_INTERVAL);
+/// The tracing span to handle a swarm event in, see [`ConnectionSpans`].
+fn event_span(connection_spans: &mut ConnectionSpans, event: &SwarmEvent<BehaviourEvent>) -> Span {
+    match event {
+        SwarmEvent::Behaviour(BehaviourEvent::Identify(
+            identify::Event::Received { peer_id, .. }
+            | identify::Event::Sent { peer_id }
+            | identify::Event::Pushed { peer_id, .. }
+            | identify::Event::Error { peer_id, .. },
+        )) => info_span!(parent: &connection_spans.peer(peer_id), "identify"),
+        SwarmEvent::Behaviour(BehaviourEvent::Ping(ping::Event { connection, .. })) => {
+            connection_spans.connection(connection)
+        }
+        SwarmEvent::Behaviour(BehaviourEvent::Dcutr(dcutr::Event { remote_peer_id, .. })) => {
+            info_span!(parent: &connection_spans.peer(remote_peer_id), "dcutr")
+        }
+        event => connection_spans.span_for(event),
+    }
+}
 
-                debug!(
-                    "external addrs: {:?}",
-                    swarm.external_addresses().collect::<Vec<&Multiaddr>>()
-                );
+async fn print_events(mut events: mpsc::Receiver<NetworkEvent>) {
+    while let Some(event) = events.recv().await {
+        match event {
+            NetworkEvent::PeerConnected(peer_id) => debug!(%peer_id, "Peer connected"),
+            NetworkEvent::PeerDisconnected(peer_id) => debug!(%peer_id, "Peer disconnected"),
+            NetworkEvent::MessageReceived {
+                source,
+                topic,
+                data,
+            } => debug!(?source, %topic, bytes = data.len(), "Message received"),
+            NetworkEvent::NatStatusChanged(status) => debug!(?status, "NAT status changed"),
+            NetworkEvent::FileDelivered {
+                peer,
+                file_id,
+                bytes_received,
+            } => debug!(%peer, file_id, bytes_received, "File delivered"),
+            NetworkEvent::TransferAborted { peer, file_id } => {
+                debug!(%peer, file_id, "File transfer aborted")
             }
         }
     }
 }
 
-#[derive(NetworkBehaviour)]
-struct Behaviour {
-    ping: ping::Behaviour,
-    dcutr: dcutr::Behaviour,
-    gossipsub: gossipsub::Behaviour,
-    identify: identify::Behaviour,
-    relay: relay::Behaviour,
-    //relay: relay::Behaviour::new(key.public().to_peer_id(), Default::default()),
-//     request_response: request_response::Behaviour<FileExchangeCodec>,
-    connection_limits: memory_connection_limits::Behaviour,
+/// The next event of the swarm, or of the recording we replay instead, in which case the swarm is
+/// never polled. None once the replay is through.
+async fn next_event(
+    swarm: &mut Swarm<Behaviour>,
+    event_replay: &mut Option<EventReplay>,
+) -> Option<Result<SwarmEvent<BehaviourEvent>>> {
+    match event_replay {
+        Some(event_replay) => event_replay.next_event(),
+        None => Some(Ok(swarm.select_next_some().await)),
+    }
 }
 
-fn create_swarm(
+/// Drives the swarm until we receive a shutdown signal, or a replay is through, reporting what
+/// happens on `event_sender` and executing the `commands` we receive. The admin API sends its
+/// commands via `command_sender`.
+#[allow(clippy::too_many_arguments)]
+async fn run(
+    mut swarm: Swarm<Behaviour>,
     local_key: identity::Keypair,
-    certificate: Certificate,
-    opt:&Opt
-) -> Result<Swarm<Behaviour>> {
-    let local_peer_id = PeerId::from(local_key.public());
-    debug!("Local peer id: {local_peer_id}");
-
-    // To content-address message, we can take the hash of message and use it as an ID.
-    let message_id_fn = |message: &gossipsub::Message| {
-        let mut s = DefaultHasher::new();
-        message.data.hash(&mut s);
-        gossipsub::MessageId::from(s.finish().to_string())
-    };
+    opt: &Opt,
+    metrics: Metrics,
+    bandwidth: Bandwidth,
+    relay_bytes: RelayBytes,
+    health: Health,
+    mut listeners: Vec<(ListenerId, Multiaddr)>,
+    validators: MessageValidators,
+    event_sender: mpsc::Sender<NetworkEvent>,
+    mut commands: mpsc::Receiver<Command>,
+    command_sender: mpsc::Sender<Command>,
+) -> Result<()> {
+    let external_ips = global_external_ips(&opt.external_address);
+    let message_limits = MessageSizeLimits::new(opt.max_message_size, &opt.topic_max_message_size);
 
-    // Set a custom gossipsub configuration
-    let gossipsub_config = gossipsub::ConfigBuilder::default()
-        .validation_mode(gossipsub::ValidationMode::Permissive) // This sets the kind of message validation. The default is Strict (enforce message signing)
-        .message_id_fn(message_id_fn) // content-address messages. No two messages of the same content will be propagated.
-        .mesh_outbound_min(1)
-        .mesh_n_low(1)
-        .flood_publish(true)
-        .build()
-        .expect("Valid config");
+    let mut bootstrap = Bootstrap::new(
+        opt.connect.clone(),
+        opt.bootstrap_file.clone(),
+        Duration::from_secs(opt.bootstrap_max_backoff_seconds),
+        Duration::from_millis(opt.dial_stagger_ms),
+    )?;
 
-    // build a gossipsub network behaviour
-    let mut gossipsub = gossipsub::Behaviour::new(
-        gossipsub::MessageAuthenticity::Signed(local_key.clone()),
-        gossipsub_config,
-    )
-    .expect("Correct configuration");
+    let mut blocklist = Blocklist::load(opt.blocklist_file.as_deref())?;
+    for peer_id in blocklist.peers() {
+        block_peer(&mut swarm, *peer_id);
+    }
 
-    // Create/subscribe Gossipsub topics
-    gossipsub.subscribe(&gossipsub::IdentTopic::new(&opt.gossipsub_peer_discovery))?;
+    let mut event_recorder = opt.record_events.as_deref().map(EventRecorder::create).transpose()?;
+    let mut event_replay = opt.replay_events.as_deref().map(EventReplay::open).transpose()?;
 
-//     let transport = {
-//         let webrtc = webrtc::tokio::Transport::new(local_key.clone(), certificate);
-//         let quic = quic::tokio::Transport::new(quic::Config::new(&local_key));
-//
-//         let mapped = webrtc.or_transport(quic).map(|fut, _| match fut {
-//             Either::Right((local_peer_id, conn)) => (local_peer_id, StreamMuxerBox::new(conn)),
-//             Either::Left((local_peer_id, conn)) => (local_peer_id, StreamMuxerBox::new(conn)),
-//         });
-//
-//         dns::TokioDnsConfig::system(mapped)?.boxed()
-//     };
+    let mut dial_queue = DialQueue::new(opt.dial_concurrency);
+    let mut peerstore = Peerstore::load(&opt.peerstore_path, opt.peerstore_max_peers).await;
+    metrics.set_peerstore_peers(peerstore.len());
+    if opt.listen_only {
+        warn!(event = "listen_only", "Outbound dialing is disabled by --listen-only, only accepting incoming connections");
+    } else if event_replay.is_none() {
+        bootstrap.dial_all(&mut swarm, &mut dial_queue);
+        dial_stored_peers(&mut swarm, &mut dial_queue, &peerstore, opt.peerstore_dial_count);
+    }
 
-    let identify_config = identify::Behaviour::new(
-        identify::Config::new("/ipfs/0.1.0".into(), local_key.public())
-            .with_interval(Duration::from_secs(60)), // do this so we can get timeouts for dropped WebRTC connections
-    );
+    // Reserve a slot on the relay. DCUtR will try to upgrade relayed connections to direct ones.
+    let mut relay_failover = RelayFailover::new(opt.relay_address.clone());
+    relay_failover.reserve(&mut swarm);
 
-    let behaviour = Behaviour {
-        ping: ping::Behaviour::new(ping::Config::new()),
-        dcutr: dcutr::Behaviour::new(local_key.public().to_peer_id()),
-        gossipsub,
-        identify: identify_config,
-        relay: relay::Behaviour::new(
-            local_peer_id,
-            relay::Config {
-                max_reservations: usize::MAX,
-                max_reservations_per_peer: 100,
-                reservation_rate_limiters: Vec::default(),
-                circuit_src_rate_limiters: Vec::default(),
-                max_circuits: usize::MAX,
-                max_circuits_per_peer: 100,
-                ..Default::default()
-            },
-        ),
-        connection_limits: memory_connection_limits::Behaviour::with_max_percentage(0.9),
+    let mut rtt_tracker = RttTracker::new(opt.ping_max_failures);
+    let mut keepalive = KeepAlive::new(opt.keepalive_max_misses.get());
+    let mut topic_census = opt
+        .topic_census
+        .then(|| TopicCensus::new(3 * Duration::from_secs(opt.topic_census_interval_seconds.get())));
+    let topic_census_topic = gossipsub::IdentTopic::new(TOPIC_CENSUS_TOPIC);
+    let status_topic = gossipsub::IdentTopic::new(&opt.status_topic);
+    let started = Instant::now();
+    let mut identify_log = IdentifyLog::default();
+    let mut transport_stats = TransportStats::default();
+    let mut connection_timings = ConnectionTimings::default();
+    let mut relay_stats = RelayStats::new(opt.max_reservations);
+    let mut connection_ages = opt
+        .max_connection_age_seconds
+        .map(|seconds| ConnectionAges::new(Duration::from_secs(seconds)));
+    let mut churn_monitor = opt.churn_alert_threshold.map(|threshold| {
+        ChurnMonitor::new(
+            threshold,
+            opt.churn_backoff_seconds.map(|seconds| Duration::from_secs(seconds.get())),
+        )
+    });
+    let mut memory_pruner = match opt.memory_high_watermark {
+        Some(high) => {
+            let low = opt.memory_low_watermark;
+            if !(0.0 < low && low < high && high <= 1.0) {
+                bail!("Memory watermarks must satisfy 0 < --memory-low-watermark < --memory-high-watermark <= 1");
+            }
+            Some(MemoryPruner::new(high, low))
+        }
+        None => None,
     };
+    let mut geoip = match opt.geoip_db.as_slice() {
+        [] => None,
+        paths => Some(GeoIp::open(paths)?),
+    };
+    let mut explicit_peers = ExplicitPeers::new(opt.max_explicit_peers);
+    let mut seen_messages = SeenMessages::new(
+        Duration::from_secs(opt.seen_message_ttl_seconds),
+        opt.seen_message_capacity,
+    );
+    let mut new_topics = NewTopics::new(opt.max_topics, opt.max_new_topics_per_peer);
+    if opt.gossipsub_score_disabled {
+        warn!("--gossipsub-score-disabled is set, so peers exceeding --max-new-topics-per-peer only have their topics ignored, their score can't be lowered");
+    }
+    let mut discovery_cache = DiscoveryCache::new(opt.discovery_cache_size);
+    let mut discovery_relay = DiscoveryRelay::new(
+        opt.discovery_ttl,
+        Duration::from_secs(opt.discovery_interval_seconds.get()),
+    );
+    let mut observed_addrs = ObservedAddrs::new(opt.observed_addr_confirmations.get(), OBSERVED_ADDR_TTL);
+    let mut mesh_repair = MeshRepair::default();
+    let mut file_deliveries = FileDeliveries::new(FILE_ACK_TIMEOUT);
+    // Files requested from us are looked up on disk outside the event handling, which mustn't
+    // wait on I/O.
+    let mut file_lookups = FuturesUnordered::new();
+    let peer_discovery_topic = gossipsub::IdentTopic::new(&opt.gossipsub_peer_discovery);
+    let topic_rates = opt
+        .topic_rate
+        .iter()
+        .filter(|(topic, _)| {
+            let exempt = *topic == opt.gossipsub_peer_discovery;
+            if exempt {
+                warn!(%topic, "Ignoring --topic-rate for the peer discovery topic");
+            }
+            !exempt
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    let mut publish_throttle = PublishThrottle::new(&topic_rates, opt.topic_rate_queue_size);
+    let mut score_monitor = (!opt.gossipsub_score_disabled).then(|| {
+        ScoreMonitor::new(gossipsub::PeerScoreThresholds::default().gossip_threshold)
+    });
 
-    let swarm = libp2p::SwarmBuilder::with_new_identity()
-        .with_tokio()
-        .with_tcp(
-            tcp::Config::default(),
-            noise::Config::new,
-            yamux::Config::default,
-        )?
-        .with_quic()
-        .with_other_transport(|id_keys| {
-            Ok(webrtc::tokio::Transport::new(
-                id_keys.clone(),
-               certificate,
-            )
-            .map(|(peer_id, conn), _| (peer_id, StreamMuxerBox::new(conn))))
-        })?
-        .with_behaviour(|key| behaviour)?
-        .build();
-
-    Ok(swarm)
-}
-
-async fn read_or_create_certificate(path: &Path) -> Result<Certificate> {
-    if path.exists() {
-        let pem = fs::read_to_string(&path).await?;
-
-        info!("Using existing certificate from {}", path.display());
+    let cert_rotation = cert_rotation(opt);
+    let mut cert_rotation_due = false;
 
-        return Ok(Certificate::from_pem(&pem)?);
+    // The sender is kept alive even without an admin socket, so `recv` doesn't resolve immediately.
+    let (admin_sender, mut admin_requests) = mpsc::channel::<AdminRequest>(16);
+    if let Some(path) = opt.admin_socket.clone() {
+        let admin_sender = admin_sender.clone();
+        tokio::spawn(async move {
+            if let Err(e) = admin::serve(path, admin_sender, command_sender).await {
+                error!(error = format!("{e:#}"), "Admin API failed");
+            }
+        });
     }
 
-    let cert = Certificate::generate(&mut rand::thread_rng())?;
-    fs::write(&path, &cert.serialize_pem().as_bytes()).await?;
+    let kad_bootstrap_interval = Duration::from_secs(opt.kad_bootstrap_interval_seconds.get());
+    let mut last_kad_bootstrap = None::<Instant>;
 
-    info!(
-        "Generated new certificate and wrote it to {}",
-        path.display()
-    );
+    let mut bandwidth_sample = (bandwidth.totals(), Instant::now());
+    let isolation_timeout = Duration::from_secs(opt.isolation_timeout_seconds);
+    let mut isolated_since = None::<Instant>;
 
-    Ok(cert)
-}
+    // The tick does the housekeeping that has no interval of its own.
+    let mut tick = futures_timer::Delay::new(TICK_INTERVAL);
+    let discovery_interval = Duration::from_secs(opt.discovery_interval_seconds.get());
+    let mut discovery_tick = futures_timer::Delay::new(discovery_interval);
+    let mesh_n_low = mesh_params(opt).mesh_n_low;
+    let mesh_health_interval = Duration::from_secs(opt.mesh_health_interval_seconds.get());
+    let mut mesh_health_tick = futures_timer::Delay::new(mesh_health_interval);
+    let peerstore_save_interval = Duration::from_secs(opt.peerstore_save_interval_seconds.get());
+    let mut peerstore_save_tick = futures_timer::Delay::new(peerstore_save_interval);
+    let stats_interval = Duration::from_secs(opt.stats_interval_seconds.get());
+    let mut stats_tick = futures_timer::Delay::new(stats_interval);
+    let keepalive_interval = Duration::from_secs(opt.keepalive_interval_seconds.get());
+    let mut keepalive_tick = futures_timer::Delay::new(keepalive_interval);
+    let topic_census_interval = Duration::from_secs(opt.topic_census_interval_seconds.get());
+    let mut topic_census_tick = futures_timer::Delay::new(topic_census_interval);
+    let mut churn_tick = futures_timer::Delay::new(CHURN_INTERVAL);
 
-async fn read_or_create_identity(path: &Path) -> Result<identity::Keypair> {
-    if path.exists() {
-        let bytes = fs::read(&path).await?;
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    let mut sighup = signal(SignalKind::hangup())?;
+    let mut sigusr1 = signal(SignalKind::user_defined1())?;
 
-        info!("Using existing identity from {}", path.display());
+    loop {
+        health.heartbeat();
 
-        return Ok(identity::Keypair::from_protobuf_encoding(&bytes)?); // This only works for ed25519 but that is what we are using.
-    }
+        tokio::select! {
+            event = next_event(&mut swarm, &mut event_replay) => {
+                let event = match event {
+                    Some(event) => event?,
+                    None => {
+                        info!(event = "replay_finished", "Replayed all recorded events");
+                        return Ok(());
+                    }
+                };
+                if let Some(event_recorder) = &mut event_recorder {
+                    event_recorder.record(&event);
+                }
+                metrics.record(&event);
+                if let SwarmEvent::Behaviour(e) = &event {
+                    metrics.record_behaviour_event(e);
+                }
+                if let SwarmEvent::NewListenAddr { .. }
+                | SwarmEvent::ExpiredListenAddr { .. }
+                | SwarmEvent::ListenerClosed { .. } = &event
+                {
+                    health.listeners.store(swarm.listeners().count(), Ordering::Relaxed);
+                }
 
-    let identity = identity::Keypair::generate_ed25519();
+                let span = event_span(&mut swarm.behaviour_mut().connection_spans, &event);
+                if let SwarmEvent::Dialing { .. }
+                | SwarmEvent::IncomingConnection { .. }
+                | SwarmEvent::ConnectionEstablished { .. }
+                | SwarmEvent::OutgoingConnectionError { .. }
+                | SwarmEvent::IncomingConnectionError { .. } = &event
+                {
+                    metrics.set_pending_connections(swarm.behaviour().connection_spans.pending());
+                }
 
-    fs::write(&path, &identity.to_protobuf_encoding()?).await?;
+                // Handled in the span of its connection, so `return` skips the rest of an event.
+                let mut lost_listener = None;
+                span.in_scope(|| match event {
+                    SwarmEvent::NewListenAddr { address, .. } => {
+                        if let Some(external_address) = advertise_override(&address, opt)
+                            .or_else(|| external_address_for(&address, &external_ips))
+                        {
+                            observed_addrs.pin(external_address.clone());
+                            swarm.add_external_address(external_address);
+                        }
 
-    info!("Generated new identity and wrote it to {}", path.display());
+                        let p2p_address = address.with(Protocol::P2p(*swarm.local_peer_id()));
+                        info!(event = "new_listen_addr", address = %p2p_address, "Listening");
+                    }
+                    SwarmEvent::ListenerClosed { listener_id, reason, .. }
+                        if relay_failover.listener() == Some(listener_id) =>
+                    {
+                        warn!(event = "relay_reservation_closed", ?reason, "Relay reservation closed");
+                        relay_failover.on_listener_closed(listener_id);
+                    }
+                    SwarmEvent::ListenerClosed { listener_id, reason, .. } => {
+                        let Some(position) = listeners.iter().position(|(id, _)| *id == listener_id) else {
+                            debug!(?listener_id, ?reason, "Listener closed");
+                            return;
+                        };
+                        let (_, address) = listeners.remove(position);
+                        error!(event = "listener_closed", ?listener_id, %address, ?reason, "Listener closed unexpectedly");
 
-    Ok(identity)
-}
\ No newline at end of file
+                        if opt.exit_on_listener_loss {
+                            lost_listener = Some(address);
+                            return;
+                        }
+                        match swarm.listen_on(address.clone()) {
+                            Ok(listener_id) => {
+                                info!(event = "listener_restarted", ?listener_id, %address, "Listening again");
+                                listeners.push((listener_id, address));
+                            }
+                            Err(e) => error!(event = "listener_restart_failed", %address, error = format!("{:#}", anyhow::Error::from(e)), "Failed to listen again"),
+                        }
+                    }
+                    SwarmEvent::ListenerError { listener_id, error } => {
+                        let address = listeners
+                            .iter()
+                            .find_map(|(id, address)| (*id == listener_id).then_some(address));
+                        warn!(event = "listener_error", ?listener_id, ?address, %error, "Listener error");
+                    }
+                    // Addresses are on the `dial` span.
+                    SwarmEvent::Dialing { peer_id, connection_id } => {
+                        let (in_flight, _) = swarm.behaviour().connection_spans.pending();
+                        debug!(event = "dialing", ?peer_id, ?connection_id, in_flight, "Dialing");
+                    }
+                    SwarmEvent::IncomingConnection { connection_id, local_addr, send_back_addr } => {
+                        let (_, in_flight) = swarm.behaviour().connection_spans.pending();
+                        debug!(event = "incoming_connection", ?connection_id, %local_addr, %send_back_addr, in_flight, "Incoming connection");
+                    }
+                    SwarmEvent::ConnectionEstablished { peer_id, connection_id, endpoint, num_established, established_in, .. } => {
+                        let remote_address = endpoint.get_remote_address();
+                        let origin = geoip.as_mut().and_then(|geoip| geoip.origin(remote_address));
+                        match &origin {
+                            Some(origin) => {
+                                info!(event = "connection_established", %peer_id, ?established_in, country = origin.country, asn = origin.asn, "Connected");
+                                metrics.record_connection_origin(origin);
+                            }
+                            None => info!(event = "connection_established", %peer_id, ?established_in, "Connected"),
+                        }
+                        isolated_since = None;
+                        if num_established.get() == 1 {
+                            event::emit(&event_sender, NetworkEvent::PeerConnected(peer_id));
+                        }
+                        health.connections.fetch_add(1, Ordering::Relaxed);
+                        if bootstrap.on_connection_established(peer_id, connection_id, num_established.get()) {
+                            swarm.close_connection(connection_id);
+                        }
+                        for failed in dial_queue.on_dial_finished(&mut swarm, connection_id) {
+                            bootstrap.on_dial_failure(failed);
+                        }
+                        mesh_repair.on_connection_established(&peer_id);
+                        keepalive.on_connection_established(peer_id, connection_id, &endpoint);
+                        let (transport, count) = transport_stats.established(&endpoint);
+                        metrics.set_transport_connections(transport, count);
+                        metrics.record_connection_establishment(transport, established_in);
+                        connection_timings.on_connection_established(peer_id, transport, established_in, num_established.get() == 1);
+                        if let Some(connection_ages) = &mut connection_ages {
+                            connection_ages.on_connection_established(peer_id, connection_id);
+                        }
+                        if let Some(memory_pruner) = &mut memory_pruner {
+                            memory_pruner.on_connection_established(peer_id, connection_id);
+                        }
+                        if let Some(churn_monitor) = &mut churn_monitor {
+                            churn_monitor.on_connection_event(peer_id);
+                        }
+                    }
+                    SwarmEvent::OutgoingConnectionError { peer_id, connection_id, error } => {
+                        let (blocked, limit) = match &error {
+                            DialError::Denied { cause } => (is_blocked(cause), exceeded_limit(cause)),
+                            _ => (false, None),
+                        };
+                        let listen_only = matches!(&error, DialError::Denied { cause } if cause.downcast_ref::<OutboundDisabled>().is_some());
+                        let denied_ip = matches!(&error, DialError::Denied { cause } if cause.downcast_ref::<DeniedIp>().is_some());
+                        match limit {
+                            _ if listen_only => debug!(event = "dial_denied", ?peer_id, "Refused to dial, --listen-only is set"),
+                            _ if denied_ip => warn!(event = "connection_denied", ?peer_id, "Refused connection to a denied IP range"),
+                            _ if blocked => debug!(event = "connection_blocked", ?peer_id, "Refused connection to blocked peer"),
+                            Some(limit) => warn!(event = "connection_limit_exceeded", ?peer_id, %limit, "Refused outgoing connection"),
+                            None => warn!(event = "outgoing_connection_error", ?peer_id, %error, "Failed to dial"),
+                        }
+                        bootstrap.on_dial_failure(connection_id);
+                        for failed in dial_queue.on_dial_finished(&mut swarm, connection_id) {
+                            bootstrap.on_dial_failure(failed);
+                        }
+                    }
+                    SwarmEvent::IncomingConnectionError { send_back_addr, error, .. } => {
+                        let (blocked, limit) = match &error {
+                            ListenError::Denied { cause } => (is_blocked(cause), exceeded_limit(cause)),
+                            _ => (false, None),
+                        };
+                        let denied_ip = matches!(&error, ListenError::Denied { cause } if cause.downcast_ref::<DeniedIp>().is_some());
+                        match limit {
+                            _ if blocked => debug!(event = "connection_blocked", remote = %send_back_addr, "Refused connection from blocked peer"),
+                            _ if denied_ip => warn!(event = "connection_denied", remote = %send_back_addr, "Refused connection from a denied IP range"),
+                            Some(limit) => warn!(event = "connection_limit_exceeded", remote = %send_back_addr, %limit, "Refused incoming connection"),
+                            None => {
+                                let error = anyhow::Error::from(error);
+                                warn!(event = "incoming_connection_error", remote = %send_back_addr, error = format!("{error:#}"), "Incoming connection failed");
+                            }
+                        }
+                    }
+                    SwarmEvent::ConnectionClosed { peer_id, connection_id, endpoint, cause, num_established } => {
+                        warn!(event = "connection_closed", %peer_id, ?cause, "Connection closed");
+                        health.connections.fetch_sub(1, Ordering::Relaxed);
+                        let (transport, count) = transport_stats.closed(&endpoint);
+                        metrics.set_transport_connections(transport, count);
+                        if let Some(connection_ages) = &mut connection_ages {
+                            connection_ages.on_connection_closed(connection_id);
+                        }
+                        keepalive.on_connection_closed(&peer_id, &connection_id);
+                        if let Some(memory_pruner) = &mut memory_pruner {
+                            memory_pruner.on_connection_closed(connection_id);
+                        }
+                        if let Some(churn_monitor) = &mut churn_monitor {
+                            churn_monitor.on_connection_event(peer_id);
+                        }
+                        bootstrap.on_connection_closed(peer_id, num_established);
+                        peerstore.on_connection_closed(&peer_id);
+                        if num_established == 0 {
+                            event::emit(&event_sender, NetworkEvent::PeerDisconnected(peer_id));
+                        }
+                        if !swarm.is_connected(&peer_id) {
+                            rtt_tracker.remove(&peer_id);
+                            identify_log.remove(&peer_id);
+                            connection_timings.on_peer_disconnected(&peer_id);
+                            file_deliveries.on_peer_disconnected(&peer_id);
+                            relay_stats.on_peer_disconnected(&peer_id);
+                            metrics.set_relay_usage(relay_stats.reservations(), relay_stats.circuits());
+                            explicit_peers.remove(&mut swarm.behaviour_mut().gossipsub, &peer_id);
+                            swarm.behaviour_mut().kademlia.remove_peer(&peer_id);
+                            info!(%peer_id, "Removed from the routing table (if it was in there)");
+                        }
+                    }
+                    SwarmEvent::Behaviour(BehaviourEvent::Relay(e)) => {
+                        relay_stats.on_event(&e);
+                        metrics.set_relay_usage(relay_stats.reservations(), relay_stats.circuits());
+                    }
+                    SwarmEvent::Behaviour(BehaviourEvent::RelayClient(relay::client::Event::ReservationReqAccepted {
+                        relay_peer_id,
+                        renewal,
+                        ..
+                    })) => {
+                        relay_failover.on_reservation_accepted(relay_peer_id, renewal);
+                    }
+                    SwarmEvent::Behaviour(BehaviourEvent::RelayClient(e)) => {
+                        info!(event = "relay_client", ?e, "Relay client event");
+                    }
+                    SwarmEvent::Behaviour(BehaviourEvent::Dcutr(dcutr::Event {
+                        remote_peer_id,
+                        result,
+                    })) => match result {
+                        Ok(connection_id) => {
+                            info!(
+                                event = "hole_punch_succeeded",
+                                peer_id = %remote_peer_id,
+                                ?connection_id,
+                                "Upgraded relayed connection to a direct one"
+                            );
+                            if let Some(connection_ages) = &mut connection_ages {
+                                connection_ages.on_hole_punched(connection_id);
+                            }
+                        }
+                        Err(error) => warn!(
+                            event = "hole_punch_failed",
+                            peer_id = %remote_peer_id,
+                            %error,
+                            "Failed to upgrade relayed connection to a direct one"
+                        ),
+                    },
+
+                    SwarmEvent::Behaviour(BehaviourEvent::Ping(ping::Event {
+                        peer,
+                        result: Ok(rtt),
+                        ..
+                    })) => {
+                        debug!(%peer, ?rtt, "🏓 Ping");
+                        rtt_tracker.record_success(peer, rtt);
+                    }
+                    SwarmEvent::Behaviour(BehaviourEvent::Ping(ping::Event {
+                        peer,
+                        result: Err(error),
+                        ..
+                    })) => {
+                        debug!(%peer, %error, "Ping failed");
+
+                        if rtt_tracker.record_failure(peer) {
+                            warn!(
+                                event = "ping_failures",
+                                %peer,
+                                failures = opt.ping_max_failures,
+                                "Disconnecting after consecutive ping failures"
+                            );
+                            let _ = swarm.disconnect_peer_id(peer);
+                        }
+                    }
+
+                    SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(
+                        libp2p::gossipsub::Event::Message {
+                            message_id,
+                            propagation_source,
+                            message,
+                        },
+                    )) => {
+                            if let Some(memory_pruner) = &mut memory_pruner {
+                                memory_pruner.on_activity(&propagation_source);
+                            }
+
+                            // From here on, compressed messages are handled as if received on the plain topic.
+                            let mut message = message;
+                            let mut decompressed = false;
+                            if let Some(plain_topic) = compression::plain_topic(&message.topic) {
+                                match compression::decompress(&message.data, message_limits.limit(&plain_topic)) {
+                                    Ok(data) => {
+                                        message.topic = plain_topic;
+                                        message.data = data;
+                                        decompressed = true;
+                                    }
+                                    Err(e) => {
+                                        warn!(
+                                            topic = %message.topic,
+                                            peer_id = %propagation_source,
+                                            size = message.data.len(),
+                                            "Rejecting compressed message that doesn't decompress within the size limit: {e}"
+                                        );
+                                        if let Err(e) = swarm.behaviour_mut().gossipsub.report_message_validation_result(
+                                            &message_id,
+                                            &propagation_source,
+                                            gossipsub::MessageAcceptance::Reject,
+                                        ) {
+                                            debug!(%e, "Failed to reject message");
+                                        }
+                                        return;
+                                    }
+                                }
+                            }
+
+                            let limit = message_limits.limit(&message.topic);
+                            let oversized = message.data.len() > limit;
+                            let invalid = !oversized && !validators.is_valid(&message.topic, &message.data);
+                            let acceptance = if oversized {
+                                warn!(
+                                    topic = %message.topic,
+                                    peer_id = %propagation_source,
+                                    size = message.data.len(),
+                                    limit,
+                                    "Rejecting oversized message"
+                                );
+                                gossipsub::MessageAcceptance::Reject
+                            } else if invalid {
+                                warn!(
+                                    topic = %message.topic,
+                                    peer_id = %propagation_source,
+                                    size = message.data.len(),
+                                    "Rejecting malformed message"
+                                );
+                                gossipsub::MessageAcceptance::Reject
+                            } else {
+                                gossipsub::MessageAcceptance::Accept
+                            };
+                            if let Err(e) = swarm.behaviour_mut().gossipsub.report_message_validation_result(
+                                &message_id,
+                                &propagation_source,
+                                acceptance,
+                            ) {
+                                debug!(%e, "Failed to forward message");
+                            }
+                            if oversized || invalid {
+                                return;
+                            }
+                            // Copies peers bridged to the plain topic have the content id of the
+                            // decompressed message, which tells they are the same.
+                            let seen_id = if decompressed && opt.message_id_strategy == MessageIdStrategy::Content {
+                                content_message_id(&message)
+                            } else {
+                                message_id.clone()
+                            };
+                            if !seen_messages.insert(seen_id) {
+                                debug!(%message_id, "Ignoring message we already processed");
+                                return;
+                            }
+                            if decompressed {
+                                if let Some(Err(e)) = compression::bridge(&mut swarm.behaviour_mut().gossipsub, &message.topic, message.data.clone()) {
+                                    debug!(topic = %message.topic, "Failed to bridge compressed message to the plain topic: {e}");
+                                }
+                            }
+
+                            if message.topic == peer_discovery_topic.hash() {
+                                if !opt.listen_only {
+                                    dial_discovered_peer(&mut swarm, &mut dial_queue, &message.data, opt.allow_unsigned_discovery);
+                                }
+                                if let Some(relayed) = discovery_relay.relayed(&message.data, swarm.local_peer_id()) {
+                                    if let Err(e) = publish_payload(&mut swarm.behaviour_mut().gossipsub, peer_discovery_topic.clone(), relayed, opt.enable_compression) {
+                                        debug!(%e, "Failed to re-publish discovery message");
+                                    }
+                                }
+                            }
+                            if message.topic == topic_census_topic.hash() {
+                                if let (Some(topic_census), Some(source)) = (&mut topic_census, message.source) {
+                                    if let Err(e) = topic_census.on_announcement(source, &message.data) {
+                                        debug!(peer_id = %source, "Ignoring invalid topic census announcement: {e}");
+                                    }
+                                }
+                                return;
+                            }
+
+                            event::emit(&event_sender, NetworkEvent::MessageReceived {
+                                source: message.source,
+                                topic: message.topic,
+                                data: message.data,
+                            });
+                    }
+                    SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(
+                        libp2p::gossipsub::Event::Subscribed { peer_id, topic },
+                    )) => {
+                            debug!(%peer_id, %topic, "Peer subscribed");
+
+                            explicit_peers.add(&mut swarm.behaviour_mut().gossipsub, peer_id);
+                            // subscribe to this topic so we can act as super peer to browsers
+                            new_topics.record(&mut swarm.behaviour_mut().gossipsub, peer_id, &topic);
+
+                    }
+
+                    SwarmEvent::Behaviour(BehaviourEvent::Identify(e)) => {
+                        match &e {
+                            identify::Event::Received { peer_id, info }
+                                if opt.quiet_peer_log && !identify_log.changed(*peer_id, info) =>
+                            {
+                                trace!(event = "identify", ?e, "Identify event");
+                            }
+                            identify::Event::Sent { .. } if opt.quiet_peer_log => {
+                                trace!(event = "identify", ?e, "Identify event");
+                            }
+                            _ => info!(event = "identify", ?e, "Identify event"),
+                        }
+
+                        if let identify::Event::Error { peer_id, error } = e {
+                            match error {
+                                libp2p::swarm::StreamUpgradeError::Timeout => {
+                                    // When a browser tab is closed, we don't get a swarm event
+                                    // maybe there's a way to get this with TransportEvent
+                                    // but for now remove the peer from routing table if there's an Identify timeout
+                                    swarm.behaviour_mut().kademlia.remove_peer(&peer_id);
+                                    info!(%peer_id, "Removed from the routing table (if it was in there)");
+                                }
+                                _ => {
+                                    debug!(%peer_id, %error, "Identify failed");
+                                }
+                            }
+                        } else if let identify::Event::Received {
+                            peer_id,
+                            info:
+                                identify::Info {
+                                    public_key,
+                                    listen_addrs,
+                                    observed_addr,
+                                    ..
+                                },
+                        } = e
+                        {
+                            debug!(%peer_id, %observed_addr, "Identify received");
+                            if let Some((transport, elapsed)) = connection_timings.on_identify(&peer_id) {
+                                metrics.record_first_identify(transport, elapsed);
+                            }
+                            observed_addrs.observed(peer_id, observed_addr);
+                            apply_observed_addrs(&mut swarm, &mut observed_addrs);
+
+                            discovery_cache.insert(public_key, listen_addrs.clone());
+                            // Also covers identify pushes, which peers send as soon as their
+                            // addresses change, so stale addresses don't linger until the next
+                            // periodic identify.
+                            for addr in peerstore.insert(peer_id, listen_addrs.clone()) {
+                                debug!(%peer_id, %addr, "Peer no longer listens on address");
+                                swarm.behaviour_mut().kademlia.remove_address(&peer_id, &addr);
+                            }
+                            let evicted = peerstore.evict(|peer_id| {
+                                bootstrap.is_bootstrap_peer(peer_id) || swarm.is_connected(peer_id)
+                            });
+                            for peer_id in evicted {
+                                debug!(%peer_id, "Evicted peer seen least recently from the peerstore");
+                                swarm.behaviour_mut().kademlia.remove_peer(&peer_id);
+                            }
+                            metrics.set_peerstore_peers(peerstore.len());
+
+                            for addr in listen_addrs {
+                                debug!(%peer_id, %addr, "Identify listen addr");
+                                swarm.behaviour_mut().kademlia.add_address(&peer_id, addr);
+                            }
+                        }
+                    },
+                    SwarmEvent::Behaviour(BehaviourEvent::RequestResponse(
+                        request_response::Event::Message { peer, message },
+                    )) => match message {
+                        request_response::Message::Request { request_id, request, channel } => {
+                            if let Some(memory_pruner) = &mut memory_pruner {
+                                memory_pruner.on_activity(&peer);
+                            }
+                            debug!(%peer, file_id = ?request.file_id, "Received file request");
+
+                            let file_dir = opt.file_dir.clone();
+                            file_lookups.push(async move {
+                                let response = serve_file(file_dir.as_deref(), &request).await;
+                                (peer, request_id, request.file_id, channel, response)
+                            });
+                        }
+                        request_response::Message::Response { response, .. } => {
+                            debug!(%peer, ?response, "Received file response");
+                        }
+                    },
+                    SwarmEvent::Behaviour(BehaviourEvent::RequestResponse(
+                        request_response::Event::ResponseSent { request_id, .. },
+                    )) => {
+                        file_deliveries.on_response_sent(request_id);
+                    }
+                    SwarmEvent::Behaviour(BehaviourEvent::RequestResponse(
+                        request_response::Event::InboundFailure { peer, request_id, error },
+                    )) => {
+                        warn!(event = "file_request_failed", %peer, %error, "Inbound file request failed");
+                        if let Some(file_id) = file_deliveries.on_inbound_failure(request_id) {
+                            event::emit(&event_sender, NetworkEvent::TransferAborted { peer, file_id });
+                        }
+                    }
+                    SwarmEvent::Behaviour(BehaviourEvent::FileAck(
+                        request_response::Event::Message {
+                            peer,
+                            message: request_response::Message::Request { request, channel, .. },
+                        },
+                    )) => {
+                        match file_deliveries.on_ack(peer, &request) {
+                            Some(bytes_sent) => {
+                                info!(
+                                    event = "file_delivered",
+                                    %peer,
+                                    file_id = request.file_id,
+                                    bytes_sent,
+                                    bytes_received = request.bytes_received,
+                                    complete = request.bytes_received == bytes_sent,
+                                    "Peer acknowledged file"
+                                );
+                                event::emit(&event_sender, NetworkEvent::FileDelivered {
+                                    peer,
+                                    file_id: request.file_id,
+                                    bytes_received: request.bytes_received,
+                                });
+                            }
+                            None => debug!(%peer, file_id = request.file_id, "Ignoring acknowledgement of a file we didn't send"),
+                        }
+                        if let Some(file_ack) = swarm.behaviour_mut().file_ack.as_mut() {
+                            let _ = file_ack.send_response(channel, ());
+                        }
+                    }
+                    SwarmEvent::Behaviour(BehaviourEvent::RequestResponse(
+                        request_response::Event::OutboundFailure { peer, error, .. },
+                    )) => {
+                        warn!(event = "file_request_failed", %peer, %error, "Outbound file request failed");
+                    }
+                    SwarmEvent::Behaviour(BehaviourEvent::TopicQuery(
+                        request_response::Event::Message {
+                            peer,
+                            message: request_response::Message::Request { channel, .. },
+                        },
+                    )) => {
+                        let topics = topic_query::subscribed_topics(&swarm.behaviour().gossipsub);
+                        debug!(%peer, topics = topics.len(), "Answering topic query");
+                        if swarm.behaviour_mut().topic_query.send_response(channel, topics).is_err() {
+                            warn!(%peer, "Failed to send topic query response");
+                        }
+                    }
+                    SwarmEvent::Behaviour(BehaviourEvent::Keepalive(
+                        request_response::Event::Message { peer, message },
+                    )) => match message {
+                        request_response::Message::Request { request, channel, .. } => {
+                            let _ = swarm.behaviour_mut().keepalive.send_response(channel, request);
+                        }
+                        request_response::Message::Response { .. } => keepalive.on_answered(&peer),
+                    },
+                    SwarmEvent::Behaviour(BehaviourEvent::Keepalive(
+                        request_response::Event::OutboundFailure { peer, error, .. },
+                    )) => {
+                        if let request_response::OutboundFailure::UnsupportedProtocols = error {
+                            debug!(%peer, "Peer doesn't support keep-alive probes");
+                            keepalive.on_unsupported(peer);
+                        } else if let Some((misses, connections)) = keepalive.on_missed(peer) {
+                            warn!(
+                                event = "keepalive_timeout",
+                                %peer,
+                                misses,
+                                %error,
+                                "Closing relayed connections, keep-alive probes went unanswered"
+                            );
+                            for connection_id in connections {
+                                swarm.close_connection(connection_id);
+                            }
+                        }
+                    }
+                    SwarmEvent::Behaviour(BehaviourEvent::Upnp(e)) => match e {
+                        // The behaviour confirms the address with the swarm itself.
+                        upnp::Event::NewExternalAddr(addr) => {
+                            info!(event = "upnp_mapped", %addr, "Router forwards a port to us via UPnP");
+                            observed_addrs.pin(addr);
+                        }
+                        upnp::Event::ExpiredExternalAddr(addr) => {
+                            warn!(event = "upnp_mapping_expired", %addr, "Failed to renew UPnP port mapping");
+                        }
+                        upnp::Event::GatewayNotFound => {
+                            warn!(event = "upnp_unsupported", "No UPnP capable router found, the ports have to be forwarded manually");
+                        }
+                        upnp::Event::NonRoutableGateway => {
+                            warn!(event = "upnp_unsupported", "The UPnP router is not publicly reachable itself, e.g. due to carrier-grade NAT");
+                        }
+                    },
+                    SwarmEvent::Behaviour(BehaviourEvent::Mdns(mdns::Event::Discovered(peers))) => {
+                        for (peer_id, addr) in peers {
+                            debug!(%peer_id, %addr, "mDNS discovered peer");
+                            if blocklist.contains(&peer_id) {
+                                continue;
+                            }
+                            explicit_peers.add(&mut swarm.behaviour_mut().gossipsub, peer_id);
+
+                            if swarm.is_connected(&peer_id) {
+                                continue;
+                            }
+                            let opts = DialOpts::peer_id(peer_id).addresses(vec![addr.clone()]).build();
+                            if let Err(e) = dial_queue.dial(&mut swarm, opts) {
+                                debug!(%addr, %e, "Failed to dial");
+                            }
+                        }
+                    }
+                    SwarmEvent::Behaviour(BehaviourEvent::Autonat(autonat::Event::StatusChanged {
+                        old,
+                        new,
+                    })) => {
+                        info!(event = "nat_status_changed", ?old, ?new, "NAT status changed");
+                        metrics.set_nat_status(&new);
+                        event::emit(&event_sender, NetworkEvent::NatStatusChanged(new.clone()));
+
+                        match new {
+                            autonat::NatStatus::Public(addr) => {
+                                observed_addrs.pin(addr.clone());
+                                swarm.add_external_address(addr);
+                            }
+                            autonat::NatStatus::Private => {
+                                warn!("AutoNAT reports that we are not publicly reachable");
+
+                                relay_failover.reserve(&mut swarm);
+                            }
+                            autonat::NatStatus::Unknown => {}
+                        }
+                    }
+                    SwarmEvent::Behaviour(BehaviourEvent::Kademlia(
+                        kad::Event::OutboundQueryProgressed {
+                            result: kad::QueryResult::Bootstrap(result),
+                            step,
+                            ..
+                        },
+                    )) => {
+                        match result {
+                            Ok(kad::BootstrapOk { peer, num_remaining }) => {
+                                debug!(%peer, num_remaining, "Kademlia bootstrap progressed");
+                            }
+                            Err(e) => warn!(event = "kad_bootstrap_failed", %e, "Kademlia bootstrap failed"),
+                        }
+                        if step.last {
+                            let routing_table_size = swarm
+                                .behaviour_mut()
+                                .kademlia
+                                .kbuckets()
+                                .map(|bucket| bucket.num_entries())
+                                .sum::<usize>();
+                            info!(event = "kad_bootstrap_finished", routing_table_size, "Kademlia bootstrap finished");
+                        }
+                    }
+                    SwarmEvent::Behaviour(BehaviourEvent::Kademlia(e)) => {
+                        debug!(?e, "Kademlia event");
+                    },
+                    _ => {},
+                });
+                if let Some(address) = lost_listener {
+                    bail!("Stopped listening on {address}");
+                }
+            }
+            _ = &mut tick => {
+                tick = futures_timer::Delay::new(TICK_INTERVAL);
+
+                seen_messages.evict_expired();
+                file_deliveries.evict_expired();
+                observed_addrs.expire();
+                apply_observed_addrs(&mut swarm, &mut observed_addrs);
+
+                if let Some(connection_ages) = &connection_ages {
+                    let expired = connection_ages.expired(|peer_id| {
+                        bootstrap.is_bootstrap_peer(peer_id)
+                            || relay_failover.is_relay(peer_id)
+                            || relay_stats.has_reservation(peer_id)
+                    });
+                    for (connection_id, peer_id, age) in expired {
+                        info!(event = "connection_recycled", %peer_id, ?age, "Closing connection older than --max-connection-age-seconds");
+                        swarm.close_connection(connection_id);
+                    }
+                }
+
+                if let Some(memory_pruner) = &mut memory_pruner {
+                    let evictions = memory_pruner.evictions(|peer_id| {
+                        bootstrap.is_bootstrap_peer(peer_id)
+                            || relay_failover.is_relay(peer_id)
+                            || relay_stats.has_reservation(peer_id)
+                    });
+                    for eviction in evictions {
+                        warn!(
+                            event = "connection_evicted",
+                            peer_id = %eviction.peer_id,
+                            idle = ?eviction.idle,
+                            estimated_freed_bytes = eviction.estimated_bytes,
+                            "Closing idle connection, memory is above --memory-high-watermark"
+                        );
+                        swarm.close_connection(eviction.connection_id);
+                    }
+                }
+
+                if !opt.relay_only {
+                    for topic in new_topics.drain(&mut swarm.behaviour_mut().gossipsub) {
+                        auto_subscribe(&mut swarm, &topic, opt);
+                    }
+                }
+
+                if opt.publish_status {
+                    let status = NodeStatus {
+                        peer_id: swarm.local_peer_id().to_string(),
+                        agent_version: opt.identify_agent_version.clone(),
+                        uptime_seconds: started.elapsed().as_secs(),
+                        connected_peers: swarm.network_info().num_peers() as u32,
+                        reservations: relay_stats.reservations() as u32,
+                    };
+                    if let Err(e) = swarm
+                        .behaviour_mut()
+                        .gossipsub
+                        .publish(status_topic.clone(), status.encode_to_vec())
+                    {
+                        debug!(%e, "Failed to publish status");
+                    }
+                }
+
+                if let (false, Some(rotation)) = (cert_rotation_due, cert_rotation) {
+                    cert_rotation_due =
+                        cert::rotation_due(Path::new(LOCAL_CERT_PATH), rotation).await;
+                    if cert_rotation_due {
+                        warn!(
+                            "WebRTC certificate is older than {:?}, restart the node to rotate it",
+                            rotation.max_age
+                        );
+                    }
+                }
+
+                if opt.bootstrap_on_empty && !opt.listen_only && swarm.network_info().num_peers() == 0 {
+                    let since = *isolated_since.get_or_insert_with(Instant::now);
+                    if since.elapsed() >= isolation_timeout {
+                        warn!(event = "self_heal", isolated = ?since.elapsed(), "No connections, redialing bootstrap peers");
+                        bootstrap.dial_all(&mut swarm, &mut dial_queue);
+                        if swarm.behaviour_mut().kademlia.bootstrap().is_ok() {
+                            last_kad_bootstrap = Some(Instant::now());
+                        }
+                        // Wait another timeout before the next attempt.
+                        isolated_since = Some(Instant::now());
+                    }
+                }
+
+                if last_kad_bootstrap.is_none_or(|at| at.elapsed() >= kad_bootstrap_interval) {
+                    // Fails while the routing table is empty, e.g. on a fresh node without peers,
+                    // in which case we try again on the next tick.
+                    match swarm.behaviour_mut().kademlia.bootstrap() {
+                        Ok(_) => {
+                            debug!("Bootstrapping Kademlia");
+                            last_kad_bootstrap = Some(Instant::now());
+                        }
+                        Err(kad::NoKnownPeers()) => debug!("Not bootstrapping Kademlia, the routing table is empty"),
+                    }
+                }
+
+                debug!(
+                    external_addrs = ?swarm.external_addresses().collect::<Vec<&Multiaddr>>(),
+                    "External addresses"
+                );
+            }
+            _ = &mut discovery_tick => {
+                discovery_tick = futures_timer::Delay::new(discovery_interval);
+                // Publishing to a topic we aren't subscribed to would still put us in its
+                // fanout.
+                if opt.relay_only {
+                    continue;
+                }
+
+                let own_addrs = swarm
+                    .external_addresses()
+                    .chain(swarm.listeners())
+                    .cloned()
+                    .collect::<Vec<_>>();
+                let own_peer = match discovery::signed_peer(&local_key, &own_addrs, opt.discovery_ttl) {
+                    Ok(peer) => Some(peer),
+                    Err(e) => {
+                        warn!(%e, "Failed to sign discovery message");
+                        None
+                    }
+                };
+                for peer in own_peer.into_iter().chain(discovery_cache.to_messages()) {
+                    if let Err(e) = publish_payload(
+                        &mut swarm.behaviour_mut().gossipsub,
+                        peer_discovery_topic.clone(),
+                        peer.encode_to_vec(),
+                        opt.enable_compression,
+                    ) {
+                        debug!(%e, "Failed to publish discovery message");
+                    }
+                }
+            }
+            _ = &mut mesh_health_tick => {
+                mesh_health_tick = futures_timer::Delay::new(mesh_health_interval);
+
+                repair_thin_meshes(&mut swarm, &mut dial_queue, mesh_n_low, &discovery_cache, &mut mesh_repair, &metrics);
+                if let Some(score_monitor) = &mut score_monitor {
+                    score_monitor.check(&swarm.behaviour().gossipsub);
+                }
+            }
+            _ = &mut peerstore_save_tick => {
+                peerstore_save_tick = futures_timer::Delay::new(peerstore_save_interval);
+
+                if let Err(e) = peerstore.save().await {
+                    warn!(error = format!("{e:#}"), "Failed to save peerstore");
+                }
+            }
+            _ = &mut keepalive_tick => {
+                keepalive_tick = futures_timer::Delay::new(keepalive_interval);
+
+                for peer_id in keepalive.peers_to_probe() {
+                    swarm.behaviour_mut().keepalive.send_request(&peer_id, rand::random());
+                }
+            }
+            _ = &mut topic_census_tick => {
+                topic_census_tick = futures_timer::Delay::new(topic_census_interval);
+
+                if let Some(topic_census) = &mut topic_census {
+                    topic_census.expire();
+                    let gossipsub = &mut swarm.behaviour_mut().gossipsub;
+                    let announcement = TopicCensus::announcement(gossipsub.topics());
+                    if let Err(e) = gossipsub.publish(topic_census_topic.clone(), announcement) {
+                        debug!(?e, "Failed to publish topic census announcement");
+                    }
+                }
+            }
+            _ = &mut churn_tick => {
+                churn_tick = futures_timer::Delay::new(CHURN_INTERVAL);
+
+                if let Some(churn_monitor) = &mut churn_monitor {
+                    for peer_id in churn_monitor.expired_backoffs() {
+                        if !blocklist.contains(&peer_id) {
+                            info!(event = "churn_backoff_expired", %peer_id, "Accepting connections from peer again");
+                            swarm.behaviour_mut().blocked_peers.unblock_peer(peer_id);
+                        }
+                    }
+
+                    let sample = churn_monitor.sample(|peer_id| bootstrap.is_bootstrap_peer(peer_id));
+                    metrics.set_connection_churn(sample.rate, sample.alert.is_some());
+                    match sample.alert {
+                        Some(Alert { dominant_peer: Some((peer_id, share)), backed_off }) => {
+                            warn!(
+                                event = "connection_churn_alert",
+                                churn_per_minute = sample.rate,
+                                %peer_id,
+                                share,
+                                backed_off,
+                                "Connection churn is above --churn-alert-threshold, mostly caused by a single peer"
+                            );
+                            if backed_off {
+                                swarm.behaviour_mut().blocked_peers.block_peer(peer_id);
+                            }
+                        }
+                        Some(Alert { dominant_peer: None, .. }) => {
+                            warn!(
+                                event = "connection_churn_alert",
+                                churn_per_minute = sample.rate,
+                                "Connection churn is above --churn-alert-threshold"
+                            );
+                        }
+                        None => debug!(churn_per_minute = sample.rate, "Connection churn"),
+                    }
+                }
+            }
+            _ = &mut stats_tick => {
+                stats_tick = futures_timer::Delay::new(stats_interval);
+
+                metrics.set_peer_rtts(rtt_tracker.rtts());
+                info!(event = "transport_connections", "Connections by transport: {}", transport_stats.summary());
+                let (in_flight, queued) = dial_queue.depth();
+                info!(event = "dial_queue", in_flight, queued, "Dials in flight and waiting for --dial-concurrency");
+                info!(event = "connection_timings", "Connection setup p50/p95/p99 by transport: {}", connection_timings.summary());
+
+                let ((inbound, outbound), now) = (bandwidth.totals(), Instant::now());
+                let ((last_inbound, last_outbound), last_sample) = bandwidth_sample;
+                let elapsed = now.duration_since(last_sample).as_secs_f64();
+                let inbound_rate = (inbound - last_inbound) as f64 / elapsed;
+                let outbound_rate = (outbound - last_outbound) as f64 / elapsed;
+                metrics.set_bandwidth_throughput(inbound_rate, outbound_rate);
+                metrics.record_relayed_bytes(relay_bytes.take_relayed());
+                relay_bytes.expire();
+                bandwidth_sample = ((inbound, outbound), now);
+                let throttled = bandwidth.take_throttled();
+                if throttled > 0 {
+                    info!(
+                        event = "bandwidth_throttled",
+                        throttled,
+                        inbound_bytes_per_sec = inbound_rate as u64,
+                        outbound_bytes_per_sec = outbound_rate as u64,
+                        "Delayed reads and writes to stay below --max-bandwidth-bytes-per-sec"
+                    );
+                }
+            }
+            _ = relay_failover.next_attempt() => {
+                relay_failover.reserve(&mut swarm);
+            }
+            Some((addr, attempt)) = bootstrap.next_retry() => {
+                bootstrap.dial(&mut swarm, &mut dial_queue, addr, attempt);
+            }
+            _ = sighup.recv() => {
+                if let Err(e) = bootstrap.reload(&mut swarm, &mut dial_queue) {
+                    error!(error = format!("{e:#}"), "Failed to reload bootstrap peers");
+                }
+            }
+            _ = sigusr1.recv() => {
+                let snapshot = state_dump::snapshot(&mut swarm, &explicit_peers);
+                match state_dump::write(&opt.state_dump_file, &snapshot).await {
+                    Ok(()) => info!(event = "state_dumped", path = %opt.state_dump_file.display(), "Wrote state snapshot"),
+                    Err(e) => error!(error = format!("{e:#}"), "Failed to write state snapshot"),
+                }
+            }
+            Some(command) = commands.recv() => {
+                handle_command(&mut swarm, command, &message_limits, &mut publish_throttle, opt.enable_compression, opt.relay_only);
+            }
+            publish = publish_throttle.next_ready() => {
+                publish_message(&mut swarm, publish, opt.enable_compression);
+            }
+            Some((peer, request_id, file_id, channel, response)) = file_lookups.next(), if !file_lookups.is_empty() => {
+                let size = match &response {
+                    FileResponse::File { size, .. } => Some(*size),
+                    _ => None,
+                };
+                // Only requested while file exchange is enabled.
+                let Some(file_exchange) = swarm.behaviour_mut().request_response.as_mut() else {
+                    continue;
+                };
+                // Fails if the request timed out or the connection closed meanwhile, in which case
+                // there is no delivery to track.
+                if file_exchange.send_response(channel, response).is_err() {
+                    warn!(%peer, "Failed to send file response");
+                    continue;
+                }
+                if let Some(size) = size {
+                    file_deliveries.on_response_queued(request_id, peer, file_id, size);
+                }
+            }
+            Some(AdminRequest { command, reply }) = admin_requests.recv() => {
+                let _ = reply.send(handle_admin_command(&mut swarm, &mut dial_queue, &seen_messages, &mut blocklist, &mut explicit_peers, topic_census.as_ref(), command));
+            }
+            result = &mut shutdown => {
+                result.context("Failed to listen for shutdown signals")?;
+                break;
+            }
+        }
+    }
+
+    let listeners = listeners
+        .into_iter()
+        .map(|(listener_id, _)| listener_id)
+        .chain(relay_failover.listener())
+        .collect();
+    shutdown_swarm(
+        &mut swarm,
+        listeners,
+        Duration::from_secs(opt.shutdown_grace_seconds),
+    )
+    .await;
+
+    if let Err(e) = peerstore.save().await {
+        warn!(error = format!("{e:#}"), "Failed to save peerstore");
+    }
+
+    Ok(())
+}
+
+/// E.g. `universal-connectivity-rust-peer/0.1.2+1a2b3c4`, the git hash being left out if it wasn't
+/// available at build time.
+fn default_agent_version() -> String {
+    let version = env!("CARGO_PKG_VERSION");
+
+    match option_env!("GIT_HASH") {
+        Some(hash) => format!("universal-connectivity-rust-peer/{version}+{hash}"),
+        None => format!("universal-connectivity-rust-peer/{version}"),
+    }
+}
+
+fn cert_rotation(opt: &Opt) -> Option<RotationPolicy> {
+    opt.cert_max_age_days.map(|days| RotationPolicy {
+        max_age: Duration::from_secs(days * 24 * 60 * 60),
+        grace: Duration::from_secs(opt.cert_grace_days * 24 * 60 * 60),
+    })
+}
+
+fn handle_command(
+    swarm: &mut Swarm<Behaviour>,
+    command: Command,
+    limits: &MessageSizeLimits,
+    publish_throttle: &mut PublishThrottle,
+    compress: bool,
+    relay_only: bool,
+) {
+    match command {
+        Command::PublishMessage { topic, data, reply } => {
+            let topic = gossipsub::IdentTopic::new(topic);
+            if relay_only {
+                debug!(%topic, "Not publishing, --relay-only is set");
+                let _ = reply.send(Err(command::PublishError::RelayOnly));
+                return;
+            }
+            let limit = limits.limit(&topic.hash());
+            if data.len() > limit {
+                warn!(%topic, size = data.len(), limit, "Not publishing oversized message");
+                let _ = reply.send(Err(gossipsub::PublishError::MessageTooLarge.into()));
+                return;
+            }
+
+            if let Some(publish) = publish_throttle.submit(PendingPublish { topic, data, reply }) {
+                publish_message(swarm, publish, compress);
+            }
+        }
+    }
+}
+
+fn publish_message(swarm: &mut Swarm<Behaviour>, publish: PendingPublish, compress: bool) {
+    let result = publish_payload(
+        &mut swarm.behaviour_mut().gossipsub,
+        publish.topic,
+        publish.data,
+        compress,
+    );
+    let _ = publish.reply.send(result.map_err(Into::into));
+}
+
+/// Publishes `data` on `topic`, compressed if `compress` is set and all subscribers support it.
+fn publish_payload(
+    gossipsub: &mut gossipsub::Behaviour,
+    topic: gossipsub::IdentTopic,
+    data: Vec<u8>,
+    compress: bool,
+) -> Result<gossipsub::MessageId, gossipsub::PublishError> {
+    if compress {
+        compression::publish(gossipsub, topic, data)
+    } else {
+        gossipsub.publish(topic, data)
+    }
+}
+
+fn handle_admin_command(
+    swarm: &mut Swarm<Behaviour>,
+    dial_queue: &mut DialQueue,
+    seen_messages: &SeenMessages,
+    blocklist: &mut Blocklist,
+    explicit_peers: &mut ExplicitPeers,
+    topic_census: Option<&TopicCensus>,
+    command: AdminCommand,
+) -> Result<serde_json::Value, AdminError> {
+    match command {
+        AdminCommand::HasSeenMessage(message_id) => {
+            Ok(serde_json::Value::Bool(seen_messages.contains(&message_id)))
+        }
+        AdminCommand::DumpState => Ok(state_dump::snapshot(swarm, explicit_peers)),
+        AdminCommand::ListConnectedPeers => Ok(swarm
+            .connected_peers()
+            .map(|peer| peer.to_string())
+            .collect()),
+        AdminCommand::ListSubscribedTopics => Ok(swarm
+            .behaviour()
+            .gossipsub
+            .topics()
+            .map(|topic| topic.to_string())
+            .collect()),
+        AdminCommand::TopicPeers => {
+            let gossipsub = &swarm.behaviour().gossipsub;
+            let topics = gossipsub
+                .topics()
+                .map(|topic| {
+                    let subscribers = || {
+                        gossipsub
+                            .all_peers()
+                            .filter(|(_, topics)| topics.contains(&topic))
+                            .map(|(peer_id, _)| peer_id)
+                    };
+                    let counts = serde_json::json!({
+                        "mesh": gossipsub.mesh_peers(topic).count(),
+                        "subscribers": subscribers().count(),
+                        "estimate": topic_census.map(|census| census.estimate(topic, subscribers())),
+                    });
+
+                    (topic.to_string(), counts)
+                })
+                .collect::<serde_json::Map<_, _>>();
+
+            Ok(serde_json::Value::Object(topics))
+        }
+        AdminCommand::ListListenAddrs => {
+            let local_peer_id = *swarm.local_peer_id();
+            let dialable = |addr: &Multiaddr| {
+                addr.clone()
+                    .with_p2p(local_peer_id)
+                    .unwrap_or_else(|addr| addr)
+                    .to_string()
+            };
+
+            Ok(serde_json::json!({
+                "listen": swarm.listeners().map(dialable).collect::<Vec<_>>(),
+                "external": swarm.external_addresses().map(dialable).collect::<Vec<_>>(),
+            }))
+        }
+        AdminCommand::Dial(addr) => {
+            if swarm.behaviour().listen_only.is_enabled() {
+                return Err(AdminError::server(OutboundDisabled.to_string()));
+            }
+            info!(%addr, "Dialing via admin API");
+            dial_queue
+                .dial(swarm, addr.into())
+                .map_err(|e| AdminError::server(e.to_string()))?;
+
+            Ok(serde_json::Value::Bool(true))
+        }
+        AdminCommand::Disconnect(peer_id) => {
+            info!(%peer_id, "Disconnecting via admin API");
+            swarm
+                .disconnect_peer_id(peer_id)
+                .map_err(|_| AdminError::server(format!("not connected to {peer_id}")))?;
+
+            Ok(serde_json::Value::Bool(true))
+        }
+        AdminCommand::BlockPeer(peer_id) => {
+            info!(%peer_id, "Blocking via admin API");
+            let added = blocklist
+                .block(peer_id)
+                .map_err(|e| AdminError::server(format!("{e:#}")))?;
+            block_peer(swarm, peer_id);
+            explicit_peers.remove(&mut swarm.behaviour_mut().gossipsub, &peer_id);
+
+            Ok(serde_json::Value::Bool(added))
+        }
+    }
+}
+
+/// Refuses connections with `peer_id`, closing the current ones, and ignores the gossipsub
+/// messages it published even when they reach us through other peers.
+fn block_peer(swarm: &mut Swarm<Behaviour>, peer_id: PeerId) {
+    swarm.behaviour_mut().blocked_peers.block_peer(peer_id);
+    swarm.behaviour_mut().gossipsub.blacklist_peer(&peer_id);
+}
+
+fn init_logging(format: LogFormat) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    // Logs go to stderr, leaving stdout for output meant for other programs, like the probe result.
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+
+    match format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().flatten_event(true).init(),
+    }
+}
+
+/// Logs the configuration we ended up with after merging the config file, environment and flags,
+/// as a single record. The identity is only described by its key type.
+fn log_startup_config(
+    swarm: &Swarm<Behaviour>,
+    opt: &Opt,
+    key_type: identity::KeyType,
+    listeners: &[(ListenerId, Multiaddr)],
+) {
+    let transports = [
+        ("tcp", !opt.disable_tcp),
+        ("quic", !opt.disable_quic),
+        ("webrtc", !opt.disable_webrtc),
+        ("websocket", !opt.disable_websocket),
+    ]
+    .into_iter()
+    .filter_map(|(transport, enabled)| enabled.then_some(transport))
+    .collect::<Vec<_>>()
+    .join(",");
+    let listen_addrs = listeners
+        .iter()
+        .map(|(_, address)| address.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+    let topics = swarm
+        .behaviour()
+        .gossipsub
+        .topics()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(",");
+    let peer_id = swarm.local_peer_id();
+
+    match opt.log_format {
+        // One flat object, so log pipelines can index every setting.
+        LogFormat::Json => info!(
+            event = "startup_config",
+            %peer_id,
+            %key_type,
+            %listen_addrs,
+            %transports,
+            max_reservations = opt.max_reservations,
+            max_reservations_per_peer = opt.max_reservations_per_peer,
+            max_circuits = opt.max_circuits,
+            max_circuits_per_peer = opt.max_circuits_per_peer,
+            %topics,
+            "Effective configuration"
+        ),
+        LogFormat::Text => info!(
+            event = "startup_config",
+            "Effective configuration:
+    peer id:      {peer_id}
+    key type:     {key_type}
+    listen addrs: {listen_addrs}
+    transports:   {transports}
+    relay limits: {} reservations ({} per peer), {} circuits ({} per peer)
+    topics:       {topics}",
+            opt.max_reservations,
+            opt.max_reservations_per_peer,
+            opt.max_circuits,
+            opt.max_circuits_per_peer,
+        ),
+    }
+}
+
+/// The addresses of the enabled transports to listen on for `ip`.
+fn listen_addresses(ip: IpAddr, opt: &Opt, wss_enabled: bool) -> Vec<Multiaddr> {
+    let mut addresses = Vec::new();
+
+    if !opt.disable_tcp {
+        addresses.push(Multiaddr::from(ip).with(Protocol::Tcp(opt.tcp_port)));
+    }
+    if !opt.disable_webrtc {
+        addresses.push(
+            Multiaddr::from(ip)
+                .with(Protocol::Udp(opt.webrtc_port))
+                .with(Protocol::WebRTCDirect),
+        );
+    }
+    if !opt.disable_quic {
+        addresses.push(
+            Multiaddr::from(ip)
+                .with(Protocol::Udp(opt.quic_port))
+                .with(Protocol::QuicV1),
+        );
+    }
+    if !opt.disable_websocket {
+        // This version of libp2p-websocket only accepts the `/wss` form of `/tls/ws` for listening.
+        addresses.push(Multiaddr::from(ip).with(Protocol::Tcp(opt.ws_port)).with(
+            if wss_enabled {
+                Protocol::Wss("/".into())
+            } else {
+                Protocol::Ws("/".into())
+            },
+        ));
+    }
+
+    addresses
+}
+
+/// Prints the addresses we listen on with these options, the way they get advertised, WebRTC
+/// first since that is what browsers dial.
+async fn print_dial_strings(opt: &Opt) -> Result<()> {
+    let local_key = read_or_create_identity(Path::new(LOCAL_KEY_PATH), opt.identity_type)
+        .await
+        .context("Failed to read identity")?;
+    let local_peer_id = local_key.public().to_peer_id();
+    // Not rotated, so the hash matches the certificate a running node presents.
+    let certificate = cert::read_or_create_certificate(Path::new(LOCAL_CERT_PATH), None)
+        .await
+        .context("Failed to read certificate")?;
+    let certhash = Protocol::Certhash(certificate.fingerprint().to_multihash());
+    let wss_enabled = opt.wss_cert.is_some() && opt.wss_key.is_some();
+    let external_ips = global_external_ips(&opt.external_address);
+
+    let mut addresses = opt
+        .listen_address
+        .iter()
+        .flat_map(|ip| listen_addresses(*ip, opt, wss_enabled))
+        .map(|address| {
+            let address = if address.iter().any(|protocol| protocol == Protocol::WebRTCDirect) {
+                address.with(certhash.clone())
+            } else {
+                address
+            };
+            advertise_override(&address, opt)
+                .or_else(|| external_address_for(&address, &external_ips))
+                .unwrap_or(address)
+        })
+        .collect::<Vec<_>>();
+    addresses.sort_by_key(|address| {
+        transport_stats::TRANSPORTS
+            .iter()
+            .position(|transport| *transport == transport_stats::classify(address))
+    });
+    addresses.dedup();
+
+    for address in addresses {
+        let unspecified = match address.iter().next() {
+            Some(Protocol::Ip4(ip)) => ip.is_unspecified(),
+            Some(Protocol::Ip6(ip)) => ip.is_unspecified(),
+            _ => false,
+        };
+        if unspecified {
+            warn!(%address, "Not dialable, pass the public IP with --external-address");
+        }
+
+        println!("{}", address.with_p2p(local_peer_id).unwrap_or_else(|address| address));
+    }
+
+    Ok(())
+}
+
+/// The `--external-address` IPs worth advertising. Addresses that aren't reachable from the internet
+/// would only make other peers dial in vain.
+fn global_external_ips(ips: &[IpAddr]) -> Vec<IpAddr> {
+    ips.iter()
+        .copied()
+        .filter(|ip| {
+            let global = is_global(ip);
+            if !global {
+                warn!(%ip, "Not advertising --external-address, it is not globally routable");
+            }
+            global
+        })
+        .collect()
+}
+
+/// Whether `ip` is routable on the internet, i.e. not private, shared (CGNAT), loopback,
+/// link-local or unspecified.
+fn is_global(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            let shared = a == 100 && b & 0xc0 == 64;
+
+            !(ip.is_private() || shared || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified())
+        }
+        IpAddr::V6(ip) => {
+            let segment = ip.segments()[0];
+            let unique_local = segment & 0xfe00 == 0xfc00;
+            let link_local = segment & 0xffc0 == 0xfe80;
+
+            !(ip.is_loopback() || ip.is_unspecified() || unique_local || link_local)
+        }
+    }
+}
+
+/// Advertises the observed addresses enough peers agree on, and stops advertising the ones they no
+/// longer do.
+fn apply_observed_addrs(swarm: &mut Swarm<Behaviour>, observed_addrs: &mut ObservedAddrs) {
+    let (promoted, demoted) = observed_addrs.changes();
+    for addr in promoted {
+        let confirmations = observed_addrs.confirmations(&addr);
+        info!(event = "observed_addr_confirmed", %addr, confirmations, "Advertising observed address");
+        swarm.add_external_address(addr);
+    }
+    for addr in demoted {
+        info!(event = "observed_addr_expired", %addr, "No longer advertising observed address");
+        swarm.remove_external_address(&addr);
+    }
+}
+
+/// The `--advertise-webrtc` or `--advertise-quic` address to advertise instead of the listen
+/// `address`, if one is set for its transport.
+fn advertise_override(address: &Multiaddr, opt: &Opt) -> Option<Multiaddr> {
+    if address.iter().any(|protocol| protocol == Protocol::WebRTCDirect) {
+        let advertised = opt.advertise_webrtc.clone()?;
+        // Browsers only accept the connection if the certificate matches the hash, so the
+        // address is useless without it.
+        let certhashes = address
+            .iter()
+            .filter(|protocol| matches!(protocol, Protocol::Certhash(_)))
+            .collect::<Vec<_>>();
+        if certhashes.is_empty() {
+            warn!(%address, "Not using --advertise-webrtc, the listen address has no certificate hash");
+            return None;
+        }
+
+        return Some(certhashes.into_iter().fold(advertised, Multiaddr::with));
+    }
+
+    let is_quic = address.iter().any(|protocol| protocol == Protocol::QuicV1)
+        && !address.iter().any(|protocol| protocol == Protocol::WebTransport);
+    if is_quic {
+        return opt.advertise_quic.clone();
+    }
+
+    None
+}
+
+fn parse_dns_resolver(s: &str) -> Result<DnsResolver, String> {
+    match s {
+        "system" => return Ok(DnsResolver::System),
+        "cloudflare" => return Ok(DnsResolver::Cloudflare),
+        "google" => return Ok(DnsResolver::Google),
+        "quad9" => return Ok(DnsResolver::Quad9),
+        _ => {}
+    }
+
+    let rest = s
+        .strip_prefix("https://")
+        .ok_or_else(|| format!("expected system, cloudflare, google, quad9 or an https:// URL, got {s:?}"))?;
+    let (authority, path) = rest.split_once('/').unwrap_or((rest, ""));
+    // The resolver always queries this path.
+    if path != "dns-query" {
+        return Err(format!("the DNS over HTTPS path must be /dns-query, got /{path}"));
+    }
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) if !port.ends_with(']') => {
+            let port = port.parse().map_err(|e| format!("invalid port {port:?}: {e}"))?;
+            (host, port)
+        }
+        _ => (authority, 443),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return Err(format!("missing host in {s:?}"));
+    }
+
+    Ok(DnsResolver::Https {
+        host: host.to_string(),
+        port,
+    })
+}
+
+fn parse_advertise_webrtc(s: &str) -> Result<Multiaddr, String> {
+    if s.contains("/certhash/") {
+        return Err("the certificate hash is taken from the listener and must be left out".to_string());
+    }
+
+    parse_advertise_address(s, Protocol::WebRTCDirect)
+}
+
+fn parse_advertise_quic(s: &str) -> Result<Multiaddr, String> {
+    parse_advertise_address(s, Protocol::QuicV1)
+}
+
+/// Parses an address to advertise, which has to end in the `transport` protocol.
+fn parse_advertise_address(s: &str, transport: Protocol) -> Result<Multiaddr, String> {
+    let addr = s
+        .parse::<Multiaddr>()
+        .map_err(|e| format!("invalid multiaddr {s:?}: {e}"))?;
+    if addr.iter().last() != Some(transport.clone()) {
+        return Err(format!("expected an address ending in /{}, got {addr}", transport.tag()));
+    }
+
+    Ok(addr)
+}
+
+/// `address` with its IP replaced by the external IP of the same family, if we know one.
+fn external_address_for(address: &Multiaddr, external_ips: &[IpAddr]) -> Option<Multiaddr> {
+    let external_ip = match address.iter().next()? {
+        Protocol::Ip4(_) => external_ips.iter().find(|ip| ip.is_ipv4())?,
+        Protocol::Ip6(_) => external_ips.iter().find(|ip| ip.is_ipv6())?,
+        _ => return None,
+    };
+
+    address.replace(0, |_| Some((*external_ip).into()))
+}
+
+/// Resolves once we receive SIGINT (Ctrl-C) or SIGTERM.
+async fn shutdown_signal() -> Result<()> {
+    let mut sigterm = signal(SignalKind::terminate())?;
+
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result?,
+        _ = sigterm.recv() => {}
+    }
+
+    Ok(())
+}
+
+/// Stops listening and closes all connections, waiting at most `grace` for them to drain.
+async fn shutdown_swarm(swarm: &mut Swarm<Behaviour>, listeners: Vec<ListenerId>, grace: Duration) {
+    info!("Shutting down");
+
+    for listener in listeners {
+        swarm.remove_listener(listener);
+    }
+
+    let peers = swarm.connected_peers().copied().collect::<Vec<_>>();
+    for peer in peers {
+        let _ = swarm.disconnect_peer_id(peer);
+    }
+
+    let drain = async {
+        while swarm.network_info().num_peers() > 0 {
+            if let SwarmEvent::ConnectionClosed { peer_id, .. } = swarm.select_next_some().await {
+                debug!(%peer_id, "Closed connection");
+            }
+        }
+    };
+
+    if tokio::time::timeout(grace, drain).await.is_err() {
+        warn!(
+            "{} peers still connected after {grace:?}, exiting anyway",
+            swarm.network_info().num_peers()
+        );
+    }
+}
+
+#[derive(NetworkBehaviour)]
+struct Behaviour {
+    ping: ping::Behaviour,
+    autonat: Unadvertised<autonat::Behaviour>,
+    dcutr: Unadvertised<dcutr::Behaviour>,
+    gossipsub: Unadvertised<gossipsub::Behaviour>,
+    identify: FilteredIdentify<identify::Behaviour>,
+    relay: Unadvertised<relay::Behaviour>,
+    relay_client: relay::client::Behaviour,
+    kademlia: Unadvertised<kad::Behaviour<MemoryStore>>,
+    mdns: Toggle<mdns::tokio::Behaviour>,
+    upnp: Toggle<upnp::tokio::Behaviour>,
+    //relay: relay::Behaviour::new(key.public().to_peer_id(), Default::default()),
+    request_response: Toggle<Unadvertised<request_response::Behaviour<FileExchangeCodec>>>,
+    file_ack: Toggle<Unadvertised<request_response::Behaviour<FileAckCodec>>>,
+    keepalive: Unadvertised<request_response::Behaviour<KeepAliveCodec>>,
+    topic_query: Unadvertised<request_response::Behaviour<TopicQueryCodec>>,
+    connection_limits: connection_limits::Behaviour,
+    memory_limits: memory_connection_limits::Behaviour,
+    listen_only: Toggle<ListenOnly>,
+    ip_filter: IpFilter,
+    connection_spans: ConnectionSpans,
+    blocked_peers: allow_block_list::Behaviour<allow_block_list::BlockedPeers>,
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn create_swarm(
+    local_key: identity::Keypair,
+    certificate: Certificate,
+    wss_tls_config: Option<websocket::tls::Config>,
+    inherited_tcp: Vec<(Multiaddr, tokio::net::TcpListener)>,
+    bandwidth: &Bandwidth,
+    relay_bytes: &RelayBytes,
+    opt: &Opt,
+    registry: &mut Registry,
+) -> Result<Swarm<Behaviour>> {
+    let local_peer_id = PeerId::from(local_key.public());
+    debug!(%local_peer_id, "Local peer id");
+
+    if !(0.0 < opt.max_memory_percentage && opt.max_memory_percentage <= 1.0) {
+        bail!("--max-memory-percentage must be in (0, 1]");
+    }
+    let memory_limits = match (opt.max_memory_bytes, memory_pruning::cgroup_memory_limit()) {
+        (Some(max_bytes), _) => {
+            info!(max_bytes, "Refusing connections above the configured memory use");
+            memory_connection_limits::Behaviour::with_max_bytes(max_bytes.get())
+        }
+        (None, Some(limit)) => {
+            let max_bytes = (limit as f64 * opt.max_memory_percentage) as usize;
+            info!(limit, max_bytes, "Refusing connections above a share of the cgroup memory limit");
+            memory_connection_limits::Behaviour::with_max_bytes(max_bytes)
+        }
+        (None, None) => memory_connection_limits::Behaviour::with_max_percentage(opt.max_memory_percentage),
+    };
+
+    let (dns_config, dns_opts) = match &opt.dns_resolver {
+        DnsResolver::System => hickory_resolver::system_conf::read_system_conf()
+            .context("Failed to read the system DNS configuration")?,
+        DnsResolver::Cloudflare => (dns::ResolverConfig::cloudflare_https(), Default::default()),
+        DnsResolver::Google => (dns::ResolverConfig::google_https(), Default::default()),
+        DnsResolver::Quad9 => (dns::ResolverConfig::quad9_https(), Default::default()),
+        DnsResolver::Https { host, port } => {
+            let ips = match host.parse::<IpAddr>() {
+                Ok(ip) => vec![ip],
+                Err(_) => tokio::net::lookup_host((host.as_str(), *port))
+                    .await
+                    .with_context(|| format!("Failed to look up the DNS over HTTPS server {host}"))?
+                    .map(|addr| addr.ip())
+                    .collect(),
+            };
+            info!(%host, ?ips, "Resolving /dns addresses with DNS over HTTPS");
+            let name_servers = NameServerConfigGroup::from_ips_https(&ips, *port, host.clone(), true);
+            (dns::ResolverConfig::from_parts(None, Vec::new(), name_servers), Default::default())
+        }
+    };
+
+    let message_id_fn: fn(&gossipsub::Message) -> gossipsub::MessageId = match opt.message_id_strategy {
+        MessageIdStrategy::Content => content_message_id,
+        MessageIdStrategy::SenderSequence => |message| match (message.source, message.sequence_number) {
+            (Some(source), Some(sequence_number)) => {
+                let mut s = DefaultHasher::new();
+                source.hash(&mut s);
+                sequence_number.hash(&mut s);
+                gossipsub::MessageId::from(s.finish().to_string())
+            }
+            _ => content_message_id(message),
+        },
+        MessageIdStrategy::Random => {
+            warn!("Using random gossipsub message ids, duplicate messages won't be suppressed");
+            |_| gossipsub::MessageId::from(rand::random::<u64>().to_string())
+        }
+    };
+
+    if opt.gossipsub_history_gossip > opt.gossipsub_history_length {
+        bail!(
+            "--gossipsub-history-gossip ({}) must not exceed --gossipsub-history-length ({})",
+            opt.gossipsub_history_gossip,
+            opt.gossipsub_history_length
+        );
+    }
+
+    let mesh_params = mesh_params(opt);
+    info!(profile = ?opt.gossipsub_profile, ?mesh_params, "Gossipsub mesh sizes");
+
+    // Set a custom gossipsub configuration
+    let gossipsub_config = gossipsub::ConfigBuilder::default()
+        .validation_mode(gossipsub::ValidationMode::Permissive) // This sets the kind of message validation. The default is Strict (enforce message signing)
+        .message_id_fn(message_id_fn)
+        .mesh_n(mesh_params.mesh_n)
+        .mesh_n_low(mesh_params.mesh_n_low)
+        .mesh_n_high(mesh_params.mesh_n_high)
+        .mesh_outbound_min(mesh_params.mesh_outbound_min)
+        .flood_publish(true)
+        .heartbeat_interval(Duration::from_millis(opt.gossipsub_heartbeat_interval_ms.get()))
+        .history_length(opt.gossipsub_history_length)
+        .history_gossip(opt.gossipsub_history_gossip)
+        .duplicate_cache_time(Duration::from_secs(opt.gossipsub_dup_cache_secs.get()))
+        // Messages are checked against the per-topic limits before we accept and forward them.
+        .validate_messages()
+        .max_transmit_size(
+            MessageSizeLimits::new(opt.max_message_size, &opt.topic_max_message_size).max()
+                + RPC_OVERHEAD,
+        )
+        .build()
+        .map_err(|e| anyhow::anyhow!("Invalid gossipsub configuration: {e}"))?;
+
+    // build a gossipsub network behaviour
+    let mut gossipsub = gossipsub::Behaviour::new_with_metrics(
+        gossipsub::MessageAuthenticity::Signed(local_key.clone()),
+        gossipsub_config,
+        registry.sub_registry_with_prefix("gossipsub"),
+        gossipsub::MetricsConfig::default(),
+    )
+    .expect("Correct configuration");
+
+    if !opt.gossipsub_score_disabled {
+        let (params, thresholds) =
+            scoring::peer_score_params(&[&opt.gossipsub_peer_discovery, &opt.dcontact_topic]);
+        gossipsub
+            .with_peer_score(params, thresholds)
+            .map_err(|e| anyhow::anyhow!("Invalid gossipsub peer score parameters: {e}"))?;
+    }
+
+    // Create/subscribe Gossipsub topics
+    if !opt.relay_only {
+        gossipsub.subscribe(&gossipsub::IdentTopic::new(&opt.gossipsub_peer_discovery))?;
+        if opt.enable_compression {
+            compression::subscribe(&mut gossipsub, &opt.gossipsub_peer_discovery)?;
+        }
+    }
+    if opt.publish_status {
+        gossipsub.subscribe(&gossipsub::IdentTopic::new(&opt.status_topic))?;
+    }
+    if opt.topic_census {
+        gossipsub.subscribe(&gossipsub::IdentTopic::new(TOPIC_CENSUS_TOPIC))?;
+    }
+
+//     let transport = {
+//         let webrtc = webrtc::tokio::Transport::new(local_key.clone(), certificate);
+//         let quic = quic::tokio::Transport::new(quic::Config::new(&local_key));
+//
+//         let mapped = webrtc.or_transport(quic).map(|fut, _| match fut {
+//             Either::Right((local_peer_id, conn)) => (local_peer_id, StreamMuxerBox::new(conn)),
+//             Either::Left((local_peer_id, conn)) => (local_peer_id, StreamMuxerBox::new(conn)),
+//         });
+//
+//         dns::TokioDnsConfig::system(mapped)?.boxed()
+//     };
+
+    let identify_config = identify::Behaviour::new(
+        identify::Config::new(opt.identify_protocol_version.clone(), local_key.public())
+            .with_agent_version(opt.identify_agent_version.clone())
+            .with_interval(Duration::from_secs(60)) // do this so we can get timeouts for dropped WebRTC connections
+            .with_push_listen_addr_updates(true),
+    );
+
+    let hideable = [
+        dcutr::PROTOCOL_NAME,
+        autonat::DEFAULT_PROTOCOL_NAME,
+        kad::PROTOCOL_NAME,
+        relay::HOP_PROTOCOL_NAME,
+        FILE_EXCHANGE_PROTOCOL,
+        FILE_ACK_PROTOCOL,
+        KEEPALIVE_PROTOCOL,
+        TOPIC_QUERY_PROTOCOL,
+    ];
+    for protocol in &opt.identify_hide_protocols {
+        if !hideable.iter().any(|hideable| hideable.as_ref() == protocol) {
+            warn!(%protocol, ?hideable, "Can't hide --identify-hide-protocols protocol, it stays advertised");
+        }
+    }
+    let mut hidden = opt.identify_hide_protocols.iter().cloned().collect::<HashSet<_>>();
+    if opt.relay_only {
+        hidden.extend(GOSSIPSUB_PROTOCOLS.map(String::from));
+    }
+    let hidden: HiddenProtocols = Arc::new(hidden);
+
+    let mut kademlia = kad::Behaviour::new(local_peer_id, MemoryStore::new(local_peer_id));
+    kademlia.set_mode(Some(if opt.kademlia_server_mode {
+        kad::Mode::Server
+    } else {
+        kad::Mode::Client
+    }));
+
+    let mdns = if opt.disable_mdns {
+        None
+    } else {
+        Some(mdns::tokio::Behaviour::new(mdns::Config::default(), local_peer_id)?)
+    };
+
+    let relay_config = relay_config(opt);
+
+    if opt.disable_tcp && opt.disable_quic && opt.disable_webrtc && opt.disable_websocket {
+        bail!("All transports are disabled, enable at least one of TCP, QUIC, WebRTC or WebSocket");
+    }
+    if opt.quic_keep_alive_seconds >= u64::from(opt.quic_max_idle_timeout_seconds) {
+        warn!("--quic-keep-alive-seconds should be below --quic-max-idle-timeout-seconds, idle QUIC connections will time out");
+    }
+
+    let negotiation_timeout = Duration::from_secs(opt.max_negotiation_timeout_seconds.get());
+    let swarm = libp2p::SwarmBuilder::with_existing_identity(local_key)
+        .with_tokio()
+        // Every transport goes through `with_other_transport`, so disabled ones can be left out.
+        .with_other_transport(|id_keys| {
+            let bandwidth = bandwidth.clone();
+            let relay_bytes = relay_bytes.clone();
+            if opt.disable_tcp {
+                return Ok(OptionalTransport::none());
+            }
+
+            Ok(OptionalTransport::some(
+                SocketActivated::new(tcp::tokio::Transport::new(tcp::Config::default()), inherited_tcp)
+                    .upgrade(upgrade::Version::V1Lazy)
+                    .authenticate(noise::Config::new(id_keys)?)
+                    .multiplex(yamux::Config::default())
+                    .timeout(negotiation_timeout)
+                    .map(move |(peer_id, conn), _| {
+                        let muxer = relay_bytes.wrap(peer_id, StreamMuxerBox::new(conn));
+                        (peer_id, bandwidth.wrap(muxer))
+                    }),
+            ))
+        })?
+        .with_other_transport(|id_keys| {
+            let bandwidth = bandwidth.clone();
+            let relay_bytes = relay_bytes.clone();
+            if opt.disable_quic {
+                return OptionalTransport::none();
+            }
+
+            let mut config = quic::Config::new(id_keys);
+            // libp2p-quic always disables QUIC connection migration, since it can't yet follow a
+            // connection to a new address. Roaming peers reconnect instead, which a short idle
+            // timeout lets us notice quickly.
+            config.max_idle_timeout = opt.quic_max_idle_timeout_seconds.saturating_mul(1000);
+            config.keep_alive_interval = Duration::from_secs(opt.quic_keep_alive_seconds);
+
+            OptionalTransport::some(
+                quic::tokio::Transport::new(config)
+                    .map(move |(peer_id, conn), _| {
+                        let muxer = relay_bytes.wrap(peer_id, StreamMuxerBox::new(conn));
+                        (peer_id, bandwidth.wrap(muxer))
+                    }),
+            )
+        })?
+        // There is no WebTransport listener yet: rust-libp2p only provides the browser side of
+        // WebTransport (libp2p-webtransport-websys), so browsers reach us via WebRTC instead.
+        .with_other_transport(|id_keys| {
+            let bandwidth = bandwidth.clone();
+            let relay_bytes = relay_bytes.clone();
+            if opt.disable_webrtc {
+                return OptionalTransport::none();
+            }
+
+            OptionalTransport::some(
+                webrtc::tokio::Transport::new(id_keys.clone(), certificate)
+                    .map(move |(peer_id, conn), _| {
+                        let muxer = relay_bytes.wrap(peer_id, StreamMuxerBox::new(conn));
+                        (peer_id, bandwidth.wrap(muxer))
+                    }),
+            )
+        })?
+        .with_other_transport(|id_keys| {
+            let bandwidth = bandwidth.clone();
+            let relay_bytes = relay_bytes.clone();
+            if opt.disable_websocket {
+                return Ok(OptionalTransport::none());
+            }
+
+            // Built by hand instead of via `with_websocket` so we can configure TLS for `/wss`.
+            let mut ws = websocket::WsConfig::new(dns::tokio::Transport::custom(
+                tcp::tokio::Transport::new(tcp::Config::default()),
+                dns_config.clone(),
+                dns_opts.clone(),
+            ));
+            if let Some(tls_config) = wss_tls_config {
+                ws.set_tls_config(tls_config);
+            }
+
+            Ok(OptionalTransport::some(
+                ws.upgrade(upgrade::Version::V1Lazy)
+                    .authenticate(noise::Config::new(id_keys)?)
+                    .multiplex(yamux::Config::default())
+                    .timeout(negotiation_timeout)
+                    .map(move |(peer_id, conn), _| {
+                        let muxer = relay_bytes.wrap(peer_id, StreamMuxerBox::new(conn));
+                        (peer_id, bandwidth.wrap(muxer))
+                    }),
+            ))
+        })?
+        .with_dns_config(dns_config.clone(), dns_opts.clone())
+        .with_relay_client(noise::Config::new, yamux::Config::default)?
+        // Bytes in and out per transport, labeled by protocol stack.
+        .with_bandwidth_metrics(registry)
+        .with_behaviour(|_, relay_client| Behaviour {
+            ping: ping::Behaviour::new(ping::Config::new()),
+            autonat: Unadvertised::new(
+                autonat::Behaviour::new(local_peer_id, autonat::Config::default()),
+                &hidden,
+            ),
+            dcutr: Unadvertised::new(dcutr::Behaviour::new(local_peer_id), &hidden),
+            gossipsub: Unadvertised::new(gossipsub, &hidden),
+            identify: FilteredIdentify::new(identify_config, &hidden),
+            relay_client,
+            relay: Unadvertised::new(
+                relay::Behaviour::new(
+                    local_peer_id,
+                    relay_config,
+                ),
+                &hidden,
+            ),
+            kademlia: Unadvertised::new(kademlia, &hidden),
+            mdns: mdns.into(),
+            upnp: opt.enable_upnp.then(upnp::tokio::Behaviour::default).into(),
+            request_response: (!opt.relay_only)
+                .then(|| {
+                    Unadvertised::new(
+                        request_response::Behaviour::with_codec(
+                            FileExchangeCodec::new(opt.file_chunk_size),
+                            [(FILE_EXCHANGE_PROTOCOL, ProtocolSupport::Full)],
+                            request_response::Config::default()
+                                .with_request_timeout(Duration::from_secs(opt.file_request_timeout_seconds.get())),
+                        ),
+                        &hidden,
+                    )
+                })
+                .into(),
+            file_ack: (!opt.relay_only)
+                .then(|| {
+                    Unadvertised::new(
+                        request_response::Behaviour::new(
+                            [(FILE_ACK_PROTOCOL, ProtocolSupport::Inbound)],
+                            request_response::Config::default(),
+                        ),
+                        &hidden,
+                    )
+                })
+                .into(),
+            keepalive: Unadvertised::new(
+                request_response::Behaviour::new(
+                    [(KEEPALIVE_PROTOCOL, ProtocolSupport::Full)],
+                    request_response::Config::default()
+                        .with_request_timeout(Duration::from_secs(opt.keepalive_interval_seconds.get())),
+                ),
+                &hidden,
+            ),
+            topic_query: Unadvertised::new(
+                request_response::Behaviour::new(
+                    [(TOPIC_QUERY_PROTOCOL, ProtocolSupport::Inbound)],
+                    request_response::Config::default(),
+                ),
+                &hidden,
+            ),
+            connection_limits: connection_limits::Behaviour::new(
+                ConnectionLimits::default()
+                    .with_max_established_incoming(Some(opt.max_established_incoming))
+                    .with_max_established_outgoing(Some(opt.max_established_outgoing))
+                    .with_max_pending_incoming(Some(opt.max_pending))
+                    .with_max_pending_outgoing(Some(opt.max_pending)),
+            ),
+            memory_limits,
+            listen_only: opt.listen_only.then_some(ListenOnly).into(),
+            ip_filter: IpFilter::new(opt.allow_cidr.clone(), opt.deny_cidr.clone()),
+            connection_spans: ConnectionSpans::default(),
+            blocked_peers: allow_block_list::Behaviour::default(),
+        })?
+        .with_swarm_config(|config| {
+            config
+                .with_idle_connection_timeout(Duration::from_secs(opt.idle_connection_timeout_seconds))
+                .with_per_connection_event_buffer_size(opt.per_connection_event_buffer_size)
+        })
+        .build();
+
+    Ok(swarm)
+}
+
+/// To content-address messages, we take the hash of the payload and use it as the id. That's the
+/// payload as sent, so compressed messages are addressed by their compressed bytes.
+fn content_message_id(message: &gossipsub::Message) -> gossipsub::MessageId {
+    let mut s = DefaultHasher::new();
+    message.data.hash(&mut s);
+    gossipsub::MessageId::from(s.finish().to_string())
+}
+
+/// The relay limits from the command line. Per-peer limits above the global ones are allowed, but
+/// have no effect beyond the global limit.
+///
+/// Reservations and circuits are rate limited per peer and per IP address, so a noisy client is
+/// throttled without affecting anyone else.
+fn relay_config(opt: &Opt) -> relay::Config {
+    if opt.max_reservations_per_peer > opt.max_reservations {
+        warn!(
+            "--max-reservations-per-peer ({}) exceeds --max-reservations ({})",
+            opt.max_reservations_per_peer, opt.max_reservations
+        );
+    }
+    if opt.max_circuits_per_peer > opt.max_circuits {
+        warn!(
+            "--max-circuits-per-peer ({}) exceeds --max-circuits ({})",
+            opt.max_circuits_per_peer, opt.max_circuits
+        );
+    }
+
+    relay::Config {
+        max_reservations: opt.max_reservations,
+        max_reservations_per_peer: opt.max_reservations_per_peer,
+        reservation_rate_limiters: Vec::default(),
+        circuit_src_rate_limiters: Vec::default(),
+        max_circuits: opt.max_circuits,
+        max_circuits_per_peer: opt.max_circuits_per_peer,
+        max_circuit_bytes: opt.max_circuit_bytes,
+        ..Default::default()
+    }
+    .reservation_rate_per_peer(opt.relay_reservation_rate, RATE_LIMIT_INTERVAL)
+    .reservation_rate_per_ip(per_ip_rate(opt.relay_reservation_rate), RATE_LIMIT_INTERVAL)
+    .circuit_src_per_peer(opt.relay_circuit_rate, RATE_LIMIT_INTERVAL)
+    .circuit_src_per_ip(per_ip_rate(opt.relay_circuit_rate), RATE_LIMIT_INTERVAL)
+}
+
+fn mesh_params(opt: &Opt) -> MeshParams {
+    match opt.gossipsub_profile {
+        GossipsubProfile::Browser => MeshParams {
+            mesh_n: 6,
+            mesh_n_low: MESH_N_LOW,
+            mesh_n_high: 12,
+            mesh_outbound_min: 1,
+        },
+        GossipsubProfile::Server => MeshParams {
+            mesh_n: 6,
+            mesh_n_low: 4,
+            mesh_n_high: 12,
+            mesh_outbound_min: 2,
+        },
+        GossipsubProfile::Custom => MeshParams {
+            mesh_n: opt.gossipsub_mesh_n,
+            mesh_n_low: opt.gossipsub_mesh_n_low,
+            mesh_n_high: opt.gossipsub_mesh_n_high,
+            mesh_outbound_min: opt.gossipsub_mesh_outbound_min,
+        },
+    }
+}
+
+fn per_ip_rate(per_peer: NonZeroU32) -> NonZeroU32 {
+    NonZeroU32::new(per_peer.get().saturating_mul(2)).unwrap_or(per_peer)
+}
+
+/// Subscribes to a topic we received a message on, if it matches `--topic-prefix` and we are
+/// below `--max-topics`.
+///
+/// With `--enable-compression` we also subscribe to its compressed topic, and a compressed topic
+/// stands for its plain one.
+fn auto_subscribe(swarm: &mut Swarm<Behaviour>, topic: &gossipsub::TopicHash, opt: &Opt) {
+    let topic = &match compression::plain_topic(topic) {
+        Some(_) if !opt.enable_compression => {
+            debug!(%topic, "Not subscribing to compressed topic, --enable-compression isn't set");
+            return;
+        }
+        Some(plain_topic) => plain_topic,
+        None => topic.clone(),
+    };
+    let gossipsub = &mut swarm.behaviour_mut().gossipsub;
+    if gossipsub.topics().any(|t| t == topic) {
+        return;
+    }
+
+    let allowed = opt.topic_prefix.is_empty()
+        || opt.topic_prefix.iter().any(|prefix| topic.as_str().starts_with(prefix));
+    if !allowed {
+        debug!(%topic, "Not subscribing to topic, it doesn't match --topic-prefix");
+        return;
+    }
+    if gossipsub.topics().filter(|t| compression::plain_topic(t).is_none()).count() >= opt.max_topics {
+        warn!(%topic, "Not subscribing to topic, already subscribed to --max-topics topics");
+        return;
+    }
+
+    match gossipsub.subscribe(&gossipsub::IdentTopic::new(topic.as_str())) {
+        Ok(_) => info!(event = "gossipsub_subscribed", %topic, "Subscribed to message topic"),
+        Err(err) => error!(%err, "Failed to subscribe to topic"),
+    }
+    if opt.enable_compression {
+        if let Err(err) = compression::subscribe(gossipsub, topic.as_str()) {
+            error!(%err, "Failed to subscribe to compressed topic");
+        }
+    }
+}
+
+/// Whether a connection was denied because the peer is on the blocklist.
+fn is_blocked(cause: &ConnectionDenied) -> bool {
+    cause.downcast_ref::<allow_block_list::Blocked>().is_some()
+}
+
+/// The limit that made the connection or memory limits deny a connection, if that's why it was.
+fn exceeded_limit(cause: &ConnectionDenied) -> Option<String> {
+    if let Some(exceeded) = cause.downcast_ref::<connection_limits::Exceeded>() {
+        return Some(exceeded.to_string());
+    }
+
+    cause
+        .downcast_ref::<memory_connection_limits::MemoryUsageLimitExceeded>()
+        .map(ToString::to_string)
+}
+
+/// Dials up to `count` peers from the peerstore.
+fn dial_stored_peers(swarm: &mut Swarm<Behaviour>, dial_queue: &mut DialQueue, peerstore: &Peerstore, count: usize) {
+    let local_peer_id = *swarm.local_peer_id();
+    let peers = peerstore
+        .peers()
+        .filter(|(peer_id, _)| **peer_id != local_peer_id)
+        .take(count);
+
+    for (peer_id, addrs) in peers {
+        let opts = DialOpts::peer_id(*peer_id)
+            .addresses(addrs.clone())
+            .condition(PeerCondition::DisconnectedAndNotDialing)
+            .build();
+
+        if let Err(e) = dial_queue.dial(swarm, opts) {
+            debug!(%peer_id, %e, "Failed to dial stored peer");
+        }
+    }
+}
+
+/// Dials peers from the discovery cache if any of our topics has fewer than `mesh_n_low` mesh
+/// peers, instead of waiting for gossip to bring new ones.
+fn repair_thin_meshes(
+    swarm: &mut Swarm<Behaviour>,
+    dial_queue: &mut DialQueue,
+    mesh_n_low: usize,
+    discovery_cache: &DiscoveryCache,
+    mesh_repair: &mut MeshRepair,
+    metrics: &Metrics,
+) {
+    let gossipsub = &swarm.behaviour().gossipsub;
+    let mesh_peers = gossipsub
+        .topics()
+        .map(|topic| (topic.clone(), gossipsub.mesh_peers(topic).count()))
+        .collect::<Vec<_>>();
+    metrics.set_mesh_peers(&mesh_peers);
+
+    let thin = mesh_peers
+        .iter()
+        .filter(|(_, count)| *count < mesh_n_low)
+        .map(|(topic, _)| topic.as_str())
+        .collect::<Vec<_>>();
+    if thin.is_empty() {
+        return;
+    }
+
+    let local_peer_id = *swarm.local_peer_id();
+    let candidates = discovery_cache
+        .peers()
+        .filter(|(peer_id, _)| **peer_id != local_peer_id && !swarm.is_connected(peer_id))
+        .filter(|(peer_id, _)| mesh_repair.try_dial(**peer_id))
+        .take(MAX_MESH_REPAIR_DIALS_PER_TICK)
+        .map(|(peer_id, addrs)| (*peer_id, addrs.clone()))
+        .collect::<Vec<_>>();
+    if candidates.is_empty() {
+        return;
+    }
+
+    info!(topics = ?thin, "Mesh is thin, dialing {} cached peers", candidates.len());
+    for (peer_id, addrs) in candidates {
+        let opts = DialOpts::peer_id(peer_id)
+            .addresses(addrs)
+            .condition(PeerCondition::DisconnectedAndNotDialing)
+            .build();
+
+        if let Err(e) = dial_queue.dial(swarm, opts) {
+            debug!(%peer_id, %e, "Failed to dial cached peer");
+        }
+    }
+}
+
+/// Dials the peer advertised in a discovery message, unless it's us or we are already connected.
+fn dial_discovered_peer(swarm: &mut Swarm<Behaviour>, dial_queue: &mut DialQueue, data: &[u8], allow_unsigned: bool) {
+    let (peer_id, addrs) = match discovery::decode_peer(data, allow_unsigned) {
+        Ok(peer) => peer,
+        Err(reason) => {
+            debug!(reason, "Ignoring discovery message");
+            return;
+        }
+    };
+    if peer_id == *swarm.local_peer_id() || swarm.is_connected(&peer_id) {
+        return;
+    }
+
+    let addrs = addrs
+        .into_iter()
+        .take(MAX_DISCOVERY_DIALS_PER_MESSAGE)
+        .collect::<Vec<_>>();
+    if addrs.is_empty() {
+        return;
+    }
+
+    debug!(%peer_id, ?addrs, "Dialing discovered peer");
+    let opts = DialOpts::peer_id(peer_id)
+        .addresses(addrs)
+        .condition(PeerCondition::DisconnectedAndNotDialing)
+        .build();
+    if let Err(e) = dial_queue.dial(swarm, opts) {
+        debug!(%peer_id, %e, "Failed to dial discovered peer");
+    }
+}
+
+/// Only looks the file up, the codec reads it while writing the response.
+async fn serve_file(file_dir: Option<&Path>, request: &FileRequest) -> FileResponse {
+    let Some(file_dir) = file_dir else {
+        return FileResponse::Error("file sharing is disabled".to_string());
+    };
+    let Some(path) = file_exchange::resolve_file_path(file_dir, &request.file_id) else {
+        return FileResponse::Error(format!("invalid file id {:?}", request.file_id));
+    };
+
+    match fs::metadata(&path).await {
+        Ok(metadata) if metadata.is_file() && metadata.len() > file_exchange::MAX_RESPONSE_SIZE => {
+            info!(path = %path.display(), bytes = metadata.len(), "Not serving file, it exceeds the response limit");
+            FileResponse::Error(format!(
+                "file {:?} exceeds {} bytes",
+                request.file_id,
+                file_exchange::MAX_RESPONSE_SIZE
+            ))
+        }
+        Ok(metadata) if metadata.is_file() => {
+            info!(path = %path.display(), bytes = metadata.len(), "Serving file");
+            FileResponse::File {
+                path,
+                size: metadata.len(),
+            }
+        }
+        Ok(_) => FileResponse::Error(format!("file {:?} not found", request.file_id)),
+        Err(e) => {
+            debug!(path = %path.display(), %e, "Failed to read file");
+            FileResponse::Error(format!("file {:?} not found", request.file_id))
+        }
+    }
+}
+
+/// Loads the TLS configuration for secure WebSockets, returning `None` (and logging why) if the files
+/// can't be used so we can fall back to plain WebSockets.
+async fn read_tls_config(cert_path: &Path, key_path: &Path) -> Option<websocket::tls::Config> {
+    let load = async {
+        let cert_pem = fs::read(cert_path).await?;
+        let key_pem = fs::read(key_path).await?;
+
+        let certs = rustls_pemfile::certs(&mut cert_pem.as_slice())?
+            .into_iter()
+            .map(websocket::tls::Certificate::new)
+            .collect::<Vec<_>>();
+        if certs.is_empty() {
+            anyhow::bail!("no certificate found in {}", cert_path.display());
+        }
+
+        let key = rustls_pemfile::read_all(&mut key_pem.as_slice())?
+            .into_iter()
+            .find_map(|item| match item {
+                rustls_pemfile::Item::PKCS8Key(key)
+                | rustls_pemfile::Item::RSAKey(key)
+                | rustls_pemfile::Item::ECKey(key) => Some(key),
+                _ => None,
+            })
+            .with_context(|| format!("no private key found in {}", key_path.display()))?;
+
+        Ok(websocket::tls::Config::new(
+            websocket::tls::PrivateKey::new(key),
+            certs,
+        )?)
+    };
+
+    match load.await {
+        Ok(config) => {
+            info!(path = %cert_path.display(), "Using TLS certificate for secure WebSockets");
+            Some(config)
+        }
+        Err(e) => {
+            warn!(error = format!("{e:#}"), "Failed to load TLS certificate for secure WebSockets, falling back to plain /ws");
+            None
+        }
+    }
+}
+
+async fn read_or_create_identity(
+    path: &Path,
+    identity_type: IdentityType,
+) -> Result<identity::Keypair> {
+    if let Ok(encoded) = std::env::var(LOCAL_KEY_ENV) {
+        let bytes = base64::engine::general_purpose::STANDARD
+            .decode(encoded.trim())
+            .with_context(|| format!("Invalid base64 in ${LOCAL_KEY_ENV}"))?;
+        let identity = identity::Keypair::from_protobuf_encoding(&bytes)
+            .with_context(|| format!("Invalid identity in ${LOCAL_KEY_ENV}"))?;
+
+        info!(key_type = ?identity.key_type(), "Using identity from ${LOCAL_KEY_ENV}");
+
+        return Ok(identity);
+    }
+
+    if path.exists() {
+        let bytes = fs::read(&path).await?;
+
+        // The protobuf encoding carries the key type, so this works for any of them.
+        let identity = identity::Keypair::from_protobuf_encoding(&bytes)?;
+
+        info!(
+            "Using existing {} identity from {}",
+            identity.key_type(),
+            path.display()
+        );
+
+        return Ok(identity);
+    }
+
+    let identity = match identity_type {
+        IdentityType::Ed25519 => identity::Keypair::generate_ed25519(),
+        IdentityType::Secp256k1 => identity::Keypair::generate_secp256k1(),
+        IdentityType::Ecdsa => identity::Keypair::generate_ecdsa(),
+    };
+
+    fs::write(&path, &identity.to_protobuf_encoding()?).await?;
+
+    info!(
+        "Generated new {} identity and wrote it to {}",
+        identity.key_type(),
+        path.display()
+    );
+
+    Ok(identity)
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn opt(args: &[&str]) -> Opt {
+        Opt::parse_from(["rust-libp2p-webrtc-peer"].iter().chain(args))
+    }
+
+    #[test]
+    fn relay_rejects_rapid_reservations_from_one_peer() {
+        let mut config = relay_config(&opt(&["--relay-reservation-rate", "3"]));
+        let peer = PeerId::random();
+        let addr: Multiaddr = "/ip4/198.51.100.1/tcp/4001".parse().unwrap();
+        let now = Instant::now();
+        let mut try_reserve = |peer, addr: &Multiaddr| {
+            config
+                .reservation_rate_limiters
+                .iter_mut()
+                .all(|limiter| limiter.try_next(peer, addr, now))
+        };
+
+        for _ in 0..3 {
+            assert!(try_reserve(peer, &addr));
+        }
+        assert!(!try_reserve(peer, &addr));
+
+        let other_addr: Multiaddr = "/ip4/198.51.100.2/tcp/4001".parse().unwrap();
+        assert!(try_reserve(PeerId::random(), &other_addr));
+    }
+
+    #[test]
+    fn listens_on_every_transport_for_both_address_families() {
+        let opt = opt(&["--listen-address", "0.0.0.0", "--listen-address", "::"]);
+        let addresses = opt
+            .listen_address
+            .iter()
+            .flat_map(|ip| listen_addresses(*ip, &opt, false))
+            .map(|address| address.to_string())
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            addresses,
+            [
+                format!("/ip4/0.0.0.0/tcp/{PORT_TCP}"),
+                format!("/ip4/0.0.0.0/udp/{PORT_WEBRTC}/webrtc-direct"),
+                format!("/ip4/0.0.0.0/udp/{PORT_QUIC}/quic-v1"),
+                format!("/ip4/0.0.0.0/tcp/{PORT_WS}/ws"),
+                format!("/ip6/::/tcp/{PORT_TCP}"),
+                format!("/ip6/::/udp/{PORT_WEBRTC}/webrtc-direct"),
+                format!("/ip6/::/udp/{PORT_QUIC}/quic-v1"),
+                format!("/ip6/::/tcp/{PORT_WS}/ws"),
+            ]
+        );
+    }
+
+    #[test]
+    fn does_not_listen_on_disabled_transports() {
+        let ip = IpAddr::from([0, 0, 0, 0]);
+
+        let addresses = listen_addresses(ip, &opt(&["--disable-quic", "--disable-websocket"]), false);
+        assert_eq!(
+            addresses.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            [
+                format!("/ip4/0.0.0.0/tcp/{PORT_TCP}"),
+                format!("/ip4/0.0.0.0/udp/{PORT_WEBRTC}/webrtc-direct"),
+            ]
+        );
+
+        let addresses = listen_addresses(ip, &opt(&["--disable-tcp", "--disable-webrtc"]), false);
+        assert_eq!(
+            addresses.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            [
+                format!("/ip4/0.0.0.0/udp/{PORT_QUIC}/quic-v1"),
+                format!("/ip4/0.0.0.0/tcp/{PORT_WS}/ws"),
+            ]
+        );
+    }
+
+    #[test]
+    fn does_not_advertise_external_ips_that_are_not_global() {
+        let ips: Vec<IpAddr> = [
+            "10.0.0.1",
+            "172.16.5.4",
+            "192.168.1.1",
+            "127.0.0.1",
+            "100.64.0.1",
+            "100.127.255.254",
+            "169.254.1.1",
+            "0.0.0.0",
+            "::1",
+            "::",
+            "fe80::1",
+            "fc00::1",
+            "fd12:3456::1",
+        ]
+        .iter()
+        .map(|ip| ip.parse().unwrap())
+        .collect();
+        assert!(global_external_ips(&ips).is_empty());
+
+        let global: Vec<IpAddr> = ["203.0.113.7", "100.128.0.1", "2001:db8::1"]
+            .iter()
+            .map(|ip| ip.parse().unwrap())
+            .collect();
+        assert_eq!(global_external_ips(&global), global);
+    }
+
+    #[tokio::test]
+    async fn pushed_listen_addresses_reach_the_peerstore() {
+        let opt = opt(&["--disable-quic", "--disable-webrtc", "--disable-websocket", "--disable-mdns"]);
+        let mut a = test_swarm(&opt).await;
+        let mut b = test_swarm(&opt).await;
+        let a_peer_id = *a.local_peer_id();
+        let path = std::env::temp_dir().join(format!("peerstore-{a_peer_id}.json"));
+        let mut peerstore = Peerstore::load(&path, None).await;
+
+        a.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+        let first = loop {
+            if let SwarmEvent::NewListenAddr { address, .. } = a.select_next_some().await {
+                break address;
+            }
+        };
+        b.dial(first).unwrap();
+
+        // Once B identified A, A listens on another address, which identify pushes to B right away
+        // rather than with the next periodic identify a minute later.
+        let mut second = None;
+        let mut listening_again = false;
+        let pushed = async {
+            loop {
+                tokio::select! {
+                    event = a.select_next_some() => {
+                        if let SwarmEvent::NewListenAddr { address, .. } = event {
+                            second = Some(address);
+                        }
+                    }
+                    event = b.select_next_some() => {
+                        let SwarmEvent::Behaviour(BehaviourEvent::Identify(identify::Event::Received { peer_id, info })) = event else {
+                            continue;
+                        };
+                        assert_eq!(peer_id, a_peer_id);
+                        peerstore.insert(peer_id, info.listen_addrs);
+
+                        if !listening_again {
+                            listening_again = true;
+                            a.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+                        }
+                        let stored = peerstore.peers().find(|(peer_id, _)| **peer_id == a_peer_id).map(|(_, addrs)| addrs);
+                        if second.as_ref().is_some_and(|second| stored.is_some_and(|addrs| addrs.contains(second))) {
+                            break;
+                        }
+                    }
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(30), pushed)
+            .await
+            .expect("new listen address is pushed before the next periodic identify");
+    }
+
+    #[tokio::test]
+    async fn denies_reservations_beyond_max_reservations() {
+        let opt = opt(&["--disable-quic", "--disable-webrtc", "--disable-websocket", "--disable-mdns", "--max-reservations", "2"]);
+        let mut relay = test_swarm(&opt).await;
+        let relay_peer_id = *relay.local_peer_id();
+        relay.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+        let relay_addr = loop {
+            if let SwarmEvent::NewListenAddr { address, .. } = relay.select_next_some().await {
+                break address;
+            }
+        };
+
+        let circuit_addr = relay_addr.with(Protocol::P2p(relay_peer_id)).with(Protocol::P2pCircuit);
+        for _ in 0..3 {
+            let mut client = test_swarm(&opt).await;
+            client.listen_on(circuit_addr.clone()).unwrap();
+            tokio::spawn(async move {
+                loop {
+                    client.select_next_some().await;
+                }
+            });
+        }
+
+        let mut relay_stats = RelayStats::new(opt.max_reservations);
+        let (mut accepted, mut denied) = (0, 0);
+        let reservations = async {
+            while accepted + denied < 3 {
+                let SwarmEvent::Behaviour(BehaviourEvent::Relay(e)) = relay.select_next_some().await else {
+                    continue;
+                };
+                relay_stats.on_event(&e);
+                match e {
+                    relay::Event::ReservationReqAccepted { .. } => accepted += 1,
+                    relay::Event::ReservationReqDenied { .. } => denied += 1,
+                    _ => {}
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(30), reservations)
+            .await
+            .expect("every client requests a reservation");
+
+        assert_eq!((accepted, denied), (2, 1));
+        assert_eq!(relay_stats.reservations(), 2);
+    }
+
+    #[tokio::test]
+    async fn suppresses_duplicates_only_within_the_duplicate_cache_time() {
+        let opt = opt(&[
+            "--disable-quic",
+            "--disable-webrtc",
+            "--disable-websocket",
+            "--disable-mdns",
+            "--gossipsub-dup-cache-secs",
+            "1",
+        ]);
+        let mut a = test_swarm(&opt).await;
+        let mut b = test_swarm(&opt).await;
+        let topic = gossipsub::IdentTopic::new("test");
+        a.behaviour_mut().gossipsub.subscribe(&topic).unwrap();
+        b.behaviour_mut().gossipsub.subscribe(&topic).unwrap();
+
+        a.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+        let addr = until(&mut a, &mut b, |event| match event {
+            SwarmEvent::NewListenAddr { address, .. } => Some(address),
+            _ => None,
+        })
+        .await;
+        b.dial(addr).unwrap();
+        let b_peer_id = *b.local_peer_id();
+        until(&mut a, &mut b, |event| match event {
+            SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(gossipsub::Event::Subscribed { peer_id, topic: subscribed }))
+                if peer_id == b_peer_id && subscribed == topic.hash() =>
+            {
+                Some(())
+            }
+            _ => None,
+        })
+        .await;
+
+        let received = |event| match event {
+            SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(gossipsub::Event::Message { message, .. })) => {
+                Some(message.data)
+            }
+            _ => None,
+        };
+        a.behaviour_mut().gossipsub.publish(topic.clone(), b"hi".to_vec()).unwrap();
+        assert_eq!(until(&mut b, &mut a, received).await, b"hi");
+
+        let duplicate = a.behaviour_mut().gossipsub.publish(topic.clone(), b"hi".to_vec());
+        assert!(matches!(duplicate, Err(gossipsub::PublishError::Duplicate)), "{duplicate:?}");
+
+        let _ = tokio::time::timeout(Duration::from_millis(1500), until(&mut a, &mut b, |_| None::<()>)).await;
+        // Gossipsub only drops expired ids when it inserts new ones, which any other message does.
+        a.behaviour_mut().gossipsub.publish(topic.clone(), b"hello".to_vec()).unwrap();
+        assert_eq!(until(&mut b, &mut a, received).await, b"hello");
+        a.behaviour_mut().gossipsub.publish(topic.clone(), b"hi".to_vec()).unwrap();
+        assert_eq!(until(&mut b, &mut a, received).await, b"hi");
+    }
+
+    #[tokio::test]
+    async fn bridges_compressed_messages_to_subscribers_only_on_the_plain_topic() {
+        let opt = opt(&["--disable-quic", "--disable-webrtc", "--disable-websocket", "--disable-mdns"]);
+        // a - b - c, where a and b support compression and c doesn't.
+        let mut a = test_swarm(&opt).await;
+        let mut b = test_swarm(&opt).await;
+        let mut c = test_swarm(&opt).await;
+        let topic = gossipsub::IdentTopic::new("chat");
+        let compressed_topic = compression::compressed_topic("chat");
+        for swarm in [&mut a, &mut b, &mut c] {
+            swarm.behaviour_mut().gossipsub.subscribe(&topic).unwrap();
+        }
+        for swarm in [&mut a, &mut b] {
+            compression::subscribe(&mut swarm.behaviour_mut().gossipsub, "chat").unwrap();
+        }
+
+        b.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+        let addr = until(&mut b, &mut a, |event| match event {
+            SwarmEvent::NewListenAddr { address, .. } => Some(address),
+            _ => None,
+        })
+        .await;
+        a.dial(addr.clone()).unwrap();
+        c.dial(addr).unwrap();
+        let (b_peer_id, c_peer_id) = (*b.local_peer_id(), *c.local_peer_id());
+        let subscribed = |swarm: &Swarm<Behaviour>, peer_id, topic: &gossipsub::TopicHash| {
+            swarm
+                .behaviour()
+                .gossipsub
+                .all_peers()
+                .any(|(peer, topics)| *peer == peer_id && topics.contains(&topic))
+        };
+        let connected = async {
+            while !subscribed(&a, b_peer_id, &compressed_topic.hash()) || !subscribed(&b, c_peer_id, &topic.hash()) {
+                tokio::select! {
+                    _ = a.select_next_some() => {}
+                    _ = b.select_next_some() => {}
+                    _ = c.select_next_some() => {}
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(30), connected).await.expect("subscriptions within 30 seconds");
+
+        let data = b"hello ".repeat(20);
+        compression::publish(&mut a.behaviour_mut().gossipsub, topic.clone(), data.clone()).unwrap();
+        let bridged = async {
+            loop {
+                tokio::select! {
+                    _ = a.select_next_some() => {}
+                    event = b.select_next_some() => {
+                        if let SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(gossipsub::Event::Message { message, .. })) = event {
+                            assert_eq!(message.topic, compressed_topic.hash());
+                            let data = compression::decompress(&message.data, usize::MAX).unwrap();
+                            compression::bridge(&mut b.behaviour_mut().gossipsub, &topic.hash(), data)
+                                .expect("c is only on the plain topic")
+                                .unwrap();
+                        }
+                    }
+                    event = c.select_next_some() => {
+                        if let SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(gossipsub::Event::Message { message, .. })) = event {
+                            break message;
+                        }
+                    }
+                }
+            }
+        };
+        let message = tokio::time::timeout(Duration::from_secs(30), bridged).await.expect("bridged within 30 seconds");
+
+        assert_eq!(message.topic, topic.hash());
+        assert_eq!(message.data, data);
+        // The only peer of a, b, is on both topics.
+        assert!(compression::bridge(&mut a.behaviour_mut().gossipsub, &topic.hash(), data).is_none());
+    }
+
+    #[tokio::test]
+    async fn identify_leaves_out_hidden_protocols_that_still_negotiate() {
+        let args = ["--disable-quic", "--disable-webrtc", "--disable-websocket", "--disable-mdns"];
+        let hiding = opt(&[&args[..], &["--identify-hide-protocols", KEEPALIVE_PROTOCOL.as_ref()]].concat());
+        let mut a = test_swarm(&hiding).await;
+        let mut b = test_swarm(&opt(&args)).await;
+        let a_peer_id = *a.local_peer_id();
+
+        a.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+        let addr = until(&mut a, &mut b, |event| match event {
+            SwarmEvent::NewListenAddr { address, .. } => Some(address),
+            _ => None,
+        })
+        .await;
+        b.dial(addr).unwrap();
+        let (mut advertised_by_a, mut advertised_by_b) = (None, None);
+        let identified = async {
+            while advertised_by_a.is_none() || advertised_by_b.is_none() {
+                let (event, advertised) = tokio::select! {
+                    event = a.select_next_some() => (event, &mut advertised_by_b),
+                    event = b.select_next_some() => (event, &mut advertised_by_a),
+                };
+                if let SwarmEvent::Behaviour(BehaviourEvent::Identify(identify::Event::Received { info, .. })) = event {
+                    *advertised = Some(info.protocols);
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(30), identified).await.expect("both peers identified");
+        let (advertised_by_a, advertised_by_b) = (advertised_by_a.unwrap(), advertised_by_b.unwrap());
+
+        assert!(!advertised_by_a.contains(&KEEPALIVE_PROTOCOL), "{advertised_by_a:?}");
+        assert!(advertised_by_a.contains(&FILE_EXCHANGE_PROTOCOL), "{advertised_by_a:?}");
+        assert!(advertised_by_b.contains(&KEEPALIVE_PROTOCOL), "{advertised_by_b:?}");
+
+        b.behaviour_mut().keepalive.send_request(&a_peer_id, 42);
+        let answered = async {
+            loop {
+                tokio::select! {
+                    event = a.select_next_some() => {
+                        if let SwarmEvent::Behaviour(BehaviourEvent::Keepalive(request_response::Event::Message {
+                            message: request_response::Message::Request { request, channel, .. },
+                            ..
+                        })) = event
+                        {
+                            a.behaviour_mut().keepalive.send_response(channel, request).unwrap();
+                        }
+                    }
+                    event = b.select_next_some() => match event {
+                        SwarmEvent::Behaviour(BehaviourEvent::Keepalive(request_response::Event::Message {
+                            message: request_response::Message::Response { response, .. },
+                            ..
+                        })) => break response,
+                        SwarmEvent::Behaviour(BehaviourEvent::Keepalive(request_response::Event::OutboundFailure { error, .. })) => {
+                            panic!("hidden protocol didn't negotiate: {error}")
+                        }
+                        _ => {}
+                    },
+                }
+            }
+        };
+        let answer = tokio::time::timeout(Duration::from_secs(30), answered).await.expect("keep-alive answered");
+        assert_eq!(answer, 42);
+    }
+
+    #[tokio::test]
+    async fn relay_only_does_not_advertise_gossipsub_or_publish() {
+        let args = ["--disable-quic", "--disable-webrtc", "--disable-websocket", "--disable-mdns"];
+        let relay_only = opt(&[&args[..], &["--relay-only"]].concat());
+        let mut a = test_swarm(&relay_only).await;
+        let mut b = test_swarm(&opt(&args)).await;
+
+        a.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+        let addr = until(&mut a, &mut b, |event| match event {
+            SwarmEvent::NewListenAddr { address, .. } => Some(address),
+            _ => None,
+        })
+        .await;
+        b.dial(addr).unwrap();
+        let advertised_by_a = until(&mut b, &mut a, |event| match event {
+            SwarmEvent::Behaviour(BehaviourEvent::Identify(identify::Event::Received { info, .. })) => Some(info.protocols),
+            _ => None,
+        })
+        .await;
+
+        for protocol in GOSSIPSUB_PROTOCOLS {
+            assert!(!advertised_by_a.iter().any(|advertised| advertised.as_ref() == protocol), "{advertised_by_a:?}");
+        }
+        assert!(advertised_by_a.contains(&relay::HOP_PROTOCOL_NAME), "{advertised_by_a:?}");
+
+        let (reply, published) = tokio::sync::oneshot::channel();
+        let command = Command::PublishMessage {
+            topic: relay_only.gossipsub_peer_discovery.clone(),
+            data: b"hi".to_vec(),
+            reply,
+        };
+        let limits = MessageSizeLimits::new(relay_only.max_message_size, &relay_only.topic_max_message_size);
+        let mut publish_throttle = PublishThrottle::new(&[], relay_only.topic_rate_queue_size);
+        handle_command(&mut a, command, &limits, &mut publish_throttle, false, true);
+        assert!(matches!(published.await.unwrap(), Err(command::PublishError::RelayOnly)));
+    }
+
+    /// Drives both swarms until `swarm` emits an event `f` picks.
+    async fn until<T>(
+        swarm: &mut Swarm<Behaviour>,
+        other: &mut Swarm<Behaviour>,
+        mut f: impl FnMut(SwarmEvent<BehaviourEvent>) -> Option<T>,
+    ) -> T {
+        let event = async {
+            loop {
+                tokio::select! {
+                    event = swarm.select_next_some() => {
+                        if let Some(t) = f(event) {
+                            return t;
+                        }
+                    }
+                    _ = other.select_next_some() => {}
+                }
+            }
+        };
+
+        tokio::time::timeout(Duration::from_secs(30), event).await.expect("event within 30 seconds")
+    }
+
+    #[tokio::test]
+    async fn refuses_connections_from_denied_ip_ranges_before_they_are_established() {
+        let args = ["--disable-quic", "--disable-webrtc", "--disable-websocket", "--disable-mdns"];
+        let mut a = test_swarm(&opt(&[&args[..], &["--deny-cidr", "127.0.0.0/8"]].concat())).await;
+        let mut b = test_swarm(&opt(&args)).await;
+
+        a.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+        let addr = until(&mut a, &mut b, |event| match event {
+            SwarmEvent::NewListenAddr { address, .. } => Some(address),
+            _ => None,
+        })
+        .await;
+        b.dial(addr).unwrap();
+        let denied = until(&mut a, &mut b, |event| match event {
+            SwarmEvent::ConnectionEstablished { .. } => Some(false),
+            SwarmEvent::IncomingConnectionError { error: ListenError::Denied { cause }, .. } => {
+                Some(cause.downcast_ref::<DeniedIp>().is_some())
+            }
+            _ => None,
+        })
+        .await;
+        assert!(denied);
+    }
+
+    #[tokio::test]
+    async fn drops_explicit_peers_on_disconnect_and_adds_them_again_on_reconnect() {
+        let dir = std::env::temp_dir().join(format!("explicit-peers-{}", rand::random::<u64>()));
+        std::fs::create_dir(&dir).unwrap();
+        let admin_socket = dir.join("admin.sock");
+        let peerstore = dir.join("peerstore.json");
+        // Listen-only, so a doesn't redial b as gossipsub does with explicit peers.
+        let a_opt = opt(&[
+            "--disable-quic",
+            "--disable-webrtc",
+            "--disable-websocket",
+            "--disable-mdns",
+            "--listen-only",
+            "--admin-socket",
+            admin_socket.to_str().unwrap(),
+            "--peerstore-path",
+            peerstore.to_str().unwrap(),
+        ]);
+        let b_opt = opt(&["--disable-quic", "--disable-webrtc", "--disable-websocket", "--disable-mdns"]);
+        let a_key = identity::Keypair::generate_ed25519();
+        let a_peer_id = a_key.public().to_peer_id();
+        let mut a = create_swarm(
+            a_key.clone(),
+            Certificate::generate(&mut rand::thread_rng()).unwrap(),
+            None,
+            Vec::new(),
+            &Bandwidth::new(None),
+            &RelayBytes::new(None),
+            &a_opt,
+            &mut Registry::default(),
+        )
+        .await
+        .unwrap();
+        let mut b = test_swarm(&b_opt).await;
+        let b_peer_id = b.local_peer_id().to_string();
+
+        let listener = a.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+        let addr = loop {
+            if let SwarmEvent::NewListenAddr { address, .. } = a.select_next_some().await {
+                break address;
+            }
+        };
+        let (event_sender, _events) = mpsc::channel(64);
+        let (command_sender, commands) = mpsc::channel(16);
+        let node = run(
+            a,
+            a_key,
+            &a_opt,
+            Metrics::new(&mut Registry::default()),
+            Bandwidth::new(None),
+            RelayBytes::new(None),
+            Health::default(),
+            vec![(listener, addr.clone())],
+            MessageValidators::default(),
+            event_sender,
+            commands,
+            command_sender,
+        );
+
+        // The explicit peers of a, asked for over its admin socket.
+        let explicit_peers = || async {
+            use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+            let mut socket = tokio::net::UnixStream::connect(&admin_socket).await.ok()?;
+            socket.write_all(b"{\"jsonrpc\":\"2.0\",\"id\":1,\"method\":\"dumpState\"}\n").await.ok()?;
+            let mut line = String::new();
+            tokio::io::BufReader::new(socket).read_line(&mut line).await.ok()?;
+            let response = serde_json::from_str::<serde_json::Value>(&line).ok()?;
+            let peers = response.pointer("/result/explicitPeers")?.as_array()?;
+            Some(peers.iter().filter_map(|peer| peer.as_str().map(str::to_string)).collect::<Vec<_>>())
+        };
+        let explicit = |expected: bool| {
+            let b_peer_id = b_peer_id.clone();
+            async move {
+                loop {
+                    if explicit_peers().await.is_some_and(|peers| peers.contains(&b_peer_id) == expected) {
+                        return;
+                    }
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                }
+            }
+        };
+        // Drives b until `until_done` completes.
+        async fn driving<F: std::future::Future>(b: &mut Swarm<Behaviour>, until_done: F) {
+            tokio::pin!(until_done);
+            loop {
+                tokio::select! {
+                    _ = &mut until_done => return,
+                    _ = b.select_next_some() => {}
+                }
+            }
+        }
+
+        let test = async {
+            b.behaviour_mut().gossipsub.subscribe(&gossipsub::IdentTopic::new("chat")).unwrap();
+            b.dial(addr.clone()).unwrap();
+            driving(&mut b, explicit(true)).await;
+
+            b.disconnect_peer_id(a_peer_id).unwrap();
+            driving(&mut b, explicit(false)).await;
+
+            b.dial(addr.clone()).unwrap();
+            driving(&mut b, explicit(true)).await;
+        };
+        tokio::select! {
+            result = node => panic!("node stopped: {result:?}"),
+            result = tokio::time::timeout(Duration::from_secs(30), test) => result.expect("explicit peers updated within 30 seconds"),
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    async fn test_swarm(opt: &Opt) -> Swarm<Behaviour> {
+        create_swarm(
+            identity::Keypair::generate_ed25519(),
+            Certificate::generate(&mut rand::thread_rng()).unwrap(),
+            None,
+            Vec::new(),
+            &Bandwidth::new(None),
+            &RelayBytes::new(None),
+            opt,
+            &mut Registry::default(),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[test]
+    fn parses_dns_over_https_urls() {
+        assert_eq!(parse_dns_resolver("quad9"), Ok(DnsResolver::Quad9));
+        assert_eq!(
+            parse_dns_resolver("https://dns.example/dns-query"),
+            Ok(DnsResolver::Https { host: "dns.example".to_string(), port: 443 })
+        );
+        assert_eq!(
+            parse_dns_resolver("https://[2001:db8::53]:8443/dns-query"),
+            Ok(DnsResolver::Https { host: "2001:db8::53".to_string(), port: 8443 })
+        );
+        assert!(parse_dns_resolver("https://dns.example/resolve").is_err());
+        assert!(parse_dns_resolver("http://dns.example/dns-query").is_err());
+        assert!(parse_dns_resolver("https:///dns-query").is_err());
+    }
+}
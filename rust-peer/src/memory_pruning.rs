@@ -0,0 +1,166 @@
+use libp2p::{swarm::ConnectionId, PeerId};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+use sysinfo::{ProcessExt, RefreshKind, System, SystemExt};
+
+/// The cgroup v2 memory limit, `max` if there is none.
+const CGROUP_V2_MEMORY_MAX: &str = "/sys/fs/cgroup/memory.max";
+/// The cgroup v1 memory limit, a huge number if there is none.
+const CGROUP_V1_MEMORY_LIMIT: &str = "/sys/fs/cgroup/memory/memory.limit_in_bytes";
+
+/// How long to wait after closing connections before closing more. The allocator rarely hands
+/// freed memory back to the system right away, so our resident memory stays up for a while and
+/// would otherwise get another batch of connections closed every tick.
+const EVICTION_COOLDOWN: Duration = Duration::from_secs(60);
+
+/// A connection chosen to be closed to free memory.
+pub struct Eviction {
+    pub connection_id: ConnectionId,
+    pub peer_id: PeerId,
+    pub idle: Duration,
+    /// Our share of the memory per connection, which is what closing it roughly frees.
+    pub estimated_bytes: u64,
+}
+
+/// Closes the least recently active connections once the process uses more than the high
+/// watermark of the memory available to it, until it is expected to be back below the low
+/// watermark. The memory available is the limit of our cgroup if there is one, see
+/// [`cgroup_memory_limit`], and the system memory otherwise.
+///
+/// `memory_connection_limits` only refuses new connections, which leaves a node that is already
+/// full stuck there.
+pub struct MemoryPruner {
+    high: u64,
+    low: u64,
+    system: System,
+    peers: HashMap<PeerId, Activity>,
+    connections: HashMap<ConnectionId, PeerId>,
+    last_eviction: Option<Instant>,
+}
+
+/// When a peer last sent us something, over any of its connections.
+struct Activity {
+    last_active: Instant,
+    connections: HashSet<ConnectionId>,
+}
+
+impl MemoryPruner {
+    /// `high` and `low` are fractions of the memory available to us.
+    pub fn new(high: f64, low: f64) -> Self {
+        let system = System::new_with_specifics(RefreshKind::new().with_memory());
+        let total = cgroup_memory_limit().map_or(system.total_memory(), |limit| limit as u64);
+
+        Self {
+            high: (total as f64 * high) as u64,
+            low: (total as f64 * low) as u64,
+            system,
+            peers: HashMap::new(),
+            connections: HashMap::new(),
+            last_eviction: None,
+        }
+    }
+
+    pub fn on_connection_established(&mut self, peer_id: PeerId, connection_id: ConnectionId) {
+        let activity = self.peers.entry(peer_id).or_insert_with(|| Activity {
+            last_active: Instant::now(),
+            connections: HashSet::new(),
+        });
+        activity.last_active = Instant::now();
+        activity.connections.insert(connection_id);
+        self.connections.insert(connection_id, peer_id);
+    }
+
+    pub fn on_connection_closed(&mut self, connection_id: ConnectionId) {
+        let Some(peer_id) = self.connections.remove(&connection_id) else {
+            return;
+        };
+        if let Entry::Occupied(mut activity) = self.peers.entry(peer_id) {
+            activity.get_mut().connections.remove(&connection_id);
+            if activity.get().connections.is_empty() {
+                activity.remove();
+            }
+        }
+    }
+
+    /// Records that `peer_id` sent us something we care about, like a message or a request.
+    pub fn on_activity(&mut self, peer_id: &PeerId) {
+        if let Some(activity) = self.peers.get_mut(peer_id) {
+            activity.last_active = Instant::now();
+        }
+    }
+
+    /// The connections to close, least recently active first, skipping those of protected peers.
+    /// Nothing is closed within [`EVICTION_COOLDOWN`] of the previous round.
+    pub fn evictions(&mut self, is_protected: impl Fn(&PeerId) -> bool) -> Vec<Eviction> {
+        if self.last_eviction.is_some_and(|last| last.elapsed() < EVICTION_COOLDOWN) {
+            return Vec::new();
+        }
+        let Some(used) = self.used_memory() else {
+            return Vec::new();
+        };
+        if used <= self.high || self.connections.is_empty() {
+            return Vec::new();
+        }
+
+        let estimated_bytes = used / self.connections.len() as u64;
+        let to_free = used - self.low;
+        let count = to_free.div_ceil(estimated_bytes.max(1)) as usize;
+
+        let mut candidates = self
+            .peers
+            .iter()
+            .filter(|(peer_id, _)| !is_protected(peer_id))
+            .flat_map(|(peer_id, activity)| {
+                activity
+                    .connections
+                    .iter()
+                    .map(move |connection_id| (*connection_id, *peer_id, activity.last_active))
+            })
+            .collect::<Vec<_>>();
+        candidates.sort_by_key(|(_, _, last_active)| *last_active);
+
+        let evictions = candidates
+            .into_iter()
+            .take(count)
+            .map(|(connection_id, peer_id, last_active)| Eviction {
+                connection_id,
+                peer_id,
+                idle: last_active.elapsed(),
+                estimated_bytes,
+            })
+            .collect::<Vec<_>>();
+        if !evictions.is_empty() {
+            self.last_eviction = Some(Instant::now());
+        }
+
+        evictions
+    }
+
+    /// The resident memory of this process.
+    fn used_memory(&mut self) -> Option<u64> {
+        let pid = sysinfo::get_current_pid().ok()?;
+        self.system.refresh_process(pid);
+
+        self.system.process(pid).map(ProcessExt::memory)
+    }
+}
+
+/// The memory limit of our cgroup, if we run in one that has a limit, like a container started
+/// with `--memory`.
+///
+/// Only the limit of the cgroup mounted at `/sys/fs/cgroup` is read, which is ours inside a
+/// container but usually the unlimited root cgroup on a host.
+pub fn cgroup_memory_limit() -> Option<usize> {
+    let limit = std::fs::read_to_string(CGROUP_V2_MEMORY_MAX)
+        .or_else(|_| std::fs::read_to_string(CGROUP_V1_MEMORY_LIMIT))
+        .ok()?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+
+    // cgroup v1 reports no limit as the largest page aligned value, so treat anything above the
+    // system memory as unlimited.
+    let total = System::new_with_specifics(RefreshKind::new().with_memory()).total_memory();
+    (limit < total).then_some(limit as usize)
+}
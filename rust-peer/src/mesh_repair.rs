@@ -0,0 +1,37 @@
+use libp2p::PeerId;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(30);
+const MAX_BACKOFF: Duration = Duration::from_secs(30 * 60);
+
+/// Decides which cached peers to dial when a gossipsub mesh runs thin, backing off exponentially
+/// per peer so an isolated node doesn't keep hammering dead addresses.
+#[derive(Default)]
+pub struct MeshRepair {
+    backoff: HashMap<PeerId, (u32, Instant)>,
+}
+
+impl MeshRepair {
+    /// Whether `peer_id` may be dialed now. If so, the next attempt is pushed back further.
+    pub fn try_dial(&mut self, peer_id: PeerId) -> bool {
+        let now = Instant::now();
+        let (attempts, next_attempt) = self.backoff.entry(peer_id).or_insert((0, now));
+        if now < *next_attempt {
+            return false;
+        }
+
+        let delay = INITIAL_BACKOFF
+            .saturating_mul(2u32.saturating_pow(*attempts))
+            .min(MAX_BACKOFF);
+        *attempts += 1;
+        *next_attempt = now + delay;
+
+        true
+    }
+
+    /// Resets the backoff of a peer we managed to connect to.
+    pub fn on_connection_established(&mut self, peer_id: &PeerId) {
+        self.backoff.remove(peer_id);
+    }
+}
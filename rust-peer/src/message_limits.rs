@@ -0,0 +1,45 @@
+use libp2p::gossipsub::{IdentTopic, TopicHash};
+use std::collections::HashMap;
+
+/// Maximum gossipsub message sizes, with optional overrides per topic.
+#[derive(Debug, Clone)]
+pub struct MessageSizeLimits {
+    default: usize,
+    topics: HashMap<TopicHash, usize>,
+}
+
+impl MessageSizeLimits {
+    pub fn new(default: usize, overrides: &[(String, usize)]) -> Self {
+        Self {
+            default,
+            topics: overrides
+                .iter()
+                .map(|(topic, limit)| (IdentTopic::new(topic).hash(), *limit))
+                .collect(),
+        }
+    }
+
+    pub fn limit(&self, topic: &TopicHash) -> usize {
+        self.topics.get(topic).copied().unwrap_or(self.default)
+    }
+
+    /// The largest message allowed on any topic, which is what gossipsub has to accept.
+    pub fn max(&self) -> usize {
+        self.topics
+            .values()
+            .copied()
+            .fold(self.default, usize::max)
+    }
+}
+
+/// Parses a `<topic>=<bytes>` override.
+pub fn parse_topic_limit(s: &str) -> Result<(String, usize), String> {
+    let (topic, limit) = s
+        .rsplit_once('=')
+        .ok_or_else(|| format!("expected <topic>=<bytes>, got {s:?}"))?;
+    let limit = limit
+        .parse()
+        .map_err(|e| format!("invalid size {limit:?}: {e}"))?;
+
+    Ok((topic.to_string(), limit))
+}
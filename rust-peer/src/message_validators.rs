@@ -0,0 +1,62 @@
+use libp2p::gossipsub::{IdentTopic, TopicHash};
+use prost::Message;
+use std::collections::HashMap;
+
+use crate::{NodeStatus, Peer};
+
+/// Decides whether a message received on a topic is well-formed.
+pub type Validator = fn(&[u8]) -> bool;
+
+/// Validators by topic, run on every received message before it is forwarded. Messages that fail
+/// are rejected, which keeps gossipsub from forwarding them and lowers the score of the peer that
+/// sent them. Messages on topics without a validator are accepted.
+#[derive(Default, Clone)]
+pub struct MessageValidators {
+    topics: HashMap<TopicHash, Validator>,
+}
+
+impl MessageValidators {
+    /// The built-in validators: messages on `dcontact_topic` and `peer_discovery_topic` have to be
+    /// a `Peer` protobuf, and those on `status_topic` a `NodeStatus`.
+    pub fn builtin(dcontact_topic: &str, peer_discovery_topic: &str, status_topic: &str) -> Self {
+        let mut validators = Self::default();
+        validators.register(dcontact_topic, is_peer);
+        validators.register(peer_discovery_topic, is_peer);
+        validators.register(status_topic, is_node_status);
+
+        validators
+    }
+
+    /// Sets the validator for `topic`, replacing and returning its previous one.
+    pub fn register(&mut self, topic: &str, validator: Validator) -> Option<Validator> {
+        self.topics.insert(IdentTopic::new(topic).hash(), validator)
+    }
+
+    pub fn is_valid(&self, topic: &TopicHash, data: &[u8]) -> bool {
+        self.topics.get(topic).is_none_or(|validator| validator(data))
+    }
+}
+
+fn is_peer(data: &[u8]) -> bool {
+    Peer::decode(data).is_ok()
+}
+
+fn is_node_status(data: &[u8]) -> bool {
+    NodeStatus::decode(data).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_malformed_peers_on_the_peer_discovery_topic() {
+        let validators = MessageValidators::builtin("dcontact", "discovery", "status");
+        let peer = Peer::default();
+        let discovery = IdentTopic::new("discovery").hash();
+
+        assert!(validators.is_valid(&discovery, &peer.encode_to_vec()));
+        assert!(!validators.is_valid(&discovery, b"\xff\xff"));
+        assert!(validators.is_valid(&IdentTopic::new("chat").hash(), b"\xff\xff"));
+    }
+}
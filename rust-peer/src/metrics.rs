@@ -0,0 +1,392 @@
+use anyhow::Result;
+use libp2p::{autonat, dcutr, gossipsub, metrics::Recorder, PeerId};
+use tracing::{debug, info};
+use prometheus_client::encoding::text::encode;
+use prometheus_client::encoding::EncodeLabelSet;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
+use prometheus_client::metrics::{counter::Counter, family::Family, gauge::Gauge};
+use std::collections::HashMap;
+use prometheus_client::registry::Registry;
+use std::net::SocketAddr;
+use std::sync::atomic::AtomicU64;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::geoip::Origin;
+use crate::http::{read_request_path, write_response};
+use crate::BehaviourEvent;
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct NatStatusLabels {
+    status: &'static str,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct PeerLabels {
+    peer_id: String,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct HolePunchLabels {
+    peer_id: String,
+    result: &'static str,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct TransportLabels {
+    transport: &'static str,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct DirectionLabels {
+    direction: &'static str,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct OriginLabels {
+    country: String,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct TopicLabels {
+    topic: String,
+}
+
+/// All metrics of the node: the libp2p protocol metrics plus our own.
+pub struct Metrics {
+    libp2p: libp2p::metrics::Metrics,
+    nat_status: Family<NatStatusLabels, Gauge>,
+    peer_rtt: Family<PeerLabels, Gauge<f64, AtomicU64>>,
+    hole_punches: Family<HolePunchLabels, Counter>,
+    transport_connections: Family<TransportLabels, Gauge>,
+    relay_reservations: Gauge,
+    relay_circuits: Gauge,
+    peerstore_peers: Gauge,
+    mesh_peers: Family<TopicLabels, Gauge>,
+    bandwidth_throughput: Family<DirectionLabels, Gauge<f64, AtomicU64>>,
+    pending_connections: Family<DirectionLabels, Gauge>,
+    relayed_bytes: Counter,
+    connection_establishment: Family<TransportLabels, Histogram, fn() -> Histogram>,
+    first_identify: Family<TransportLabels, Histogram, fn() -> Histogram>,
+    connection_churn: Gauge<f64, AtomicU64>,
+    connection_churn_alert: Gauge,
+    connection_origins: Family<OriginLabels, Counter>,
+}
+
+impl Metrics {
+    pub fn new(registry: &mut Registry) -> Self {
+        let libp2p = libp2p::metrics::Metrics::new(registry);
+
+        let nat_status = Family::default();
+        registry.register(
+            "nat_status",
+            "Reachability as reported by AutoNAT, 1 for the current status",
+            nat_status.clone(),
+        );
+
+        let peer_rtt = Family::default();
+        registry.register(
+            "peer_rtt_seconds",
+            "Last known ping round-trip time per connected peer",
+            peer_rtt.clone(),
+        );
+
+        let hole_punches = Family::default();
+        registry.register(
+            "hole_punches",
+            "DCUtR hole punches per remote peer, by result",
+            hole_punches.clone(),
+        );
+
+        let transport_connections = Family::default();
+        registry.register(
+            "transport_connections",
+            "Open connections per transport",
+            transport_connections.clone(),
+        );
+
+        let relay_reservations = Gauge::default();
+        registry.register(
+            "relay_reservations",
+            "Active reservations on our relay",
+            relay_reservations.clone(),
+        );
+
+        let relay_circuits = Gauge::default();
+        registry.register(
+            "relay_circuits",
+            "Active circuits through our relay",
+            relay_circuits.clone(),
+        );
+
+        let peerstore_peers = Gauge::default();
+        registry.register(
+            "peerstore_peers",
+            "Peers in the peerstore",
+            peerstore_peers.clone(),
+        );
+
+        let mesh_peers = Family::default();
+        registry.register(
+            "gossipsub_mesh_peers",
+            "Peers in our gossipsub mesh per subscribed topic",
+            mesh_peers.clone(),
+        );
+
+        let bandwidth_throughput = Family::default();
+        registry.register(
+            "bandwidth_throughput_bytes_per_second",
+            "Bytes per second over all connections since the last stats update, by direction",
+            bandwidth_throughput.clone(),
+        );
+
+        let pending_connections = Family::default();
+        registry.register(
+            "pending_connections",
+            "Connection attempts neither established nor failed yet, by direction",
+            pending_connections.clone(),
+        );
+
+        let relayed_bytes = Counter::default();
+        registry.register(
+            "relayed_bytes",
+            "Bytes relayed over circuits through our relay, in both directions",
+            relayed_bytes.clone(),
+        );
+
+        let connection_establishment = Family::new_with_constructor(duration_histogram as fn() -> Histogram);
+        registry.register(
+            "connection_establishment_seconds",
+            "Time from dialing or an incoming connection attempt until the connection is upgraded, by transport",
+            connection_establishment.clone(),
+        );
+
+        let first_identify = Family::new_with_constructor(duration_histogram as fn() -> Histogram);
+        registry.register(
+            "time_to_first_identify_seconds",
+            "Time from the first connection to a peer until its first identify, by transport",
+            first_identify.clone(),
+        );
+
+        let connection_churn = Gauge::default();
+        registry.register(
+            "connection_churn_per_minute",
+            "Moving average of connects and disconnects per minute",
+            connection_churn.clone(),
+        );
+
+        let connection_churn_alert = Gauge::default();
+        registry.register(
+            "connection_churn_alert",
+            "Whether connection churn is above --churn-alert-threshold",
+            connection_churn_alert.clone(),
+        );
+
+        let connection_origins = Family::default();
+        registry.register(
+            "connection_origins",
+            "Established connections by the country of the remote IP, with --geoip-db",
+            connection_origins.clone(),
+        );
+
+        Self {
+            libp2p,
+            nat_status,
+            peer_rtt,
+            hole_punches,
+            transport_connections,
+            relay_reservations,
+            relay_circuits,
+            peerstore_peers,
+            mesh_peers,
+            bandwidth_throughput,
+            pending_connections,
+            relayed_bytes,
+            connection_establishment,
+            first_identify,
+            connection_churn,
+            connection_churn_alert,
+            connection_origins,
+        }
+    }
+
+    pub fn record<E>(&self, event: &E)
+    where
+        libp2p::metrics::Metrics: Recorder<E>,
+    {
+        self.libp2p.record(event)
+    }
+
+    pub fn record_behaviour_event(&self, event: &BehaviourEvent) {
+        match event {
+            BehaviourEvent::Ping(e) => self.record(e),
+            BehaviourEvent::Dcutr(e) => {
+                self.record(e);
+                self.record_hole_punch(e);
+            }
+            BehaviourEvent::Gossipsub(e) => self.record(e),
+            BehaviourEvent::Identify(e) => self.record(e),
+            BehaviourEvent::Relay(e) => self.record(e),
+            BehaviourEvent::Kademlia(e) => self.record(e),
+            _ => {}
+        }
+    }
+
+    fn record_hole_punch(&self, event: &dcutr::Event) {
+        self.hole_punches
+            .get_or_create(&HolePunchLabels {
+                peer_id: event.remote_peer_id.to_string(),
+                result: if event.result.is_ok() {
+                    "success"
+                } else {
+                    "failure"
+                },
+            })
+            .inc();
+    }
+
+    pub fn set_nat_status(&self, status: &autonat::NatStatus) {
+        let current = match status {
+            autonat::NatStatus::Public(_) => "public",
+            autonat::NatStatus::Private => "private",
+            autonat::NatStatus::Unknown => "unknown",
+        };
+
+        for status in ["public", "private", "unknown"] {
+            self.nat_status
+                .get_or_create(&NatStatusLabels { status })
+                .set((status == current) as i64);
+        }
+    }
+
+    pub fn set_transport_connections(&self, transport: &'static str, count: usize) {
+        self.transport_connections
+            .get_or_create(&TransportLabels { transport })
+            .set(count as i64);
+    }
+
+    pub fn set_relay_usage(&self, reservations: usize, circuits: usize) {
+        self.relay_reservations.set(reservations as i64);
+        self.relay_circuits.set(circuits as i64);
+    }
+
+    pub fn set_peerstore_peers(&self, count: usize) {
+        self.peerstore_peers.set(count as i64);
+    }
+
+    pub fn set_bandwidth_throughput(&self, inbound: f64, outbound: f64) {
+        self.bandwidth_throughput
+            .get_or_create(&DirectionLabels { direction: "inbound" })
+            .set(inbound);
+        self.bandwidth_throughput
+            .get_or_create(&DirectionLabels { direction: "outbound" })
+            .set(outbound);
+    }
+
+    pub fn set_pending_connections(&self, (outbound, inbound): (usize, usize)) {
+        self.pending_connections
+            .get_or_create(&DirectionLabels { direction: "outbound" })
+            .set(outbound as i64);
+        self.pending_connections
+            .get_or_create(&DirectionLabels { direction: "inbound" })
+            .set(inbound as i64);
+    }
+
+    pub fn set_connection_churn(&self, per_minute: f64, alert: bool) {
+        self.connection_churn.set(per_minute);
+        self.connection_churn_alert.set(alert as i64);
+    }
+
+    pub fn record_connection_establishment(&self, transport: &'static str, duration: Duration) {
+        self.connection_establishment
+            .get_or_create(&TransportLabels { transport })
+            .observe(duration.as_secs_f64());
+    }
+
+    pub fn record_connection_origin(&self, origin: &Origin) {
+        self.connection_origins
+            .get_or_create(&OriginLabels {
+                country: origin.country.clone().unwrap_or_else(|| "unknown".to_string()),
+            })
+            .inc();
+    }
+
+    pub fn record_first_identify(&self, transport: &'static str, duration: Duration) {
+        self.first_identify
+            .get_or_create(&TransportLabels { transport })
+            .observe(duration.as_secs_f64());
+    }
+
+    pub fn record_relayed_bytes(&self, bytes: u64) {
+        self.relayed_bytes.inc_by(bytes);
+    }
+
+    pub fn set_mesh_peers(&self, mesh_peers: &[(gossipsub::TopicHash, usize)]) {
+        self.mesh_peers.clear();
+
+        for (topic, count) in mesh_peers {
+            self.mesh_peers
+                .get_or_create(&TopicLabels {
+                    topic: topic.to_string(),
+                })
+                .set(*count as i64);
+        }
+    }
+
+    pub fn set_peer_rtts(&self, rtts: &HashMap<PeerId, Duration>) {
+        self.peer_rtt.clear();
+
+        for (peer, rtt) in rtts {
+            self.peer_rtt
+                .get_or_create(&PeerLabels {
+                    peer_id: peer.to_string(),
+                })
+                .set(rtt.as_secs_f64());
+        }
+    }
+}
+
+/// Serves the registry in the OpenMetrics text format on `GET /metrics`.
+pub async fn serve(addr: SocketAddr, registry: Arc<Mutex<Registry>>) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!(%addr, "Serving metrics on /metrics");
+
+    loop {
+        let (stream, remote) = listener.accept().await?;
+        let registry = registry.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = handle_request(stream, &registry).await {
+                debug!(%remote, %e, "Failed to handle metrics request");
+            }
+        });
+    }
+}
+
+async fn handle_request(mut stream: TcpStream, registry: &Mutex<Registry>) -> Result<()> {
+    let Some(path) = read_request_path(&mut stream).await? else {
+        return write_response(&mut stream, "400 Bad Request", "text/plain", "").await;
+    };
+
+    if path != "/metrics" {
+        return write_response(&mut stream, "404 Not Found", "text/plain", "").await;
+    }
+
+    let mut body = String::new();
+    encode(&mut body, &registry.lock().expect("metrics registry lock poisoned"))?;
+
+    write_response(
+        &mut stream,
+        "200 OK",
+        "application/openmetrics-text; version=1.0.0; charset=utf-8",
+        &body,
+    )
+    .await
+}
+
+/// 10ms to about 40s, which spans a local TCP handshake up to a WebRTC connection through a relay
+/// that is about to time out.
+fn duration_histogram() -> Histogram {
+    Histogram::new(exponential_buckets(0.01, 2.0, 13))
+}
@@ -0,0 +1,100 @@
+use libp2p::{gossipsub, PeerId};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Window over which new topics are counted per peer.
+const WINDOW: Duration = Duration::from_secs(60);
+/// Application score given to peers that bring us too many new topics. With the default
+/// application weight of 10 this puts them below the gossip threshold.
+const FLOOD_APPLICATION_SCORE: f64 = -5.0;
+
+/// Topics our peers subscribed to and we want to subscribe to as well, buffered so we subscribe at
+/// most once per tick no matter how many new topics show up in between.
+///
+/// Gossipsub only hands us messages on topics we are subscribed to, so new topics are learned from
+/// the subscriptions of our peers instead. Peers subscribing to more than `max_per_peer` new topics
+/// within [`WINDOW`] are penalized through their gossipsub application score, and the topics they
+/// bring are ignored until they calm down.
+pub struct NewTopics {
+    pending: HashSet<gossipsub::TopicHash>,
+    max_pending: usize,
+    seen: HashMap<PeerId, VecDeque<Instant>>,
+    max_per_peer: usize,
+    penalized: HashSet<PeerId>,
+}
+
+impl NewTopics {
+    pub fn new(max_pending: usize, max_per_peer: usize) -> Self {
+        Self {
+            pending: HashSet::new(),
+            max_pending,
+            seen: HashMap::new(),
+            max_per_peer,
+            penalized: HashSet::new(),
+        }
+    }
+
+    /// Records that `source` subscribed to `topic`.
+    pub fn record(
+        &mut self,
+        gossipsub: &mut gossipsub::Behaviour,
+        source: PeerId,
+        topic: &gossipsub::TopicHash,
+    ) {
+        if self.pending.contains(topic) || gossipsub.topics().any(|t| t == topic) {
+            return;
+        }
+
+        let now = Instant::now();
+        let seen = self.seen.entry(source).or_default();
+        prune(seen, now);
+        seen.push_back(now);
+
+        if seen.len() > self.max_per_peer {
+            if self.penalized.insert(source) {
+                warn!(
+                    peer_id = %source,
+                    "Peer subscribed to more than {} new topics within {WINDOW:?}, penalizing it",
+                    self.max_per_peer
+                );
+                gossipsub.set_application_score(&source, FLOOD_APPLICATION_SCORE);
+            }
+            return;
+        }
+
+        if self.pending.len() < self.max_pending {
+            self.pending.insert(topic.clone());
+        }
+    }
+
+    /// Takes the topics buffered since the last call, and lifts the penalty of peers that stayed
+    /// below the limit for a whole window.
+    pub fn drain(&mut self, gossipsub: &mut gossipsub::Behaviour) -> Vec<gossipsub::TopicHash> {
+        let now = Instant::now();
+        self.seen.retain(|_, seen| {
+            prune(seen, now);
+            !seen.is_empty()
+        });
+
+        let seen = &self.seen;
+        let max_per_peer = self.max_per_peer;
+        self.penalized.retain(|peer| {
+            if seen.get(peer).is_some_and(|seen| seen.len() > max_per_peer) {
+                return true;
+            }
+
+            info!(peer_id = %peer, "Lifting the new topic penalty of peer");
+            gossipsub.set_application_score(peer, 0.0);
+            false
+        });
+
+        self.pending.drain().collect()
+    }
+}
+
+fn prune(seen: &mut VecDeque<Instant>, now: Instant) {
+    while seen.front().is_some_and(|t| now.duration_since(*t) > WINDOW) {
+        seen.pop_front();
+    }
+}
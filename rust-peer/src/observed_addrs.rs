@@ -0,0 +1,78 @@
+use libp2p::{Multiaddr, PeerId};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+/// The addresses peers observe us on via identify, so we only advertise one once enough distinct
+/// peers agree on it, rather than trusting whichever peer identified us last.
+///
+/// Each peer has one vote, for the address it observed most recently. Votes lapse after `ttl`
+/// unless the periodic identify renews them, and addresses falling below the threshold are
+/// demoted again.
+pub struct ObservedAddrs {
+    confirmations: usize,
+    ttl: Duration,
+    votes: HashMap<PeerId, (Multiaddr, Instant)>,
+    promoted: HashSet<Multiaddr>,
+    /// External addresses we know from elsewhere, like `--external-address` or AutoNAT, which
+    /// are never demoted.
+    pinned: HashSet<Multiaddr>,
+}
+
+impl ObservedAddrs {
+    pub fn new(confirmations: usize, ttl: Duration) -> Self {
+        Self {
+            confirmations,
+            ttl,
+            votes: HashMap::new(),
+            promoted: HashSet::new(),
+            pinned: HashSet::new(),
+        }
+    }
+
+    pub fn observed(&mut self, peer_id: PeerId, addr: Multiaddr) {
+        self.votes.insert(peer_id, (addr, Instant::now()));
+    }
+
+    pub fn pin(&mut self, addr: Multiaddr) {
+        self.pinned.insert(addr);
+    }
+
+    /// Drops lapsed votes.
+    pub fn expire(&mut self) {
+        let ttl = self.ttl;
+        self.votes.retain(|_, (_, observed)| observed.elapsed() < ttl);
+    }
+
+    /// The addresses to add as external addresses and the ones to remove again, since the last
+    /// call.
+    pub fn changes(&mut self) -> (Vec<Multiaddr>, Vec<Multiaddr>) {
+        let mut counts = HashMap::<&Multiaddr, usize>::new();
+        for (addr, _) in self.votes.values() {
+            *counts.entry(addr).or_default() += 1;
+        }
+        let confirmed = counts
+            .into_iter()
+            .filter(|(_, count)| *count >= self.confirmations)
+            .map(|(addr, _)| addr.clone())
+            .collect::<HashSet<_>>();
+
+        let promoted = confirmed
+            .difference(&self.promoted)
+            .cloned()
+            .collect::<Vec<_>>();
+        let demoted = self
+            .promoted
+            .difference(&confirmed)
+            .filter(|addr| !self.pinned.contains(*addr))
+            .cloned()
+            .collect::<Vec<_>>();
+        self.promoted = confirmed;
+
+        (promoted, demoted)
+    }
+
+    /// How many peers currently vote for `addr`.
+    pub fn confirmations(&self, addr: &Multiaddr) -> usize {
+        self.votes.values().filter(|(vote, _)| vote == addr).count()
+    }
+}
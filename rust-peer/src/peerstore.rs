@@ -0,0 +1,192 @@
+use anyhow::{Context, Result};
+use libp2p::{Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs;
+use tracing::{info, warn};
+
+/// Addresses of the peers we have identified, persisted so we can rejoin the network quickly after
+/// a restart.
+///
+/// With `max_peers`, the peers seen least recently are evicted once there are more, so transient
+/// peers like browsers don't make it grow forever on a busy node.
+pub struct Peerstore {
+    path: PathBuf,
+    max_peers: Option<NonZeroUsize>,
+    peers: HashMap<PeerId, StoredAddrs>,
+    dirty: bool,
+}
+
+struct StoredAddrs {
+    addrs: Vec<Multiaddr>,
+    /// Seconds since the Unix epoch.
+    last_seen: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredPeer {
+    peer_id: PeerId,
+    addrs: Vec<Multiaddr>,
+    /// Missing in peerstores written before peers were evicted, which then go first.
+    #[serde(default)]
+    last_seen: u64,
+}
+
+impl Peerstore {
+    /// Loads the peerstore at `path`. A missing or unreadable file results in an empty peerstore.
+    pub async fn load(path: &Path, max_peers: Option<NonZeroUsize>) -> Self {
+        let peers = match read_peers(path).await {
+            Ok(peers) => {
+                info!(peers = peers.len(), path = %path.display(), "Loaded peerstore");
+                peers
+            }
+            Err(e) => {
+                if path.exists() {
+                    warn!(path = %path.display(), error = format!("{e:#}"), "Ignoring peerstore");
+                }
+                HashMap::new()
+            }
+        };
+
+        Self {
+            path: path.to_path_buf(),
+            max_peers,
+            peers,
+            dirty: false,
+        }
+    }
+
+    /// Replaces the addresses of `peer_id`, returning the previously known ones it no longer
+    /// listens on.
+    pub fn insert(&mut self, peer_id: PeerId, addrs: Vec<Multiaddr>) -> Vec<Multiaddr> {
+        if addrs.is_empty() {
+            return Vec::new();
+        }
+        let last_seen = now();
+        if let Some(stored) = self.peers.get_mut(&peer_id).filter(|stored| stored.addrs == addrs) {
+            stored.last_seen = last_seen;
+            return Vec::new();
+        }
+
+        let previous = self.peers.insert(
+            peer_id,
+            StoredAddrs {
+                addrs: addrs.clone(),
+                last_seen,
+            },
+        );
+        self.dirty = true;
+
+        previous
+            .map(|stored| stored.addrs)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|addr| !addrs.contains(addr))
+            .collect()
+    }
+
+    /// Records that we were connected to `peer_id` until now, so it counts as recently seen.
+    pub fn on_connection_closed(&mut self, peer_id: &PeerId) {
+        if let Some(stored) = self.peers.get_mut(peer_id) {
+            stored.last_seen = now();
+        }
+    }
+
+    /// Removes the peers seen least recently until there are no more than `max_peers`, skipping
+    /// pinned ones, and returns them.
+    pub fn evict(&mut self, is_pinned: impl Fn(&PeerId) -> bool) -> Vec<PeerId> {
+        let Some(max_peers) = self.max_peers else {
+            return Vec::new();
+        };
+        let Some(excess) = self.peers.len().checked_sub(max_peers.get()).filter(|excess| *excess > 0) else {
+            return Vec::new();
+        };
+
+        let mut candidates = self
+            .peers
+            .iter()
+            .filter(|(peer_id, _)| !is_pinned(peer_id))
+            .map(|(peer_id, stored)| (*peer_id, stored.last_seen))
+            .collect::<Vec<_>>();
+        candidates.sort_by_key(|(_, last_seen)| *last_seen);
+
+        let evicted = candidates
+            .into_iter()
+            .take(excess)
+            .map(|(peer_id, _)| peer_id)
+            .collect::<Vec<_>>();
+        for peer_id in &evicted {
+            self.peers.remove(peer_id);
+        }
+        self.dirty |= !evicted.is_empty();
+
+        evicted
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn peers(&self) -> impl Iterator<Item = (&PeerId, &Vec<Multiaddr>)> {
+        self.peers.iter().map(|(peer_id, stored)| (peer_id, &stored.addrs))
+    }
+
+    /// Writes the peerstore to disk if it changed since the last save.
+    ///
+    /// The file is written next to its destination and then renamed, so a crash mid-write leaves
+    /// the previous version intact.
+    pub async fn save(&mut self) -> Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        let peers = self
+            .peers
+            .iter()
+            .map(|(peer_id, stored)| StoredPeer {
+                peer_id: *peer_id,
+                addrs: stored.addrs.clone(),
+                last_seen: stored.last_seen,
+            })
+            .collect::<Vec<_>>();
+        let json = serde_json::to_vec_pretty(&peers)?;
+
+        let tmp = self.path.with_extension("json.tmp");
+        fs::write(&tmp, json)
+            .await
+            .with_context(|| format!("Failed to write {}", tmp.display()))?;
+        fs::rename(&tmp, &self.path)
+            .await
+            .with_context(|| format!("Failed to replace {}", self.path.display()))?;
+
+        self.dirty = false;
+
+        Ok(())
+    }
+}
+
+async fn read_peers(path: &Path) -> Result<HashMap<PeerId, StoredAddrs>> {
+    let json = fs::read(path).await?;
+    let peers = serde_json::from_slice::<Vec<StoredPeer>>(&json)?;
+
+    Ok(peers
+        .into_iter()
+        .map(|peer| {
+            let stored = StoredAddrs {
+                addrs: peer.addrs,
+                last_seen: peer.last_seen,
+            };
+            (peer.peer_id, stored)
+        })
+        .collect())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
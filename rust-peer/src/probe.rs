@@ -0,0 +1,109 @@
+use anyhow::{bail, Context, Result};
+use futures::StreamExt;
+use libp2p::{
+    identify, noise, ping,
+    core::muxing::StreamMuxerBox,
+    swarm::{NetworkBehaviour, SwarmEvent},
+    tcp, yamux, Multiaddr, Transport,
+};
+use libp2p_webrtc as webrtc;
+use std::time::Duration;
+use tracing::debug;
+
+use crate::IDENTIFY_PROTOCOL_VERSION;
+
+/// Only what's needed to learn about a remote peer, so probing doesn't join the network.
+#[derive(NetworkBehaviour)]
+struct ProbeBehaviour {
+    ping: ping::Behaviour,
+    identify: identify::Behaviour,
+}
+
+/// Dials `target` with a throwaway identity and prints what it reports via identify, along with
+/// the ping round trip time, as a JSON object on stdout.
+pub async fn run(target: Multiaddr, timeout: Duration) -> Result<()> {
+    let mut swarm = libp2p::SwarmBuilder::with_new_identity()
+        .with_tokio()
+        .with_tcp(tcp::Config::default(), noise::Config::new, yamux::Config::default)?
+        .with_quic()
+        .with_other_transport(|key| {
+            let certificate = webrtc::tokio::Certificate::generate(&mut rand::thread_rng())?;
+            Ok(webrtc::tokio::Transport::new(key.clone(), certificate)
+                .map(|(peer_id, conn), _| (peer_id, StreamMuxerBox::new(conn))))
+        })?
+        .with_dns()?
+        .with_websocket(noise::Config::new, yamux::Config::default)
+        .await?
+        .with_behaviour(|key| ProbeBehaviour {
+            ping: ping::Behaviour::new(ping::Config::new().with_interval(Duration::from_secs(1))),
+            identify: identify::Behaviour::new(identify::Config::new(
+                IDENTIFY_PROTOCOL_VERSION.into(),
+                key.public(),
+            )),
+        })?
+        // Neither ping nor identify keep the connection alive on their own.
+        .with_swarm_config(|config| config.with_idle_connection_timeout(timeout))
+        .build();
+
+    swarm
+        .dial(target.clone())
+        .with_context(|| format!("Failed to dial {target}"))?;
+
+    let (peer_id, info, rtt) = tokio::time::timeout(timeout, async {
+        let mut info = None;
+        let mut rtt = None;
+
+        loop {
+            match swarm.select_next_some().await {
+                SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                    debug!(%peer_id, "Connected to {target}");
+                }
+                SwarmEvent::OutgoingConnectionError { error, .. } => {
+                    bail!("Failed to connect to {target}: {error}");
+                }
+                SwarmEvent::ConnectionClosed { peer_id, cause, .. } => {
+                    bail!("Connection to {peer_id} closed before the probe completed: {cause:?}");
+                }
+                SwarmEvent::Behaviour(ProbeBehaviourEvent::Identify(
+                    identify::Event::Received { peer_id, info: received },
+                )) => {
+                    info = Some((peer_id, received));
+                }
+                SwarmEvent::Behaviour(ProbeBehaviourEvent::Identify(identify::Event::Error {
+                    peer_id,
+                    error,
+                })) => {
+                    bail!("Identify with {peer_id} failed: {error}");
+                }
+                SwarmEvent::Behaviour(ProbeBehaviourEvent::Ping(ping::Event {
+                    peer,
+                    result,
+                    ..
+                })) => match result {
+                    Ok(duration) => rtt = Some(duration),
+                    Err(e) => bail!("Ping to {peer} failed: {e}"),
+                },
+                event => debug!(?event, "Swarm event"),
+            }
+
+            if let (Some((peer_id, info)), Some(rtt)) = (&info, rtt) {
+                return Ok((*peer_id, info.clone(), rtt));
+            }
+        }
+    })
+    .await
+    .with_context(|| format!("Timed out probing {target} after {timeout:?}"))??;
+
+    let report = serde_json::json!({
+        "peer_id": peer_id.to_string(),
+        "agent_version": info.agent_version,
+        "protocol_version": info.protocol_version,
+        "protocols": info.protocols.iter().map(ToString::to_string).collect::<Vec<_>>(),
+        "listen_addrs": info.listen_addrs.iter().map(ToString::to_string).collect::<Vec<_>>(),
+        "observed_addr": info.observed_addr.to_string(),
+        "rtt_ms": rtt.as_secs_f64() * 1000.0,
+    });
+    println!("{report}");
+
+    Ok(())
+}
@@ -0,0 +1,136 @@
+use crate::command::PublishError;
+use libp2p::gossipsub::{self, IdentTopic, TopicHash};
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+use tracing::warn;
+
+/// A message we were asked to publish.
+pub struct PendingPublish {
+    pub topic: IdentTopic,
+    pub data: Vec<u8>,
+    pub reply: oneshot::Sender<Result<gossipsub::MessageId, PublishError>>,
+}
+
+/// Token buckets limiting how fast we publish on individual topics, so a hot topic can't use up
+/// all our bandwidth while `flood_publish` sends every message to all peers.
+///
+/// Messages over the rate are queued, and rejected once the topic's queue is full.
+pub struct PublishThrottle {
+    buckets: HashMap<TopicHash, Bucket>,
+    queue_size: usize,
+}
+
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+    refilled: Instant,
+    queue: VecDeque<PendingPublish>,
+    dropped: u64,
+}
+
+impl Bucket {
+    /// Allows bursts of up to a second's worth of messages.
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate.max(1.0));
+        self.refilled = now;
+    }
+
+    fn next_token(&self) -> Instant {
+        let missing = (1.0 - self.tokens).max(0.0);
+        self.refilled + Duration::from_secs_f64(missing / self.rate)
+    }
+}
+
+impl PublishThrottle {
+    /// `rates` are in messages per second. Topics without a rate aren't throttled.
+    pub fn new(rates: &[(String, f64)], queue_size: usize) -> Self {
+        let now = Instant::now();
+
+        Self {
+            buckets: rates
+                .iter()
+                .map(|(topic, rate)| {
+                    let bucket = Bucket {
+                        rate: *rate,
+                        tokens: rate.max(1.0),
+                        refilled: now,
+                        queue: VecDeque::new(),
+                        dropped: 0,
+                    };
+                    (IdentTopic::new(topic).hash(), bucket)
+                })
+                .collect(),
+            queue_size,
+        }
+    }
+
+    /// Returns `publish` if it may be published right away, otherwise queues it.
+    pub fn submit(&mut self, publish: PendingPublish) -> Option<PendingPublish> {
+        let Some(bucket) = self.buckets.get_mut(&publish.topic.hash()) else {
+            return Some(publish);
+        };
+        bucket.refill(Instant::now());
+
+        if bucket.queue.is_empty() && bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Some(publish);
+        }
+        if bucket.queue.len() < self.queue_size {
+            bucket.queue.push_back(publish);
+            return None;
+        }
+
+        bucket.dropped += 1;
+        warn!(
+            event = "publish_throttled",
+            topic = %publish.topic,
+            dropped = bucket.dropped,
+            "Dropping message, the topic's publish queue is full"
+        );
+        let _ = publish.reply.send(Err(PublishError::Throttled));
+
+        None
+    }
+
+    /// Waits until one of the queued messages may be published. Never resolves if nothing is
+    /// queued.
+    ///
+    /// Cancel safe, nothing is dequeued until the message is returned.
+    pub async fn next_ready(&mut self) -> PendingPublish {
+        let Some((topic, at)) = self
+            .buckets
+            .iter()
+            .filter(|(_, bucket)| !bucket.queue.is_empty())
+            .map(|(topic, bucket)| (topic.clone(), bucket.next_token()))
+            .min_by_key(|(_, at)| *at)
+        else {
+            return futures::future::pending().await;
+        };
+
+        tokio::time::sleep_until(at.into()).await;
+
+        let bucket = self.buckets.get_mut(&topic).expect("bucket of a queued message");
+        bucket.refill(Instant::now());
+        // May go slightly below zero due to rounding, which the next refill makes up for.
+        bucket.tokens -= 1.0;
+
+        bucket.queue.pop_front().expect("queue is not empty")
+    }
+}
+
+/// Parses a `<topic>=<msgs_per_sec>` rate.
+pub fn parse_topic_rate(s: &str) -> Result<(String, f64), String> {
+    let (topic, rate) = s
+        .rsplit_once('=')
+        .ok_or_else(|| format!("expected <topic>=<msgs_per_sec>, got {s:?}"))?;
+    let rate = rate
+        .parse::<f64>()
+        .map_err(|e| format!("invalid rate {rate:?}: {e}"))?;
+    if !(rate > 0.0 && rate.is_finite()) {
+        return Err(format!("rate must be a positive number, got {rate}"));
+    }
+
+    Ok((topic.to_string(), rate))
+}
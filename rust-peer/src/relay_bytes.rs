@@ -0,0 +1,330 @@
+use futures::{ready, AsyncRead, AsyncWrite};
+use libp2p::core::muxing::{StreamMuxer, StreamMuxerBox, StreamMuxerEvent, SubstreamBox};
+use libp2p::PeerId;
+use std::collections::HashMap;
+use std::io;
+use std::num::NonZeroU64;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// Circuits are opened on inbound streams of this protocol, which then carry the relayed bytes.
+const HOP_PROTOCOL: &[u8] = b"/libp2p/circuit/relay/0.2.0/hop";
+/// The multistream-select header of a stream and its protocol fit into this many bytes.
+const SNIFF_LEN: usize = 64;
+/// How long a peer has to go without circuits before we forget what it relayed.
+const FORGET_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// Counts the bytes we relay per peer, and cuts off peers that relayed more than
+/// `--max-relay-bytes-per-peer`, so the relay can't be used as a free proxy.
+///
+/// The relay only enforces `--max-circuit-bytes` per circuit and keeps its counts to itself, so
+/// this recognizes the streams circuits run over by their protocol instead. The bytes of a
+/// circuit are attributed to the peer that opened it, and also count the small handshake and
+/// reservations, which are made over the same protocol. Once a peer is over its limit the streams
+/// carrying its circuits fail, which closes them, and new ones fail right away.
+///
+/// Peers are forgotten once they went without hop streams for [`FORGET_AFTER`], which keeps the
+/// counts from piling up for every peer that ever used the relay, and starts their limit over.
+#[derive(Clone)]
+pub struct RelayBytes {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    max_bytes_per_peer: Option<u64>,
+    peers: Mutex<HashMap<PeerId, PeerBytes>>,
+    /// Bytes relayed since the last [`RelayBytes::take_relayed`].
+    relayed: AtomicU64,
+}
+
+#[derive(Default)]
+struct PeerBytes {
+    relayed: u64,
+    /// Hop streams currently open.
+    streams: usize,
+    /// When the last hop stream closed, if none are open.
+    idle_since: Option<Instant>,
+}
+
+impl RelayBytes {
+    pub fn new(max_bytes_per_peer: Option<NonZeroU64>) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                max_bytes_per_peer: max_bytes_per_peer.map(NonZeroU64::get),
+                peers: Mutex::new(HashMap::new()),
+                relayed: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Wraps the muxer of a connection to `peer_id`.
+    pub fn wrap(&self, peer_id: PeerId, muxer: StreamMuxerBox) -> StreamMuxerBox {
+        StreamMuxerBox::new(Muxer {
+            inner: muxer,
+            peer_id,
+            relay_bytes: self.clone(),
+        })
+    }
+
+    /// Forgets the peers that went without hop streams for [`FORGET_AFTER`].
+    pub fn expire(&self) {
+        self.inner.peers.lock().unwrap().retain(|_, peer| {
+            peer.idle_since
+                .is_none_or(|idle_since| idle_since.elapsed() < FORGET_AFTER)
+        });
+    }
+
+    /// Bytes relayed for all peers since the last call.
+    pub fn take_relayed(&self) -> u64 {
+        self.inner.relayed.swap(0, Ordering::Relaxed)
+    }
+
+    /// Counts `bytes` relayed for `peer_id`, failing if that takes it over its limit.
+    fn consume(&self, peer_id: &PeerId, bytes: usize) -> io::Result<()> {
+        self.inner.relayed.fetch_add(bytes as u64, Ordering::Relaxed);
+        let mut peers = self.inner.peers.lock().unwrap();
+        let peer = peers.entry(*peer_id).or_default();
+        peer.relayed += bytes as u64;
+
+        self.check(peer.relayed)
+    }
+
+    /// Records that a hop stream of `peer_id` opened, failing if it is over its limit already.
+    fn on_stream_opened(&self, peer_id: &PeerId) -> io::Result<()> {
+        let mut peers = self.inner.peers.lock().unwrap();
+        let peer = peers.entry(*peer_id).or_default();
+        peer.streams += 1;
+        peer.idle_since = None;
+
+        self.check(peer.relayed)
+    }
+
+    fn on_stream_closed(&self, peer_id: &PeerId) {
+        let mut peers = self.inner.peers.lock().unwrap();
+        let Some(peer) = peers.get_mut(peer_id) else {
+            return;
+        };
+        peer.streams = peer.streams.saturating_sub(1);
+        if peer.streams == 0 {
+            peer.idle_since = Some(Instant::now());
+        }
+    }
+
+    fn check(&self, total: u64) -> io::Result<()> {
+        match self.inner.max_bytes_per_peer {
+            Some(max) if total > max => Err(io::Error::other("--max-relay-bytes-per-peer reached")),
+            _ => Ok(()),
+        }
+    }
+}
+
+struct Muxer {
+    inner: StreamMuxerBox,
+    peer_id: PeerId,
+    relay_bytes: RelayBytes,
+}
+
+impl StreamMuxer for Muxer {
+    type Substream = SubstreamBox;
+    type Error = io::Error;
+
+    fn poll_inbound(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Self::Substream, Self::Error>> {
+        let inner = ready!(Pin::new(&mut self.inner).poll_inbound(cx))?;
+        Poll::Ready(Ok(SubstreamBox::new(Stream {
+            inner,
+            peer_id: self.peer_id,
+            relay_bytes: self.relay_bytes.clone(),
+            state: State::Sniffing(Vec::new()),
+        })))
+    }
+
+    /// Circuits are only ever opened by the remote, so our own streams aren't looked at.
+    fn poll_outbound(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Self::Substream, Self::Error>> {
+        Pin::new(&mut self.inner).poll_outbound(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+
+    fn poll(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<StreamMuxerEvent, Self::Error>> {
+        Pin::new(&mut self.inner).poll(cx)
+    }
+}
+
+enum State {
+    /// The first bytes the remote sent, until we know the stream's protocol.
+    Sniffing(Vec<u8>),
+    /// A hop stream, with the bytes relayed over it so far.
+    Relay(u64),
+    Other,
+}
+
+struct Stream {
+    inner: SubstreamBox,
+    peer_id: PeerId,
+    relay_bytes: RelayBytes,
+    state: State,
+}
+
+impl Stream {
+    fn sniff(&mut self, read: &[u8]) -> io::Result<()> {
+        let State::Sniffing(sniffed) = &mut self.state else {
+            return Ok(());
+        };
+        sniffed.extend_from_slice(&read[..read.len().min(SNIFF_LEN - sniffed.len())]);
+
+        if sniffed.windows(HOP_PROTOCOL.len()).any(|window| window == HOP_PROTOCOL) {
+            self.state = State::Relay(0);
+            // Fails new circuits of peers that are over their limit already.
+            self.relay_bytes.on_stream_opened(&self.peer_id)?;
+        } else if sniffed.len() == SNIFF_LEN {
+            self.state = State::Other;
+        }
+
+        Ok(())
+    }
+
+    fn count(&mut self, bytes: usize) -> io::Result<()> {
+        let State::Relay(relayed) = &mut self.state else {
+            return Ok(());
+        };
+        *relayed += bytes as u64;
+
+        self.relay_bytes.consume(&self.peer_id, bytes)
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        if let State::Relay(relayed) = self.state {
+            debug!(peer_id = %self.peer_id, relayed, "Relay stream closed");
+            self.relay_bytes.on_stream_closed(&self.peer_id);
+        }
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        let n = ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.sniff(&buf[..n])?;
+        this.count(n)?;
+
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        this.count(n)?;
+
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::io::{AsyncReadExt, Cursor};
+
+    impl RelayBytes {
+        fn peer(&self, peer_id: &PeerId) -> u64 {
+            self.inner
+                .peers
+                .lock()
+                .unwrap()
+                .get(peer_id)
+                .map_or(0, |peer| peer.relayed)
+        }
+    }
+
+    /// An inbound stream of `peer_id` on which the remote sends `len` bytes after negotiating the
+    /// hop protocol.
+    fn hop_stream(relay_bytes: &RelayBytes, peer_id: PeerId, len: usize) -> Stream {
+        let mut data = b"\x13/multistream/1.0.0\n\x20/libp2p/circuit/relay/0.2.0/hop\n".to_vec();
+        data.resize(data.len() + len, 0);
+
+        Stream {
+            inner: SubstreamBox::new(Cursor::new(data)),
+            peer_id,
+            relay_bytes: relay_bytes.clone(),
+            state: State::Sniffing(Vec::new()),
+        }
+    }
+
+    /// Reads `stream` to the end in small chunks, returning the error it failed with, if any.
+    fn read_to_end(stream: &mut Stream) -> Option<io::Error> {
+        futures::executor::block_on(async {
+            let mut buf = [0; 16];
+            loop {
+                match stream.read(&mut buf).await {
+                    Ok(0) => return None,
+                    Ok(_) => {}
+                    Err(e) => return Some(e),
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn fails_hop_streams_over_the_limit() {
+        let relay_bytes = RelayBytes::new(NonZeroU64::new(1000));
+        let peer_id = PeerId::random();
+
+        assert!(read_to_end(&mut hop_stream(&relay_bytes, peer_id, 500)).is_none());
+        assert!(read_to_end(&mut hop_stream(&relay_bytes, peer_id, 1000)).is_some());
+        assert!(relay_bytes.peer(&peer_id) > 1000);
+
+        // New streams of the peer fail right away, other peers aren't affected.
+        let mut stream = hop_stream(&relay_bytes, peer_id, 0);
+        assert!(read_to_end(&mut stream).is_some());
+        assert!(read_to_end(&mut hop_stream(&relay_bytes, PeerId::random(), 500)).is_none());
+    }
+
+    #[test]
+    fn forgets_peers_without_streams() {
+        let relay_bytes = RelayBytes::new(None);
+        let open = PeerId::random();
+        let closed = PeerId::random();
+
+        let mut stream = hop_stream(&relay_bytes, open, 100);
+        assert!(read_to_end(&mut stream).is_none());
+        drop(read_to_end(&mut hop_stream(&relay_bytes, closed, 100)));
+        relay_bytes.inner.peers.lock().unwrap().get_mut(&closed).unwrap().idle_since =
+            Instant::now().checked_sub(FORGET_AFTER);
+
+        relay_bytes.expire();
+        assert!(relay_bytes.peer(&open) > 0);
+        assert_eq!(relay_bytes.peer(&closed), 0);
+    }
+}
@@ -0,0 +1,134 @@
+use futures_timer::Delay;
+use libp2p::core::transport::ListenerId;
+use libp2p::multiaddr::Protocol;
+use libp2p::swarm::{NetworkBehaviour, Swarm};
+use libp2p::{Multiaddr, PeerId};
+use rand::Rng;
+use std::future;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+/// How long to wait before reserving again after the first failure. Doubles with every failure in
+/// a row, up to [`MAX_RETRY_DELAY`].
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+
+/// Keeps a reservation on one of the `--relay-address` relays, so we stay reachable behind a NAT.
+///
+/// The relay client renews an accepted reservation by itself, at 3/4 of its TTL, and doesn't let
+/// us change when. Once a reservation is lost, because a renewal failed or the connection to the
+/// relay dropped, we reserve on the next relay in the list, wrapping around to the first. The
+/// delay before doing so grows with every failure in a row and is randomized by up to as much
+/// again, so the clients of a relay that went down don't all hit the next one at the same moment.
+pub struct RelayFailover {
+    relays: Vec<Multiaddr>,
+    current: usize,
+    listener: Option<ListenerId>,
+    failures: u32,
+    retry: Option<Delay>,
+}
+
+impl RelayFailover {
+    pub fn new(relays: Vec<Multiaddr>) -> Self {
+        Self {
+            relays,
+            current: 0,
+            listener: None,
+            failures: 0,
+            retry: None,
+        }
+    }
+
+    /// Whether `peer_id` is one of the relays.
+    pub fn is_relay(&self, peer_id: &PeerId) -> bool {
+        self.relays.iter().any(|relay| relay_peer_id(relay) == Some(*peer_id))
+    }
+
+    /// The listener of the reservation we hold or requested, if any.
+    pub fn listener(&self) -> Option<ListenerId> {
+        self.listener
+    }
+
+    /// Requests a reservation on the current relay, unless we hold or requested one already, or
+    /// are waiting to fail over.
+    pub fn reserve<B: NetworkBehaviour>(&mut self, swarm: &mut Swarm<B>) {
+        let Some(relay) = self.relays.get(self.current) else {
+            return;
+        };
+        if self.listener.is_some() || self.retry.is_some() {
+            return;
+        }
+
+        match swarm.listen_on(relay.clone().with(Protocol::P2pCircuit)) {
+            Ok(listener_id) => {
+                info!(%relay, "Requesting reservation on relay");
+                self.listener = Some(listener_id);
+            }
+            Err(e) => {
+                error!(%relay, %e, "Failed to listen on relay");
+                self.fail_over();
+            }
+        }
+    }
+
+    pub fn on_reservation_accepted(&mut self, relay_peer_id: PeerId, renewal: bool) {
+        self.failures = 0;
+        if renewal {
+            info!(event = "relay_reservation_renewed", %relay_peer_id, "Renewed reservation on relay");
+        } else {
+            info!(event = "relay_reservation_accepted", %relay_peer_id, "Relay accepted our reservation");
+        }
+    }
+
+    /// Returns whether `listener_id` was our reservation, in which case we fail over.
+    pub fn on_listener_closed(&mut self, listener_id: ListenerId) -> bool {
+        if self.listener != Some(listener_id) {
+            return false;
+        }
+        self.listener = None;
+        self.fail_over();
+
+        true
+    }
+
+    /// Resolves once it's time to reserve on the next relay after a failure.
+    pub async fn next_attempt(&mut self) {
+        match &mut self.retry {
+            Some(retry) => {
+                retry.await;
+                self.retry = None;
+            }
+            None => future::pending().await,
+        }
+    }
+
+    fn fail_over(&mut self) {
+        if self.relays.is_empty() {
+            return;
+        }
+        let previous = self.relays[self.current].clone();
+        self.current = (self.current + 1) % self.relays.len();
+        self.failures += 1;
+
+        let delay = INITIAL_RETRY_DELAY
+            .saturating_mul(2u32.saturating_pow(self.failures - 1))
+            .min(MAX_RETRY_DELAY);
+        let delay = delay.mul_f64(1.0 + rand::thread_rng().gen::<f64>());
+        warn!(
+            event = "relay_failover",
+            %previous,
+            next = %self.relays[self.current],
+            failures = self.failures,
+            ?delay,
+            "Lost relay reservation, failing over"
+        );
+        self.retry = Some(Delay::new(delay));
+    }
+}
+
+fn relay_peer_id(relay: &Multiaddr) -> Option<PeerId> {
+    relay.iter().find_map(|protocol| match protocol {
+        Protocol::P2p(peer_id) => Some(peer_id),
+        _ => None,
+    })
+}
@@ -0,0 +1,101 @@
+use libp2p::{relay, PeerId};
+use std::collections::HashSet;
+use tracing::{debug, info, warn};
+
+/// Share of `--max-reservations` in use above which we warn that the relay is running full.
+const HIGH_UTILIZATION: f64 = 0.8;
+
+/// Logs what the relay server does and keeps count of the active reservations and circuits.
+pub struct RelayStats {
+    reservations: HashSet<PeerId>,
+    max_reservations: usize,
+    high_utilization: bool,
+    circuits: usize,
+}
+
+impl RelayStats {
+    pub fn new(max_reservations: usize) -> Self {
+        Self {
+            reservations: HashSet::new(),
+            max_reservations,
+            high_utilization: false,
+            circuits: 0,
+        }
+    }
+
+    pub fn on_event(&mut self, event: &relay::Event) {
+        match event {
+            relay::Event::ReservationReqAccepted { src_peer_id, renewed } => {
+                self.reservations.insert(*src_peer_id);
+                info!(event = "relay_reservation_accepted", peer_id = %src_peer_id, renewed, "Accepted relay reservation");
+            }
+            relay::Event::ReservationReqDenied { src_peer_id } => {
+                info!(event = "relay_reservation_denied", peer_id = %src_peer_id, "Denied relay reservation");
+            }
+            relay::Event::ReservationTimedOut { src_peer_id } => {
+                self.reservations.remove(src_peer_id);
+                info!(event = "relay_reservation_expired", peer_id = %src_peer_id, "Relay reservation expired");
+            }
+            relay::Event::CircuitReqAccepted { src_peer_id, dst_peer_id } => {
+                self.circuits += 1;
+                info!(event = "relay_circuit_accepted", peer_id = %src_peer_id, %dst_peer_id, "Accepted relay circuit");
+            }
+            relay::Event::CircuitReqDenied { src_peer_id, dst_peer_id } => {
+                info!(event = "relay_circuit_denied", peer_id = %src_peer_id, %dst_peer_id, "Denied relay circuit");
+            }
+            relay::Event::CircuitClosed { src_peer_id, dst_peer_id, error } => {
+                self.circuits = self.circuits.saturating_sub(1);
+                info!(event = "relay_circuit_closed", peer_id = %src_peer_id, %dst_peer_id, ?error, "Relay circuit closed");
+            }
+            e => debug!(?e, "Relay event"),
+        }
+
+        self.check_utilization();
+    }
+
+    /// The relay drops the reservations of peers we are no longer connected to without telling us.
+    pub fn on_peer_disconnected(&mut self, peer_id: &PeerId) {
+        self.reservations.remove(peer_id);
+        self.check_utilization();
+    }
+
+    /// Warns once when the reservations cross [`HIGH_UTILIZATION`], so operators can add relays
+    /// before clients start getting denied on renewal.
+    fn check_utilization(&mut self) {
+        let high = self.max_reservations > 0
+            && self.reservations.len() as f64 >= self.max_reservations as f64 * HIGH_UTILIZATION;
+        if high == self.high_utilization {
+            return;
+        }
+
+        self.high_utilization = high;
+        if high {
+            warn!(
+                event = "relay_high_utilization",
+                reservations = self.reservations.len(),
+                max_reservations = self.max_reservations,
+                "Relay reservations are above {:.0}% of --max-reservations",
+                HIGH_UTILIZATION * 100.0
+            );
+        } else {
+            info!(
+                event = "relay_utilization_recovered",
+                reservations = self.reservations.len(),
+                "Relay reservations are back below {:.0}% of --max-reservations",
+                HIGH_UTILIZATION * 100.0
+            );
+        }
+    }
+
+    pub fn has_reservation(&self, peer_id: &PeerId) -> bool {
+        self.reservations.contains(peer_id)
+    }
+
+    pub fn reservations(&self) -> usize {
+        self.reservations.len()
+    }
+
+    pub fn circuits(&self) -> usize {
+        self.circuits
+    }
+}
@@ -0,0 +1,43 @@
+use libp2p::PeerId;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Keeps track of the last known ping round-trip time and consecutive ping failures per peer.
+pub struct RttTracker {
+    rtts: HashMap<PeerId, Duration>,
+    failures: HashMap<PeerId, u32>,
+    max_failures: u32,
+}
+
+impl RttTracker {
+    pub fn new(max_failures: u32) -> Self {
+        Self {
+            rtts: HashMap::new(),
+            failures: HashMap::new(),
+            max_failures,
+        }
+    }
+
+    pub fn record_success(&mut self, peer: PeerId, rtt: Duration) {
+        self.rtts.insert(peer, rtt);
+        self.failures.remove(&peer);
+    }
+
+    /// Records a failed ping and returns whether the peer has now failed too many times in a row.
+    pub fn record_failure(&mut self, peer: PeerId) -> bool {
+        let failures = self.failures.entry(peer).or_default();
+        *failures += 1;
+
+        *failures >= self.max_failures
+    }
+
+    pub fn remove(&mut self, peer: &PeerId) {
+        self.rtts.remove(peer);
+        self.failures.remove(peer);
+    }
+
+    /// The last known round-trip time of every peer that answered a ping.
+    pub fn rtts(&self) -> &HashMap<PeerId, Duration> {
+        &self.rtts
+    }
+}
@@ -0,0 +1,70 @@
+use libp2p::{gossipsub, PeerId};
+use tracing::{info, warn};
+use std::collections::HashSet;
+
+/// Peer scoring parameters for the given topics.
+///
+/// Invalid messages are penalized heavily. Duplicates never reach the application (they are
+/// filtered by message id), but peers that advertise messages via IHAVE and then don't deliver them
+/// are penalized through the behaviour penalty. We don't penalize peers for under-delivering in a
+/// mesh, since chat topics are mostly quiet and that would kick honest browsers out.
+pub fn peer_score_params(
+    topics: &[&str],
+) -> (gossipsub::PeerScoreParams, gossipsub::PeerScoreThresholds) {
+    let mut params = gossipsub::PeerScoreParams::default();
+
+    for topic in topics {
+        params.topics.insert(
+            gossipsub::IdentTopic::new(*topic).hash(),
+            gossipsub::TopicScoreParams {
+                mesh_message_deliveries_weight: 0.0,
+                mesh_failure_penalty_weight: 0.0,
+                invalid_message_deliveries_weight: -100.0,
+                invalid_message_deliveries_decay: 0.5,
+                ..Default::default()
+            },
+        );
+    }
+
+    (params, gossipsub::PeerScoreThresholds::default())
+}
+
+/// Remembers which peers are below the gossip threshold, so we only log when that changes.
+pub struct ScoreMonitor {
+    gossip_threshold: f64,
+    below_threshold: HashSet<PeerId>,
+}
+
+impl ScoreMonitor {
+    pub fn new(gossip_threshold: f64) -> Self {
+        Self {
+            gossip_threshold,
+            below_threshold: HashSet::new(),
+        }
+    }
+
+    pub fn check(&mut self, gossipsub: &gossipsub::Behaviour) {
+        let mut below_threshold = HashSet::new();
+
+        for (peer, _) in gossipsub.all_peers() {
+            let Some(score) = gossipsub.peer_score(peer) else {
+                continue;
+            };
+            if score >= self.gossip_threshold {
+                continue;
+            }
+
+            if !self.below_threshold.contains(peer) {
+                warn!(%peer, score, "Gossipsub score dropped below the gossip threshold");
+            }
+            below_threshold.insert(*peer);
+        }
+
+        for peer in self.below_threshold.difference(&below_threshold) {
+            if gossipsub.peer_score(peer).is_some() {
+                info!(%peer, "Gossipsub score recovered");
+            }
+        }
+        self.below_threshold = below_threshold;
+    }
+}
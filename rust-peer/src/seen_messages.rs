@@ -0,0 +1,65 @@
+use libp2p::gossipsub::MessageId;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// Ids of the gossipsub messages we processed recently.
+///
+/// Gossipsub only deduplicates within its own short-lived cache, so this lets us skip messages we
+/// already acted on for longer, and answer whether we have seen a message. Entries are evicted
+/// oldest first once they are older than the TTL or the cache is full.
+pub struct SeenMessages {
+    ttl: Duration,
+    capacity: usize,
+    ids: HashMap<MessageId, Instant>,
+    order: VecDeque<MessageId>,
+}
+
+impl SeenMessages {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            ttl,
+            capacity,
+            ids: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Records `id`, returning whether it is new, i.e. hasn't been seen within the TTL.
+    pub fn insert(&mut self, id: MessageId) -> bool {
+        self.evict_expired();
+        if self.ids.contains_key(&id) {
+            return false;
+        }
+
+        while self.order.len() >= self.capacity {
+            let Some(oldest) = self.order.pop_front() else {
+                break;
+            };
+            self.ids.remove(&oldest);
+        }
+
+        if self.capacity > 0 {
+            self.ids.insert(id.clone(), Instant::now());
+            self.order.push_back(id);
+        }
+
+        true
+    }
+
+    pub fn contains(&self, id: &MessageId) -> bool {
+        self.ids
+            .get(id)
+            .is_some_and(|seen| seen.elapsed() <= self.ttl)
+    }
+
+    pub fn evict_expired(&mut self) {
+        while let Some(oldest) = self.order.front() {
+            if self.ids[oldest].elapsed() <= self.ttl {
+                break;
+            }
+
+            self.ids.remove(oldest);
+            self.order.pop_front();
+        }
+    }
+}
@@ -0,0 +1,174 @@
+use anyhow::{Context as _, Result};
+use futures::future::{self, Ready};
+use libp2p::core::transport::{ListenerId, TransportError, TransportEvent};
+use libp2p::core::Transport;
+use libp2p::multiaddr::{Multiaddr, Protocol};
+use libp2p::tcp;
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddr;
+use std::os::fd::{FromRawFd, RawFd};
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
+use tokio::net::TcpListener;
+use tracing::{info, warn};
+
+/// The first file descriptor systemd passes, see `sd_listen_fds(3)`.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Takes over the listening TCP sockets systemd passed us via `LISTEN_FDS`, if it started us.
+/// Returns none if it didn't, so we bind our own.
+pub fn inherited_tcp_listeners() -> Result<Vec<(Multiaddr, TcpListener)>> {
+    let for_us = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_some_and(|pid| pid == std::process::id());
+    let count = match std::env::var("LISTEN_FDS") {
+        Ok(count) if for_us => count.parse::<RawFd>().context("Invalid LISTEN_FDS")?,
+        _ => {
+            warn!("--systemd-socket-activation is set, but systemd didn't pass any sockets");
+            return Ok(Vec::new());
+        }
+    };
+
+    let mut listeners = Vec::new();
+    for fd in SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count {
+        // SAFETY: systemd hands these file descriptors to us and nothing else in the process uses
+        // them.
+        let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+        let address = listener
+            .local_addr()
+            .with_context(|| format!("File descriptor {fd} from systemd is not a TCP socket"))?;
+        if address.ip().is_unspecified() {
+            warn!(%address, "Inherited socket listens on all interfaces, which peers can't dial, consider setting --external-address");
+        }
+        listener.set_nonblocking(true)?;
+
+        info!(%address, "Inherited TCP socket from systemd");
+        listeners.push((socket_multiaddr(address), TcpListener::from_std(listener)?));
+    }
+
+    Ok(listeners)
+}
+
+/// The TCP transport, except that listening on the address of a socket inherited from systemd
+/// accepts connections on that socket instead of binding a new one.
+///
+/// Once a listener on an inherited socket is removed, the socket is closed, and listening on its
+/// address again binds normally.
+pub struct SocketActivated {
+    inner: tcp::tokio::Transport,
+    /// Inherited sockets we aren't listening on yet.
+    inherited: Vec<(Multiaddr, TcpListener)>,
+    listeners: Vec<(ListenerId, Multiaddr, TcpListener)>,
+    pending_events: VecDeque<TransportEvent<Ready<io::Result<tcp::tokio::TcpStream>>, io::Error>>,
+    waker: Option<Waker>,
+}
+
+impl SocketActivated {
+    pub fn new(inner: tcp::tokio::Transport, inherited: Vec<(Multiaddr, TcpListener)>) -> Self {
+        Self {
+            inner,
+            inherited,
+            listeners: Vec::new(),
+            pending_events: VecDeque::new(),
+            waker: None,
+        }
+    }
+
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl Transport for SocketActivated {
+    type Output = tcp::tokio::TcpStream;
+    type Error = io::Error;
+    type ListenerUpgrade = Ready<io::Result<Self::Output>>;
+    type Dial = <tcp::tokio::Transport as Transport>::Dial;
+
+    fn listen_on(&mut self, id: ListenerId, addr: Multiaddr) -> Result<(), TransportError<Self::Error>> {
+        let Some(position) = self.inherited.iter().position(|(address, _)| *address == addr) else {
+            return self.inner.listen_on(id, addr);
+        };
+
+        let (address, listener) = self.inherited.remove(position);
+        self.pending_events.push_back(TransportEvent::NewAddress {
+            listener_id: id,
+            listen_addr: address.clone(),
+        });
+        self.listeners.push((id, address, listener));
+        self.wake();
+
+        Ok(())
+    }
+
+    fn remove_listener(&mut self, id: ListenerId) -> bool {
+        let Some(position) = self.listeners.iter().position(|(listener_id, ..)| *listener_id == id) else {
+            return self.inner.remove_listener(id);
+        };
+
+        let (listener_id, listen_addr, _) = self.listeners.remove(position);
+        self.pending_events.push_back(TransportEvent::AddressExpired {
+            listener_id,
+            listen_addr,
+        });
+        self.pending_events.push_back(TransportEvent::ListenerClosed {
+            listener_id,
+            reason: Ok(()),
+        });
+        self.wake();
+
+        true
+    }
+
+    fn dial(&mut self, addr: Multiaddr) -> Result<Self::Dial, TransportError<Self::Error>> {
+        self.inner.dial(addr)
+    }
+
+    fn dial_as_listener(&mut self, addr: Multiaddr) -> Result<Self::Dial, TransportError<Self::Error>> {
+        self.inner.dial_as_listener(addr)
+    }
+
+    fn poll(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<TransportEvent<Self::ListenerUpgrade, Self::Error>> {
+        if let Some(event) = self.pending_events.pop_front() {
+            return Poll::Ready(event);
+        }
+
+        for (listener_id, local_addr, listener) in &self.listeners {
+            match listener.poll_accept(cx) {
+                Poll::Ready(Ok((stream, remote))) => {
+                    return Poll::Ready(TransportEvent::Incoming {
+                        listener_id: *listener_id,
+                        upgrade: future::ready(Ok(tcp::tokio::TcpStream(stream))),
+                        local_addr: local_addr.clone(),
+                        send_back_addr: socket_multiaddr(remote),
+                    });
+                }
+                Poll::Ready(Err(error)) => {
+                    return Poll::Ready(TransportEvent::ListenerError {
+                        listener_id: *listener_id,
+                        error,
+                    });
+                }
+                Poll::Pending => {}
+            }
+        }
+
+        self.waker = Some(cx.waker().clone());
+        Pin::new(&mut self.inner).poll(cx)
+    }
+
+    fn address_translation(&self, listen: &Multiaddr, observed: &Multiaddr) -> Option<Multiaddr> {
+        self.inner.address_translation(listen, observed)
+    }
+}
+
+fn socket_multiaddr(address: SocketAddr) -> Multiaddr {
+    Multiaddr::from(address.ip()).with(Protocol::Tcp(address.port()))
+}
@@ -0,0 +1,86 @@
+use anyhow::{Context, Result};
+use libp2p::kad::NodeStatus;
+use libp2p::Swarm;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::path::Path;
+use tokio::fs;
+
+use crate::explicit_peers::ExplicitPeers;
+use crate::Behaviour;
+
+/// A snapshot of our connections, the gossipsub mesh and the Kademlia routing table, to diagnose
+/// partitions and messages that don't propagate without attaching a debugger.
+///
+/// Requested with the `dumpState` admin command, or written to `--state-dump-file` on `SIGUSR1`.
+pub fn snapshot(swarm: &mut Swarm<Behaviour>, explicit_peers: &ExplicitPeers) -> Value {
+    let gossipsub = &swarm.behaviour().gossipsub;
+    let subscriptions = gossipsub
+        .all_peers()
+        .map(|(peer_id, topics)| (*peer_id, topics.iter().map(|topic| topic.to_string()).collect::<Vec<_>>()))
+        .collect::<HashMap<_, _>>();
+    let connected_peers = swarm
+        .connected_peers()
+        .map(|peer_id| {
+            json!({
+                "peerId": peer_id.to_string(),
+                "topics": subscriptions.get(peer_id).cloned().unwrap_or_default(),
+            })
+        })
+        .collect::<Vec<_>>();
+    let mesh = gossipsub
+        .topics()
+        .map(|topic| {
+            let peers = gossipsub.mesh_peers(topic).map(|peer_id| peer_id.to_string()).collect::<Vec<_>>();
+            (topic.to_string(), json!(peers))
+        })
+        .collect::<Map<_, _>>();
+    let explicit_peers = explicit_peers.peers().map(|peer_id| peer_id.to_string()).collect::<Vec<_>>();
+    let external_addresses = swarm.external_addresses().map(|addr| addr.to_string()).collect::<Vec<_>>();
+
+    let buckets = swarm
+        .behaviour_mut()
+        .kademlia
+        .kbuckets()
+        .map(|bucket| {
+            let entries = bucket
+                .iter()
+                .map(|entry| {
+                    json!({
+                        "peerId": entry.node.key.preimage().to_string(),
+                        "addresses": entry.node.value.iter().map(|addr| addr.to_string()).collect::<Vec<_>>(),
+                        "connected": matches!(entry.status, NodeStatus::Connected),
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            // Bucket i holds the peers at a distance in [2^i, 2^(i+1)).
+            json!({ "index": bucket.range().0.ilog2(), "entries": entries })
+        })
+        .collect::<Vec<_>>();
+
+    json!({
+        "peerId": swarm.local_peer_id().to_string(),
+        "connectedPeers": connected_peers,
+        "mesh": mesh,
+        "explicitPeers": explicit_peers,
+        "externalAddresses": external_addresses,
+        "routingTable": buckets,
+    })
+}
+
+/// Writes `snapshot` to `path`, next to it first and then renamed, so readers never see half of
+/// it.
+pub async fn write(path: &Path, snapshot: &Value) -> Result<()> {
+    let json = serde_json::to_vec_pretty(snapshot)?;
+
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, json)
+        .await
+        .with_context(|| format!("Failed to write {}", tmp.display()))?;
+    fs::rename(&tmp, path)
+        .await
+        .with_context(|| format!("Failed to replace {}", path.display()))?;
+
+    Ok(())
+}
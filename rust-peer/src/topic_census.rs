@@ -0,0 +1,122 @@
+use anyhow::Result;
+use libp2p::gossipsub::TopicHash;
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+/// Topic peers running `--topic-census` announce the topics they are subscribed to on.
+pub const TOPIC_CENSUS_TOPIC: &str = "universal-connectivity-topic-census";
+/// Peers whose announcements we keep at most. Beyond that, the oldest announcement is dropped.
+const MAX_ANNOUNCEMENTS: usize = 10_000;
+
+#[derive(Serialize, Deserialize)]
+struct Announcement {
+    topics: Vec<String>,
+    /// Keeps announcements with unchanged topics from getting the same content based message id,
+    /// which gossipsub would drop as duplicates.
+    nonce: u64,
+}
+
+/// A coarse estimate of how many peers in the whole network subscribe to each topic.
+///
+/// Gossipsub only tells us about the subscriptions of peers we are connected to. With the census,
+/// every participating peer periodically announces its own topics, so we also learn about peers
+/// further away. The estimate is still only a lower bound: peers that don't run the census, like
+/// browsers, are only counted while we are connected to them, announcements can get lost in
+/// partitions or stay behind gossipsub's mesh, and a peer that unsubscribed is counted until its
+/// announcement lapses after `ttl`.
+///
+/// It can also be inflated: with `Permissive` validation, messages aren't required to be signed,
+/// so the source of an announcement is whatever its publisher claims. One peer can announce in the
+/// name of made up peers, up to [`MAX_ANNOUNCEMENTS`] of them.
+pub struct TopicCensus {
+    ttl: Duration,
+    announcements: HashMap<PeerId, (HashSet<TopicHash>, Instant)>,
+}
+
+impl TopicCensus {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            announcements: HashMap::new(),
+        }
+    }
+
+    /// Encodes the announcement for our own `topics`.
+    pub fn announcement<'a>(topics: impl Iterator<Item = &'a TopicHash>) -> Vec<u8> {
+        let announcement = Announcement {
+            topics: topics
+                .filter(|topic| topic.as_str() != TOPIC_CENSUS_TOPIC)
+                .map(|topic| topic.to_string())
+                .collect(),
+            nonce: rand::random(),
+        };
+
+        serde_json::to_vec(&announcement).expect("Serializing to JSON doesn't fail")
+    }
+
+    /// Records the announcement `data` published by `source`, replacing its previous one.
+    pub fn on_announcement(&mut self, source: PeerId, data: &[u8]) -> Result<()> {
+        let announcement = serde_json::from_slice::<Announcement>(data)?;
+        let topics = announcement.topics.into_iter().map(TopicHash::from_raw).collect();
+        if !self.announcements.contains_key(&source) && self.announcements.len() >= MAX_ANNOUNCEMENTS {
+            let oldest = self
+                .announcements
+                .iter()
+                .min_by_key(|(_, (_, announced))| *announced)
+                .map(|(peer_id, _)| *peer_id);
+            if let Some(oldest) = oldest {
+                self.announcements.remove(&oldest);
+            }
+        }
+        self.announcements.insert(source, (topics, Instant::now()));
+
+        Ok(())
+    }
+
+    /// Drops announcements that weren't renewed within the ttl.
+    pub fn expire(&mut self) {
+        let ttl = self.ttl;
+        self.announcements.retain(|_, (_, announced)| announced.elapsed() < ttl);
+    }
+
+    /// The estimated number of subscribers to `topic` other than us, counting both the peers that
+    /// announced it and the `known` subscribers gossipsub told us about.
+    pub fn estimate<'a>(&self, topic: &TopicHash, known: impl Iterator<Item = &'a PeerId>) -> usize {
+        let mut subscribers = known.collect::<HashSet<_>>();
+        subscribers.extend(
+            self.announcements
+                .iter()
+                .filter(|(_, (topics, _))| topics.contains(topic))
+                .map(|(peer_id, _)| peer_id),
+        );
+
+        subscribers.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drops_the_oldest_announcement_when_full() {
+        let mut census = TopicCensus::new(Duration::from_secs(60));
+        let topic = TopicHash::from_raw("chat");
+        let announcement = br#"{"topics":["chat"],"nonce":0}"#;
+
+        let oldest = PeerId::random();
+        census.on_announcement(oldest, announcement).unwrap();
+        for _ in 1..MAX_ANNOUNCEMENTS {
+            census.on_announcement(PeerId::random(), announcement).unwrap();
+        }
+        assert_eq!(census.estimate(&topic, std::iter::empty()), MAX_ANNOUNCEMENTS);
+
+        let newest = PeerId::random();
+        census.on_announcement(newest, announcement).unwrap();
+        assert_eq!(census.estimate(&topic, std::iter::empty()), MAX_ANNOUNCEMENTS);
+        assert!(!census.announcements.contains_key(&oldest));
+        assert!(census.announcements.contains_key(&newest));
+    }
+}
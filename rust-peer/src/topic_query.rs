@@ -0,0 +1,87 @@
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use libp2p::{gossipsub, request_response, StreamProtocol};
+use serde::{Deserialize, Serialize};
+use std::io;
+
+/// Lets a peer, typically a browser that just joined, ask which topics we carry, so it can pick an
+/// active one instead of guessing.
+pub const TOPIC_QUERY_PROTOCOL: StreamProtocol =
+    StreamProtocol::new("/universal-connectivity-topics/1");
+
+/// Upper bound for a response we receive. Requests are empty.
+const MAX_RESPONSE_SIZE: u64 = 64 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TopicInfo {
+    pub topic: String,
+    pub mesh_peers: usize,
+}
+
+/// The topics we are subscribed to, with the number of peers in our mesh for each.
+pub fn subscribed_topics(gossipsub: &gossipsub::Behaviour) -> Vec<TopicInfo> {
+    gossipsub
+        .topics()
+        .map(|topic| TopicInfo {
+            topic: topic.to_string(),
+            mesh_peers: gossipsub.mesh_peers(topic).count(),
+        })
+        .collect()
+}
+
+/// Codec for the topic query protocol.
+///
+/// The request is empty, the requester just closes its write half. The response is a JSON array of
+/// [`TopicInfo`], e.g. `[{"topic":"universal-connectivity","meshPeers":4}]`.
+#[derive(Debug, Clone, Default)]
+pub struct TopicQueryCodec;
+
+#[async_trait]
+impl request_response::Codec for TopicQueryCodec {
+    type Protocol = StreamProtocol;
+    type Request = ();
+    type Response = Vec<TopicInfo>;
+
+    async fn read_request<T>(&mut self, _: &StreamProtocol, _: &mut T) -> io::Result<()>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        Ok(())
+    }
+
+    async fn read_response<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<Vec<TopicInfo>>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let mut buf = Vec::new();
+        io.take(MAX_RESPONSE_SIZE).read_to_end(&mut buf).await?;
+
+        serde_json::from_slice(&buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    async fn write_request<T>(&mut self, _: &StreamProtocol, io: &mut T, (): ()) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        io.close().await?;
+
+        Ok(())
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        topics: Vec<TopicInfo>,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let json = serde_json::to_vec(&topics).expect("Serializing to JSON doesn't fail");
+        io.write_all(&json).await?;
+        io.close().await?;
+
+        Ok(())
+    }
+}
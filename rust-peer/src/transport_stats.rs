@@ -0,0 +1,63 @@
+use libp2p::core::ConnectedPoint;
+use libp2p::multiaddr::{Multiaddr, Protocol};
+use std::collections::BTreeMap;
+
+/// Transports we classify connections by, in the order they are reported.
+pub const TRANSPORTS: [&str; 6] = ["webrtc", "quic", "websocket", "tcp", "relay", "other"];
+
+/// Number of open connections per transport, so operators can see how peers actually reach us.
+#[derive(Default)]
+pub struct TransportStats {
+    connections: BTreeMap<&'static str, usize>,
+}
+
+impl TransportStats {
+    /// Counts a new connection, returning its transport and the updated number of connections
+    /// over it.
+    pub fn established(&mut self, endpoint: &ConnectedPoint) -> (&'static str, usize) {
+        let transport = classify(endpoint.get_remote_address());
+        let count = self.connections.entry(transport).or_default();
+        *count += 1;
+
+        (transport, *count)
+    }
+
+    pub fn closed(&mut self, endpoint: &ConnectedPoint) -> (&'static str, usize) {
+        let transport = classify(endpoint.get_remote_address());
+        let count = self.connections.entry(transport).or_default();
+        *count = count.saturating_sub(1);
+
+        (transport, *count)
+    }
+
+    /// E.g. `webrtc=2 quic=0 websocket=1 tcp=5 relay=0 other=0`.
+    pub fn summary(&self) -> String {
+        TRANSPORTS
+            .iter()
+            .map(|transport| {
+                let count = self.connections.get(transport).copied().unwrap_or_default();
+                format!("{transport}={count}")
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// The transport of a connection with the given remote address. Relayed connections count as
+/// `relay`, whatever transport the relay connection itself uses.
+pub fn classify(addr: &Multiaddr) -> &'static str {
+    let mut transport = "other";
+
+    for protocol in addr.iter() {
+        match protocol {
+            Protocol::P2pCircuit => return "relay",
+            Protocol::WebRTCDirect => transport = "webrtc",
+            Protocol::QuicV1 | Protocol::Quic => transport = "quic",
+            Protocol::Ws(_) | Protocol::Wss(_) => transport = "websocket",
+            Protocol::Tcp(_) if transport == "other" => transport = "tcp",
+            _ => {}
+        }
+    }
+
+    transport
+}